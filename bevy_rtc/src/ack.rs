@@ -0,0 +1,437 @@
+use crate::{
+    events::{DeliveryEvent, DeliveryStatus},
+    protocol::RtcPacketHeader,
+    sequence::is_newer,
+};
+#[cfg(any(feature = "client", feature = "server"))]
+use instant::{Duration, Instant};
+use matchbox_socket::{Packet, PeerId};
+use std::collections::HashMap;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// The length of the sequence number and acknowledgements following the
+/// header of an acked datagram.
+const ACKS_LEN: usize = 8;
+/// The length of the framing an acked datagram adds to the packets it carries.
+pub(crate) const ENVELOPE_LEN: usize = RtcPacketHeader::LEN + ACKS_LEN;
+/// The number of datagrams acknowledged by the bitfield, up to the newest.
+const ACK_BITS: u16 = 32;
+
+/// Identifies a payload sent with an `*_acked` method of a network writer, to
+/// match it with its [`DeliveryEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Configures the acknowledgement of payloads sent with the `*_acked` methods
+/// of a network writer.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone)]
+pub struct AckConfig {
+    /// How long a datagram waits to be acknowledged before its payloads are
+    /// reported lost. Defaults to 1 second.
+    pub loss_timeout: Duration,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl Default for AckConfig {
+    fn default() -> Self {
+        Self {
+            loss_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A datagram sent with tracked payloads, waiting to be acknowledged.
+#[derive(Debug)]
+struct SentDatagram {
+    #[cfg(any(feature = "client", feature = "server"))]
+    at: Instant,
+    tracked: Vec<(&'static str, MessageId)>,
+}
+
+/// The acknowledgements exchanged with a peer.
+#[derive(Debug, Default)]
+struct PeerAcks {
+    next_sequence: u16,
+    /// Datagrams sent with tracked payloads, by sequence number
+    sent: HashMap<u16, SentDatagram>,
+    /// The protocol of each tracked payload, and the number of its datagrams
+    /// not yet acknowledged
+    pending: HashMap<MessageId, (&'static str, usize)>,
+    /// The newest sequence number received
+    received: Option<u16>,
+    /// Which of the [`ACK_BITS`] sequence numbers up to the newest were
+    /// received, the lowest bit being the newest
+    received_bits: u32,
+    /// Whether a datagram carrying payloads was received since the peer was
+    /// last sent acknowledgements
+    owes_ack: bool,
+}
+
+impl PeerAcks {
+    /// Record the sequence number of a received datagram.
+    fn record(&mut self, sequence: u16) {
+        let Some(newest) = self.received else {
+            self.received = Some(sequence);
+            self.received_bits = 1;
+            return;
+        };
+        if is_newer(sequence, newest) {
+            let shift = u32::from(sequence.wrapping_sub(newest));
+            self.received_bits = self.received_bits.checked_shl(shift).unwrap_or(0) | 1;
+            self.received = Some(sequence);
+        } else {
+            let behind = newest.wrapping_sub(sequence);
+            if behind < ACK_BITS {
+                self.received_bits |= 1 << behind;
+            }
+        }
+    }
+
+    /// Resolve the datagrams acknowledged by a peer, and those which fell
+    /// behind its acknowledgements without being acknowledged.
+    fn acknowledge(&mut self, peer: PeerId, ack: u16, bits: u32) -> Vec<DeliveryEvent> {
+        if bits == 0 {
+            // Nothing was received yet
+            return vec![];
+        }
+        let resolved: Vec<(u16, bool)> = self
+            .sent
+            .keys()
+            .filter(|sequence| **sequence == ack || is_newer(ack, **sequence))
+            .filter_map(|sequence| {
+                let behind = ack.wrapping_sub(*sequence);
+                if behind >= ACK_BITS {
+                    Some((*sequence, false))
+                } else if bits & (1 << behind) != 0 {
+                    Some((*sequence, true))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        resolved
+            .into_iter()
+            .flat_map(|(sequence, delivered)| self.resolve(peer, sequence, delivered))
+            .collect()
+    }
+
+    /// Resolve a sent datagram, reporting its tracked payloads delivered once
+    /// all of their datagrams are, or lost as soon as one is.
+    fn resolve(&mut self, peer: PeerId, sequence: u16, delivered: bool) -> Vec<DeliveryEvent> {
+        let Some(datagram) = self.sent.remove(&sequence) else {
+            return vec![];
+        };
+        let mut events = vec![];
+        for (protocol, id) in datagram.tracked {
+            let Some((_, remaining)) = self.pending.get_mut(&id) else {
+                // Already reported lost
+                continue;
+            };
+            *remaining -= 1;
+            if delivered && *remaining > 0 {
+                continue;
+            }
+            self.pending.remove(&id);
+            events.push(DeliveryEvent {
+                peer,
+                protocol,
+                id,
+                status: if delivered {
+                    DeliveryStatus::Delivered
+                } else {
+                    DeliveryStatus::Lost
+                },
+            });
+        }
+        events
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Report everything pending as lost.
+    fn lose_all(self, peer: PeerId) -> impl Iterator<Item = DeliveryEvent> {
+        self.pending
+            .into_iter()
+            .map(move |(id, (protocol, _))| DeliveryEvent {
+                peer,
+                protocol,
+                id,
+                status: DeliveryStatus::Lost,
+            })
+    }
+}
+
+/// Tracks the datagrams exchanged with each peer on the unreliable channel,
+/// to acknowledge those received and resolve the delivery of payloads sent
+/// with the `*_acked` methods of a network writer.
+///
+/// Acked datagrams follow a header flagged [`RtcPacketHeader::FLAG_ACKED`],
+/// with their sequence number, the newest sequence number received from the
+/// peer, and a bitfield of which of the [`ACK_BITS`] up to it were received.
+/// Acknowledgements are repeated by every datagram, so most survive the loss
+/// of a few. Once a payload is tracked to a peer, or an acked datagram is
+/// received from it, every datagram to it on the unreliable channel is acked.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct Acks {
+    peers: HashMap<PeerId, PeerAcks>,
+}
+
+impl Acks {
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Whether datagrams to a peer carry acknowledgements.
+    pub(crate) fn is_active(&self, peer: PeerId) -> bool {
+        self.peers.contains_key(&peer)
+    }
+
+    /// Peers which sent payloads that were not yet acknowledged.
+    pub(crate) fn owed(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, acks)| acks.owes_ack)
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Frame a datagram to a peer with a sequence number and the
+    /// acknowledgements of those received from it, tracking its payloads.
+    /// Returns the framed datagram and its sequence number.
+    pub(crate) fn wrap(
+        &mut self,
+        peer: PeerId,
+        tracked: Vec<(&'static str, MessageId)>,
+        packet: &[u8],
+    ) -> (u16, Packet) {
+        let acks = self.peers.entry(peer).or_default();
+        let sequence = acks.next_sequence;
+        acks.next_sequence = acks.next_sequence.wrapping_add(1);
+        acks.owes_ack = false;
+        if !tracked.is_empty() {
+            for (protocol, id) in tracked.iter() {
+                acks.pending.entry(*id).or_insert((protocol, 0)).1 += 1;
+            }
+            acks.sent.insert(
+                sequence,
+                SentDatagram {
+                    #[cfg(any(feature = "client", feature = "server"))]
+                    at: Instant::now(),
+                    tracked,
+                },
+            );
+        }
+
+        let mut buf = Vec::with_capacity(ENVELOPE_LEN + packet.len());
+        RtcPacketHeader {
+            msg_id: 0,
+            version: 0,
+            codec: 0,
+            flags: RtcPacketHeader::FLAG_ACKED,
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(&sequence.to_le_bytes());
+        // Until anything is received, every bit is clear
+        buf.extend_from_slice(&acks.received.unwrap_or_default().to_le_bytes());
+        buf.extend_from_slice(&acks.received_bits.to_le_bytes());
+        buf.extend_from_slice(packet);
+        (sequence, buf.into_boxed_slice())
+    }
+
+    /// Open a datagram received from a peer, recording its sequence number and
+    /// resolving the payloads it acknowledges. Returns the packets it carries,
+    /// or `None` if it carries only acknowledgements or is malformed. Datagrams
+    /// which are not acked are returned as they are.
+    pub(crate) fn open(
+        &mut self,
+        peer: PeerId,
+        message: Packet,
+        events: &mut Vec<DeliveryEvent>,
+    ) -> Option<Packet> {
+        match RtcPacketHeader::read(&message) {
+            Some((header, data)) if header.has_flag(RtcPacketHeader::FLAG_ACKED) => {
+                if data.len() < ACKS_LEN {
+                    warn!("Received a malformed acked datagram from {peer}");
+                    return None;
+                }
+                let (acks_data, packet) = data.split_at(ACKS_LEN);
+                let sequence = u16::from_le_bytes([acks_data[0], acks_data[1]]);
+                let ack = u16::from_le_bytes([acks_data[2], acks_data[3]]);
+                let bits =
+                    u32::from_le_bytes([acks_data[4], acks_data[5], acks_data[6], acks_data[7]]);
+                let acks = self.peers.entry(peer).or_default();
+                acks.record(sequence);
+                events.extend(acks.acknowledge(peer, ack, bits));
+                if packet.is_empty() {
+                    return None;
+                }
+                acks.owes_ack = true;
+                Some(packet.into())
+            }
+            _ => Some(message),
+        }
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Report a datagram which could not be sent as lost.
+    pub(crate) fn lose(&mut self, peer: PeerId, sequence: u16) -> Vec<DeliveryEvent> {
+        match self.peers.get_mut(&peer) {
+            Some(acks) => acks.resolve(peer, sequence, false),
+            None => vec![],
+        }
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Report datagrams which waited longer than the loss timeout as lost, and
+    /// everything pending to peers which disconnected.
+    pub(crate) fn expire(
+        &mut self,
+        connected: &[PeerId],
+        config: &AckConfig,
+    ) -> Vec<DeliveryEvent> {
+        let mut events = vec![];
+        let disconnected: Vec<PeerId> = self
+            .peers
+            .keys()
+            .filter(|peer| !connected.contains(peer))
+            .copied()
+            .collect();
+        for peer in disconnected {
+            if let Some(acks) = self.peers.remove(&peer) {
+                events.extend(acks.lose_all(peer));
+            }
+        }
+        let now = Instant::now();
+        for (peer, acks) in self.peers.iter_mut() {
+            let expired: Vec<u16> = acks
+                .sent
+                .iter()
+                .filter(|(_, datagram)| now.duration_since(datagram.at) >= config.loss_timeout)
+                .map(|(sequence, _)| *sequence)
+                .collect();
+            for sequence in expired {
+                events.extend(acks.resolve(*peer, sequence, false));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    /// Send a datagram carrying a payload, tracking it if `id` is given.
+    fn send(acks: &mut Acks, id: Option<u64>) -> Packet {
+        let tracked = id
+            .map(|id| vec![("Test", MessageId(id))])
+            .unwrap_or_default();
+        acks.wrap(PEER, tracked, &[1, 2, 3]).1
+    }
+
+    /// Deliver datagrams to the receiver, and its acknowledgements back to the
+    /// sender.
+    fn exchange(
+        sender: &mut Acks,
+        receiver: &mut Acks,
+        datagrams: Vec<Packet>,
+    ) -> Vec<DeliveryEvent> {
+        for datagram in datagrams {
+            let packet = receiver.open(PEER, datagram, &mut vec![]);
+            assert_eq!(packet.as_deref(), Some(&[1, 2, 3][..]));
+        }
+        assert_eq!(receiver.owed().collect::<Vec<_>>(), vec![PEER]);
+        let (_, ack) = receiver.wrap(PEER, vec![], &[]);
+        let mut events = vec![];
+        assert!(sender.open(PEER, ack, &mut events).is_none());
+        events
+    }
+
+    fn statuses(events: &[DeliveryEvent]) -> Vec<(u64, DeliveryStatus)> {
+        let mut statuses: Vec<_> = events
+            .iter()
+            .map(|event| (event.id.0, event.status))
+            .collect();
+        statuses.sort_by_key(|(id, _)| *id);
+        statuses
+    }
+
+    #[test]
+    fn unacked_datagrams_pass_through() {
+        let mut acks = Acks::default();
+        let packet: Packet = vec![1, 2, 3].into_boxed_slice();
+        assert_eq!(acks.open(PEER, packet.clone(), &mut vec![]), Some(packet));
+        assert_eq!(acks.owed().count(), 0);
+    }
+
+    #[test]
+    fn acknowledgements_survive_wraparound() {
+        let (mut sender, mut receiver) = (Acks::default(), Acks::default());
+        sender.peers.entry(PEER).or_default().next_sequence = u16::MAX - 1;
+
+        // Sequence numbers 65534, 65535, 0 and 1, the third of which is lost
+        let datagrams: Vec<Packet> = (0..4).map(|id| send(&mut sender, Some(id))).collect();
+        let received = vec![
+            datagrams[0].clone(),
+            datagrams[1].clone(),
+            datagrams[3].clone(),
+        ];
+        let events = exchange(&mut sender, &mut receiver, received);
+        assert_eq!(
+            statuses(&events),
+            vec![
+                (0, DeliveryStatus::Delivered),
+                (1, DeliveryStatus::Delivered),
+                (3, DeliveryStatus::Delivered),
+            ]
+        );
+
+        // The lost datagram is reported once it falls behind the bitfield
+        let datagrams: Vec<Packet> = (0..ACK_BITS).map(|_| send(&mut sender, None)).collect();
+        let events = exchange(&mut sender, &mut receiver, datagrams);
+        assert_eq!(statuses(&events), vec![(2, DeliveryStatus::Lost)]);
+    }
+
+    #[test]
+    fn payloads_over_several_datagrams_are_delivered_once() {
+        let (mut sender, mut receiver) = (Acks::default(), Acks::default());
+        let datagrams = [send(&mut sender, Some(7)), send(&mut sender, Some(7))];
+
+        let events = exchange(&mut sender, &mut receiver, vec![datagrams[0].clone()]);
+        assert!(events.is_empty());
+        let events = exchange(&mut sender, &mut receiver, vec![datagrams[1].clone()]);
+        assert_eq!(statuses(&events), vec![(7, DeliveryStatus::Delivered)]);
+    }
+
+    #[test]
+    fn malformed_datagrams_are_rejected() {
+        let mut acks = Acks::default();
+        let mut buf = vec![];
+        RtcPacketHeader {
+            msg_id: 0,
+            version: 0,
+            codec: 0,
+            flags: RtcPacketHeader::FLAG_ACKED,
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(&[0; ACKS_LEN - 1]);
+        assert!(acks.open(PEER, buf.into(), &mut vec![]).is_none());
+    }
+}
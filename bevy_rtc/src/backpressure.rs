@@ -0,0 +1,241 @@
+use crate::channel::RtcChannels;
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::events::CongestionEvent;
+use matchbox_socket::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::collections::HashSet;
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Configures when a peer is considered congested, see
+/// [`CongestionEvent`].
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone, Default)]
+pub struct BackpressureConfig {
+    /// The bytes buffered to a peer on a reliable channel at which sends to it
+    /// on that channel are refused with `SendErrorKind::Congested`, until it
+    /// catches up. Unlimited by default, in which case no estimates are made
+    /// and peers are not asked to report what they received.
+    pub high_water_mark: Option<usize>,
+}
+
+/// The bytes received from a peer on each channel, reported to it so it can
+/// estimate how much it has buffered.
+#[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
+#[payload(name = "bevy_rtc::ReceiptPayload")]
+pub(crate) struct ReceiptPayload {
+    /// The total bytes received, by channel index
+    pub received: Vec<u64>,
+}
+
+/// Counts the bytes exchanged with each peer by channel, to estimate those
+/// buffered but not yet received.
+///
+/// The data channels do not expose their buffered amount, so peers report the
+/// bytes they received instead, and anything sent but not reported is assumed
+/// to be buffered or in flight. Unreliable channels drop rather than buffer,
+/// so only reliable channels are estimated, and only for peers which report.
+///
+/// Reports are only sent by sides with a high-water mark, and in reply to
+/// peers which send them, so neither side reports unless one asks.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct Buffered {
+    #[cfg(any(feature = "client", feature = "server"))]
+    sent: HashMap<(PeerId, usize), u64>,
+    received: HashMap<(PeerId, usize), u64>,
+    /// The bytes each peer last reported receiving
+    #[cfg(any(feature = "client", feature = "server"))]
+    reported: HashMap<(PeerId, usize), u64>,
+    #[cfg(any(feature = "client", feature = "server"))]
+    reporting: HashSet<PeerId>,
+    #[cfg(any(feature = "client", feature = "server"))]
+    congested: HashSet<(PeerId, usize)>,
+}
+
+impl Buffered {
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub(crate) fn record_sent(&mut self, peer: PeerId, channel: usize, len: usize) {
+        *self.sent.entry((peer, channel)).or_default() += len as u64;
+    }
+
+    pub(crate) fn record_received(&mut self, peer: PeerId, channel: usize, len: usize) {
+        *self.received.entry((peer, channel)).or_default() += len as u64;
+    }
+
+    /// The report of bytes received from a peer.
+    pub(crate) fn receipt(&self, peer: PeerId, channels: &RtcChannels) -> ReceiptPayload {
+        ReceiptPayload {
+            received: (0..channels.iter().count())
+                .map(|channel| {
+                    self.received
+                        .get(&(peer, channel))
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .collect(),
+        }
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Record the bytes a peer reported receiving. Reports arrive unreliably,
+    /// so an older report never lowers the count.
+    pub(crate) fn acknowledge(&mut self, peer: PeerId, receipt: ReceiptPayload) {
+        self.reporting.insert(peer);
+        for (channel, received) in receipt.received.into_iter().enumerate() {
+            let reported = self.reported.entry((peer, channel)).or_default();
+            *reported = (*reported).max(received);
+        }
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Whether receipts are sent to a peer, either to ask it to report what it
+    /// received or because it asked.
+    pub(crate) fn wants_receipts(&self, peer: PeerId, config: &BackpressureConfig) -> bool {
+        config.high_water_mark.is_some() || self.reporting.contains(&peer)
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Whether sends to a peer on a channel are refused.
+    pub(crate) fn is_congested(&self, peer: PeerId, channel: usize) -> bool {
+        self.congested.contains(&(peer, channel))
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Estimate the bytes buffered to each reporting peer on each reliable
+    /// channel, and which peers crossed the high-water mark either way.
+    pub(crate) fn update(
+        &mut self,
+        connected: &[PeerId],
+        channels: &RtcChannels,
+        config: &BackpressureConfig,
+    ) -> (HashMap<(PeerId, usize), usize>, Vec<CongestionEvent>) {
+        self.sent.retain(|(peer, _), _| connected.contains(peer));
+        self.received
+            .retain(|(peer, _), _| connected.contains(peer));
+        self.reported
+            .retain(|(peer, _), _| connected.contains(peer));
+        self.reporting.retain(|peer| connected.contains(peer));
+        self.congested.retain(|(peer, _)| connected.contains(peer));
+
+        let mut estimates = HashMap::new();
+        let mut events = vec![];
+        for peer in self.reporting.iter() {
+            for (channel, kind) in channels.iter() {
+                if !kind.is_reliable() {
+                    continue;
+                }
+                let key = (*peer, channel);
+                let sent = self.sent.get(&key).copied().unwrap_or_default();
+                let reported = self.reported.get(&key).copied().unwrap_or_default();
+                let buffered = sent.saturating_sub(reported) as usize;
+                estimates.insert(key, buffered);
+
+                let congested = config
+                    .high_water_mark
+                    .is_some_and(|high_water_mark| buffered >= high_water_mark);
+                let changed = if congested {
+                    self.congested.insert(key)
+                } else {
+                    self.congested.remove(&key)
+                };
+                if changed {
+                    events.push(CongestionEvent {
+                        peer: *peer,
+                        channel,
+                        buffered,
+                        congested,
+                    });
+                }
+            }
+        }
+        (estimates, events)
+    }
+}
+
+#[cfg(all(test, any(feature = "client", feature = "server")))]
+mod tests {
+    use super::*;
+    use crate::socket::{RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX};
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    fn receipt(reliable: u64) -> ReceiptPayload {
+        let mut received = vec![0; 2];
+        received[RELIABLE_CHANNEL_INDEX] = reliable;
+        ReceiptPayload { received }
+    }
+
+    #[test]
+    fn estimates_what_was_sent_but_not_reported() {
+        let (channels, config) = (RtcChannels::default(), BackpressureConfig::default());
+        let mut buffered = Buffered::default();
+        buffered.record_sent(PEER, RELIABLE_CHANNEL_INDEX, 1000);
+        buffered.record_sent(PEER, UNRELIABLE_CHANNEL_INDEX, 1000);
+
+        // Peers which never report are not estimated
+        let (estimates, _) = buffered.update(&[PEER], &channels, &config);
+        assert!(estimates.is_empty());
+
+        buffered.acknowledge(PEER, receipt(400));
+        // An older report arriving late does not lower the count
+        buffered.acknowledge(PEER, receipt(100));
+        let (estimates, events) = buffered.update(&[PEER], &channels, &config);
+        assert_eq!(
+            estimates,
+            HashMap::from([((PEER, RELIABLE_CHANNEL_INDEX), 600)])
+        );
+        assert!(events.is_empty());
+
+        let (estimates, _) = buffered.update(&[], &channels, &config);
+        assert!(estimates.is_empty());
+    }
+
+    #[test]
+    fn crossing_the_high_water_mark_is_reported_both_ways() {
+        let channels = RtcChannels::default();
+        let config = BackpressureConfig {
+            high_water_mark: Some(500),
+        };
+        let mut buffered = Buffered::default();
+        buffered.record_sent(PEER, RELIABLE_CHANNEL_INDEX, 1000);
+        buffered.acknowledge(PEER, receipt(400));
+
+        let (_, events) = buffered.update(&[PEER], &channels, &config);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].congested);
+        assert_eq!(events[0].buffered, 600);
+        assert!(buffered.is_congested(PEER, RELIABLE_CHANNEL_INDEX));
+
+        // Staying congested is not reported again
+        let (_, events) = buffered.update(&[PEER], &channels, &config);
+        assert!(events.is_empty());
+
+        buffered.acknowledge(PEER, receipt(1000));
+        let (_, events) = buffered.update(&[PEER], &channels, &config);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].congested);
+        assert!(!buffered.is_congested(PEER, RELIABLE_CHANNEL_INDEX));
+    }
+
+    #[test]
+    fn receipts_are_only_sent_when_asked_for() {
+        let mut buffered = Buffered::default();
+        assert!(!buffered.wants_receipts(PEER, &BackpressureConfig::default()));
+        let config = BackpressureConfig {
+            high_water_mark: Some(500),
+        };
+        assert!(buffered.wants_receipts(PEER, &config));
+
+        buffered.acknowledge(PEER, receipt(0));
+        assert!(buffered.wants_receipts(PEER, &BackpressureConfig::default()));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{ack::MessageId, channel::RtcChannels};
+use bevy::{prelude::*, utils::HashMap};
+use bevy_matchbox::matchbox_socket::{Packet, PeerId};
+use instant::{Duration, Instant};
+
+/// Configures a budget of bytes sent to each peer per second, so peers on slow
+/// links are not flooded.
+///
+/// Packets on reliable channels are always sent, and count against the budget.
+/// Payloads on other channels which do not fit are deferred, and chosen by
+/// their protocol's priority, accumulated over each frame they wait. Hence
+/// low priority payloads still go out eventually, unless they wait longer than
+/// `max_deferral` and are dropped. Priority only decides which payloads are
+/// sent in a frame; those sent keep the order they were queued in.
+#[derive(Resource, Debug, Clone)]
+pub struct BandwidthConfig {
+    /// The bytes sent to each peer per second. Unlimited by default.
+    pub bytes_per_second: Option<usize>,
+    /// Budgets for particular peers, overriding `bytes_per_second`.
+    pub peer_bytes_per_second: HashMap<PeerId, usize>,
+    /// How long a payload is deferred before it is dropped. Defaults to 250ms.
+    pub max_deferral: Duration,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            bytes_per_second: None,
+            peer_bytes_per_second: HashMap::new(),
+            max_deferral: Duration::from_millis(250),
+        }
+    }
+}
+
+impl BandwidthConfig {
+    /// The budget of a peer, if it has one.
+    pub fn budget(&self, peer: PeerId) -> Option<usize> {
+        self.peer_bytes_per_second
+            .get(&peer)
+            .copied()
+            .or(self.bytes_per_second)
+    }
+}
+
+/// A payload queued to be sent, split into the packets which carry it.
+#[derive(Debug)]
+pub(crate) struct QueuedMessage {
+    /// The name of the protocol of the payload
+    pub protocol: &'static str,
+    pub priority: f32,
+    /// The order the payload was queued in, across protocols
+    pub enqueued: u64,
+    pub packets: Vec<Packet>,
+    /// The ID of the payload, if its delivery is tracked
+    pub tracked: Option<MessageId>,
+}
+
+impl QueuedMessage {
+    fn len(&self) -> usize {
+        self.packets.iter().map(|packet| packet.len()).sum()
+    }
+}
+
+/// A payload which did not fit in its peer's budget.
+#[derive(Debug)]
+struct Deferred {
+    channel: usize,
+    message: QueuedMessage,
+    /// The priority accumulated while waiting
+    accumulated: f32,
+    since: Instant,
+}
+
+/// The budget remaining for a peer, and the payloads waiting to be sent.
+#[derive(Debug)]
+struct PeerBandwidth {
+    /// Bytes which can be sent now. Negative after sending a payload larger
+    /// than what remained.
+    allowance: f64,
+    last_update: Instant,
+    deferred: Vec<Deferred>,
+}
+
+/// Tracks the budget of each peer, see [`BandwidthConfig`].
+#[derive(Resource, Default, Debug)]
+pub(crate) struct Bandwidth {
+    peers: HashMap<PeerId, PeerBandwidth>,
+}
+
+impl Bandwidth {
+    /// Peers with deferred payloads, which must be scheduled even if nothing
+    /// new is queued for them.
+    pub(crate) fn deferred_peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| !peer.deferred.is_empty())
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Forget peers which are no longer connected.
+    pub(crate) fn prune(&mut self, connected: &[PeerId]) {
+        self.peers.retain(|peer, _| connected.contains(peer));
+    }
+
+    /// Choose which of the payloads queued for a peer, and those deferred
+    /// before, are sent now. Returns the payloads to send with their channels,
+    /// and those dropped for waiting too long.
+    pub(crate) fn schedule(
+        &mut self,
+        peer: PeerId,
+        queued: Vec<(usize, QueuedMessage)>,
+        channels: &RtcChannels,
+        config: &BandwidthConfig,
+    ) -> (Vec<(usize, QueuedMessage)>, Vec<QueuedMessage>) {
+        let now = Instant::now();
+        let Some(budget) = config.budget(peer) else {
+            // Send anything deferred before the budget was removed
+            let mut send: Vec<_> = self
+                .peers
+                .remove(&peer)
+                .into_iter()
+                .flat_map(|peer| peer.deferred)
+                .map(|deferred| (deferred.channel, deferred.message))
+                .collect();
+            send.extend(queued);
+            return (send, vec![]);
+        };
+        let state = self.peers.entry(peer).or_insert_with(|| PeerBandwidth {
+            allowance: budget as f64,
+            last_update: now,
+            deferred: vec![],
+        });
+        let elapsed = now.duration_since(state.last_update).as_secs_f64();
+        state.allowance = (state.allowance + budget as f64 * elapsed).min(budget as f64);
+        state.last_update = now;
+
+        let mut send = vec![];
+        let mut candidates = std::mem::take(&mut state.deferred);
+        for (channel, message) in queued {
+            let reliable = channels.kind(channel).is_none_or(|kind| kind.is_reliable());
+            if reliable {
+                state.allowance -= message.len() as f64;
+                send.push((channel, message));
+            } else {
+                candidates.push(Deferred {
+                    channel,
+                    accumulated: 0.0,
+                    message,
+                    since: now,
+                });
+            }
+        }
+
+        // Payloads waiting longest have accumulated the most priority
+        for candidate in candidates.iter_mut() {
+            candidate.accumulated += candidate.message.priority;
+        }
+        // Among equal priorities, those queued first are chosen first
+        candidates.sort_by(|a, b| {
+            b.accumulated
+                .total_cmp(&a.accumulated)
+                .then(a.message.enqueued.cmp(&b.message.enqueued))
+        });
+        let mut dropped = vec![];
+        for candidate in candidates {
+            if state.allowance > 0.0 {
+                state.allowance -= candidate.message.len() as f64;
+                send.push((candidate.channel, candidate.message));
+            } else if now.duration_since(candidate.since) >= config.max_deferral {
+                dropped.push(candidate.message);
+            } else {
+                state.deferred.push(candidate);
+            }
+        }
+        send.sort_by_key(|(_, message)| message.enqueued);
+        (send, dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::{RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX};
+
+    fn message(protocol: &'static str, priority: f32, enqueued: u64) -> (usize, QueuedMessage) {
+        (
+            UNRELIABLE_CHANNEL_INDEX,
+            QueuedMessage {
+                protocol,
+                priority,
+                enqueued,
+                packets: vec![vec![0; 100].into_boxed_slice()],
+                tracked: None,
+            },
+        )
+    }
+
+    fn enqueued(send: &[(usize, QueuedMessage)]) -> Vec<u64> {
+        send.iter().map(|(_, message)| message.enqueued).collect()
+    }
+
+    #[test]
+    fn sent_payloads_keep_their_order() {
+        let peer = PeerId(uuid::Uuid::from_u128(1));
+        let config = BandwidthConfig {
+            bytes_per_second: Some(10_000),
+            ..default()
+        };
+        let queued = vec![
+            message("low", 1.0, 0),
+            message("high", 4.0, 1),
+            message("low", 1.0, 2),
+        ];
+        let (send, dropped) =
+            Bandwidth::default().schedule(peer, queued, &RtcChannels::default(), &config);
+        assert_eq!(enqueued(&send), vec![0, 1, 2]);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn priority_chooses_what_is_sent() {
+        let peer = PeerId(uuid::Uuid::from_u128(1));
+        // Each payload overdraws the budget, so one is sent per frame
+        let config = BandwidthConfig {
+            bytes_per_second: Some(1),
+            ..default()
+        };
+        let mut bandwidth = Bandwidth::default();
+        let queued = vec![
+            message("low", 1.0, 0),
+            message("high", 4.0, 1),
+            message("high", 4.0, 2),
+        ];
+        // Of the equal priorities, the payload queued first goes
+        let (send, _) = bandwidth.schedule(peer, queued, &RtcChannels::default(), &config);
+        assert_eq!(enqueued(&send), vec![1]);
+
+        bandwidth.peers.get_mut(&peer).unwrap().allowance = 1.0;
+        let (send, _) = bandwidth.schedule(peer, vec![], &RtcChannels::default(), &config);
+        assert_eq!(enqueued(&send), vec![2]);
+    }
+
+    #[test]
+    fn payloads_deferred_too_long_are_dropped() {
+        let peer = PeerId(uuid::Uuid::from_u128(1));
+        let config = BandwidthConfig {
+            bytes_per_second: Some(1),
+            max_deferral: Duration::ZERO,
+            ..default()
+        };
+        let queued = vec![message("a", 1.0, 0), message("b", 1.0, 1)];
+        let (send, dropped) =
+            Bandwidth::default().schedule(peer, queued, &RtcChannels::default(), &config);
+        assert_eq!(enqueued(&send), vec![0]);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].enqueued, 1);
+    }
+
+    #[test]
+    fn reliable_payloads_bypass_the_budget() {
+        let peer = PeerId(uuid::Uuid::from_u128(1));
+        let config = BandwidthConfig {
+            bytes_per_second: Some(1),
+            ..default()
+        };
+        let reliable = |enqueued| {
+            let (_, message) = message("reliable", 1.0, enqueued);
+            (RELIABLE_CHANNEL_INDEX, message)
+        };
+        let mut bandwidth = Bandwidth::default();
+        let queued = vec![reliable(0), message("unreliable", 1.0, 1), reliable(2)];
+        let (send, dropped) = bandwidth.schedule(peer, queued, &RtcChannels::default(), &config);
+        assert_eq!(enqueued(&send), vec![0, 2]);
+        assert!(dropped.is_empty());
+        // They still count against the budget
+        let state = &bandwidth.peers[&peer];
+        assert!(state.allowance < 0.0);
+        assert_eq!(state.deferred.len(), 1);
+    }
+
+    #[test]
+    fn low_priority_payloads_are_not_starved() {
+        let peer = PeerId(uuid::Uuid::from_u128(1));
+        // Each payload overdraws the budget, so one is sent per frame
+        let config = BandwidthConfig {
+            bytes_per_second: Some(1),
+            max_deferral: Duration::from_secs(60),
+            ..default()
+        };
+        let mut bandwidth = Bandwidth::default();
+        let mut queued = vec![message("low", 1.0, 0)];
+        // A new high priority payload is queued every frame, yet the low
+        // priority payload catches up as it waits
+        for frame in 1..=4 {
+            queued.push(message("high", 4.0, frame));
+            let (send, _) = bandwidth.schedule(peer, queued, &RtcChannels::default(), &config);
+            if send.iter().any(|(_, message)| message.protocol == "low") {
+                return;
+            }
+            bandwidth.peers.get_mut(&peer).unwrap().allowance = 1.0;
+            queued = vec![];
+        }
+        panic!("the low priority payload was never sent");
+    }
+}
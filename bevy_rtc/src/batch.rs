@@ -0,0 +1,212 @@
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::protocol::RtcPacketHeader;
+use matchbox_socket::Packet;
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Configures coalescing of the packets sent to a peer each frame into
+/// batches, which saves the overhead of a data channel message per packet.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone)]
+pub struct BatchingConfig {
+    /// Whether packets are batched. Disabled by default.
+    pub enabled: bool,
+    /// The largest batch to build, in bytes. Packets which are larger on their
+    /// own are sent unbatched. Defaults to 1200 bytes, which fits in a single
+    /// datagram on most links.
+    pub max_batch_size: usize,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_batch_size: 1200,
+        }
+    }
+}
+
+/// The length prefix of each packet in a batch.
+const LEN_PREFIX: usize = 2;
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// The packets of a batch being built.
+struct Batch<T> {
+    packets: Vec<(T, Packet)>,
+    size: usize,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Self {
+            packets: vec![],
+            size: 0,
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl<T> Batch<T> {
+    /// The encoded size of the batch if a packet of `len` bytes were added.
+    fn size_with(&self, len: usize) -> usize {
+        RtcPacketHeader::LEN + self.size + LEN_PREFIX + len
+    }
+
+    fn push(&mut self, protocol: T, packet: Packet) {
+        self.size += LEN_PREFIX + packet.len();
+        self.packets.push((protocol, packet));
+    }
+
+    /// Frame the batch, with the protocols of the packets it carries. A batch
+    /// of one packet is sent without the batch framing.
+    fn finish(mut self) -> Option<(Vec<T>, Packet)> {
+        if self.packets.len() <= 1 {
+            return self
+                .packets
+                .pop()
+                .map(|(protocol, packet)| (vec![protocol], packet));
+        }
+        let mut buf = Vec::with_capacity(RtcPacketHeader::LEN + self.size);
+        RtcPacketHeader {
+            msg_id: 0,
+            version: 0,
+            codec: 0,
+            flags: RtcPacketHeader::FLAG_BATCH,
+        }
+        .write(&mut buf);
+        let mut protocols = Vec::with_capacity(self.packets.len());
+        for (protocol, packet) in self.packets {
+            buf.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&packet);
+            protocols.push(protocol);
+        }
+        Some((protocols, buf.into_boxed_slice()))
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Pack packets into as few batches as fit in `max_batch_size`, keeping their
+/// order. Each result lists the protocols of the packets it carries.
+pub(crate) fn pack<T>(
+    packets: impl IntoIterator<Item = (T, Packet)>,
+    max_batch_size: usize,
+) -> Vec<(Vec<T>, Packet)> {
+    let mut packed = vec![];
+    let mut batch = Batch::default();
+    for (protocol, packet) in packets {
+        if packet.len() > u16::MAX as usize
+            || Batch::<T>::default().size_with(packet.len()) > max_batch_size
+        {
+            packed.extend(std::mem::take(&mut batch).finish());
+            packed.push((vec![protocol], packet));
+            continue;
+        }
+        if batch.size_with(packet.len()) > max_batch_size {
+            packed.extend(std::mem::take(&mut batch).finish());
+        }
+        batch.push(protocol, packet);
+    }
+    packed.extend(batch.finish());
+    packed
+}
+
+/// Unpack the packets in a batch, without their batch header. Returns `None`
+/// if the batch is malformed.
+pub(crate) fn unpack(mut data: &[u8]) -> Option<Vec<Packet>> {
+    let mut packets = vec![];
+    while !data.is_empty() {
+        if data.len() < LEN_PREFIX {
+            return None;
+        }
+        let (len, rest) = data.split_at(LEN_PREFIX);
+        let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+        if rest.len() < len {
+            return None;
+        }
+        let (packet, rest) = rest.split_at(len);
+        packets.push(packet.into());
+        data = rest;
+    }
+    Some(packets)
+}
+
+#[cfg(all(test, any(feature = "client", feature = "server")))]
+mod tests {
+    use super::*;
+
+    fn packet(len: usize) -> Packet {
+        vec![len as u8; len].into_boxed_slice()
+    }
+
+    /// The packets in a packed message, unpacking batches.
+    fn unpacked(packet: &Packet) -> Vec<Packet> {
+        match RtcPacketHeader::read(packet) {
+            Some((header, data)) if header.has_flag(RtcPacketHeader::FLAG_BATCH) => {
+                unpack(data).unwrap()
+            }
+            _ => vec![packet.clone()],
+        }
+    }
+
+    #[test]
+    fn packs_and_unpacks_in_order() {
+        let packets: Vec<(usize, Packet)> = (1..=5).map(|i| (i, packet(10 * i))).collect();
+        let packed = pack(packets.clone(), 1200);
+        assert_eq!(packed.len(), 1);
+        let (tags, batch) = &packed[0];
+        assert_eq!(tags, &[1, 2, 3, 4, 5]);
+        let originals: Vec<Packet> = packets.into_iter().map(|(_, packet)| packet).collect();
+        assert_eq!(unpacked(batch), originals);
+    }
+
+    #[test]
+    fn batches_fill_exactly_to_the_limit() {
+        // Two packets of 20 bytes take the header and two length prefixes
+        let max_batch_size = RtcPacketHeader::LEN + 2 * (LEN_PREFIX + 20);
+        let packed = pack(
+            [(0, packet(20)), (1, packet(20)), (2, packet(20))],
+            max_batch_size,
+        );
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed[0].0, vec![0, 1]);
+        assert_eq!(packed[0].1.len(), max_batch_size);
+        // A batch of one is sent without the batch framing
+        assert_eq!(packed[1], (vec![2], packet(20)));
+    }
+
+    #[test]
+    fn batches_never_exceed_the_limit() {
+        let max_batch_size = 100;
+        let packets = (0..50).map(|i| (i, packet(i % 40 + 1)));
+        let packed = pack(packets, max_batch_size);
+        assert!(packed
+            .iter()
+            .all(|(_, packet)| packet.len() <= max_batch_size));
+        let tags: Vec<usize> = packed.iter().flat_map(|(tags, _)| tags.clone()).collect();
+        assert_eq!(tags, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn oversized_packets_are_sent_alone() {
+        let packed = pack([(0, packet(5)), (1, packet(200)), (2, packet(5))], 100);
+        assert_eq!(
+            packed,
+            vec![
+                (vec![0], packet(5)),
+                (vec![1], packet(200)),
+                (vec![2], packet(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_batches_are_rejected() {
+        assert_eq!(unpack(&[]), Some(vec![]));
+        assert_eq!(unpack(&[1]), None);
+        assert_eq!(unpack(&[3, 0, 1, 2]), None);
+    }
+}
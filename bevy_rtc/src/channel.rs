@@ -0,0 +1,136 @@
+//! Data channels of the socket.
+//!
+//! Every payload is sent on one of the channels in [`RtcChannels`], which the
+//! client and server must configure identically.
+
+use crate::socket::{RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX};
+use matchbox_socket::{
+    ChannelConfig, MultipleChannels, Packet, PeerId, WebRtcSocket, WebRtcSocketBuilder,
+};
+
+/// How a data channel delivers messages.
+///
+/// WebRTC can also bound retransmissions by time rather than by count, but the
+/// socket does not expose it. Use [`ChannelKind::PartiallyReliable`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// Messages are sent once, and may be lost or arrive out of order
+    UnreliableUnordered,
+    /// Messages are sent once, and may be lost, but arrive in order
+    UnreliableOrdered,
+    /// Messages are retransmitted until delivered, and may arrive out of order
+    ReliableUnordered,
+    /// Messages are retransmitted until delivered, and arrive in order
+    ReliableOrdered,
+    /// Messages are retransmitted at most `max_retransmits` times
+    PartiallyReliable { ordered: bool, max_retransmits: u16 },
+}
+
+impl ChannelKind {
+    /// Whether every message sent on the channel is delivered.
+    pub fn is_reliable(&self) -> bool {
+        matches!(self, Self::ReliableUnordered | Self::ReliableOrdered)
+    }
+
+    /// The data channel configuration of this kind.
+    pub(crate) fn config(&self) -> ChannelConfig {
+        let (ordered, max_retransmits) = match *self {
+            Self::UnreliableUnordered => (false, Some(0)),
+            Self::UnreliableOrdered => (true, Some(0)),
+            Self::ReliableUnordered => (false, None),
+            Self::ReliableOrdered => (true, None),
+            Self::PartiallyReliable {
+                ordered,
+                max_retransmits,
+            } => (ordered, Some(max_retransmits)),
+        };
+        ChannelConfig {
+            ordered,
+            max_retransmits,
+        }
+    }
+}
+
+/// The data channels opened on the socket, by index. The client and server
+/// must use the same channels, so define them once, e.g. in a crate of
+/// protocols shared by both, and insert the resource on each app before
+/// connecting.
+///
+/// The first two channels are the unreliable and reliable channels used by the
+/// `unreliable_*` and `reliable_*` methods of the network writers. Channels
+/// added with [`RtcChannels::with_channel`] follow, from index 2.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtcChannels {
+    kinds: Vec<ChannelKind>,
+}
+
+impl Default for RtcChannels {
+    fn default() -> Self {
+        Self {
+            kinds: vec![
+                // Match UNRELIABLE_CHANNEL_INDEX
+                ChannelKind::UnreliableOrdered,
+                // Match RELIABLE_CHANNEL_INDEX
+                ChannelKind::ReliableOrdered,
+            ],
+        }
+    }
+}
+
+impl RtcChannels {
+    /// Add a channel, at the next index.
+    pub fn with_channel(mut self, kind: ChannelKind) -> Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// The kind of the channel at an index, if it exists.
+    pub fn kind(&self, channel: usize) -> Option<ChannelKind> {
+        self.kinds.get(channel).copied()
+    }
+
+    /// Iterate on the channels and their indices.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, ChannelKind)> + '_ {
+        self.kinds.iter().copied().enumerate()
+    }
+
+    /// Add the channels to a socket builder.
+    pub(crate) fn add_to(
+        &self,
+        builder: WebRtcSocketBuilder,
+    ) -> WebRtcSocketBuilder<MultipleChannels> {
+        let builder = builder
+            .add_channel(self.kinds[UNRELIABLE_CHANNEL_INDEX].config())
+            .add_channel(self.kinds[RELIABLE_CHANNEL_INDEX].config());
+        self.kinds[2..]
+            .iter()
+            .fold(builder, |builder, kind| builder.add_channel(kind.config()))
+    }
+
+    /// Take the messages received on every channel, with the channel index and
+    /// whether the channel is reliable. Reliable channels are read first.
+    pub(crate) fn receive(
+        &self,
+        socket: &mut WebRtcSocket<MultipleChannels>,
+    ) -> Vec<(usize, bool, (PeerId, Packet))> {
+        let (reliable, unreliable): (Vec<_>, Vec<_>) =
+            self.iter().partition(|(_, kind)| kind.is_reliable());
+        reliable
+            .into_iter()
+            .chain(unreliable)
+            .filter_map(|(channel, kind)| {
+                let messages = socket.get_channel_mut(channel).ok()?.receive();
+                Some(
+                    messages
+                        .into_iter()
+                        .map(move |message| (channel, kind.is_reliable(), message)),
+                )
+            })
+            .flatten()
+            .collect()
+    }
+}
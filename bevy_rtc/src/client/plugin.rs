@@ -2,9 +2,21 @@ use super::{
     systems, AddProtocolExt, ConnectionRequest, RtcClientEvent, RtcClientStatus, RtcState,
 };
 use crate::{
-    events::SocketRecvEvent,
+    ack::{AckConfig, Acks},
+    backpressure::{BackpressureConfig, Buffered, ReceiptPayload},
+    bandwidth::{Bandwidth, BandwidthConfig},
+    batch::BatchingConfig,
+    channel::RtcChannels,
+    compression::CompressionConfig,
+    events::{
+        CongestionEvent, DeliveryEvent, NetworkSendError, ProtocolOverflow, UnknownPayloadEvent,
+    },
+    fragment::{FragmentationConfig, Fragmenter, Reassembler},
     latency::LatencyTracerPayload,
-    socket::{common_socket_reader, RtcSocket},
+    manifest::ProtocolManifest,
+    order::OrderedMessages,
+    overflow::{DisconnectPayload, Disconnections},
+    socket::{common_socket_reader, common_socket_writer, Outbox, RoutedPackets, RtcSocket},
 };
 use bevy::{prelude::*, time::common_conditions::on_timer};
 use instant::Duration;
@@ -14,9 +26,32 @@ pub struct RtcClientPlugin;
 
 impl Plugin for RtcClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<SocketRecvEvent>()
+        app.add_event::<UnknownPayloadEvent>()
+            .add_event::<NetworkSendError>()
+            .add_event::<DeliveryEvent>()
+            .add_event::<CongestionEvent>()
+            .add_event::<ProtocolOverflow>()
+            .init_resource::<RoutedPackets>()
+            .init_resource::<OrderedMessages>()
+            .init_resource::<Disconnections>()
+            .init_resource::<FragmentationConfig>()
+            .init_resource::<Fragmenter>()
+            .init_resource::<Reassembler>()
+            .init_resource::<BatchingConfig>()
+            .init_resource::<BandwidthConfig>()
+            .init_resource::<Bandwidth>()
+            .init_resource::<AckConfig>()
+            .init_resource::<Acks>()
+            .init_resource::<BackpressureConfig>()
+            .init_resource::<Buffered>()
+            .init_resource::<CompressionConfig>()
+            .init_resource::<RtcChannels>()
+            .init_resource::<Outbox>()
             .insert_resource(RtcState::default())
             .add_bounded_protocol::<LatencyTracerPayload>(2)
+            .add_bounded_protocol::<ProtocolManifest>(1)
+            .add_bounded_protocol::<ReceiptPayload>(4)
+            .add_bounded_protocol::<DisconnectPayload>(1)
             .init_state::<RtcClientStatus>()
             .add_event::<ConnectionRequest>()
             .add_event::<RtcClientEvent>()
@@ -25,6 +60,7 @@ impl Plugin for RtcClientPlugin {
                 OnEnter(RtcClientStatus::Disconnected),
                 systems::reset_socket,
             )
+            .add_systems(OnEnter(RtcClientStatus::Connected), systems::send_manifest)
             .add_systems(First, systems::connection_request_handler)
             .add_systems(
                 First,
@@ -32,6 +68,10 @@ impl Plugin for RtcClientPlugin {
                     .chain()
                     .run_if(resource_exists::<RtcSocket>),
             )
+            .add_systems(
+                Last,
+                common_socket_writer.run_if(resource_exists::<RtcSocket>),
+            )
             .add_systems(
                 First,
                 systems::calculate_latency
@@ -41,8 +81,12 @@ impl Plugin for RtcClientPlugin {
             .add_systems(
                 Update,
                 (
+                    systems::read_manifest,
+                    systems::read_disconnects,
                     systems::read_latency_tracers,
                     systems::send_latency_tracers.run_if(on_timer(Duration::from_millis(100))),
+                    systems::read_receipts,
+                    systems::send_receipts.run_if(on_timer(Duration::from_millis(100))),
                 )
                     .run_if(in_state(RtcClientStatus::Connected)),
             );
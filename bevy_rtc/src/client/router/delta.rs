@@ -0,0 +1,46 @@
+use super::{IncomingMessages, OutgoingMessages};
+use crate::{
+    delta::{DeltaAck, DeltaHistory, DeltaUpdate},
+    order::next_enqueued,
+    protocol::DeltaPayload,
+    socket::UNRELIABLE_CHANNEL_INDEX,
+};
+use bevy::prelude::*;
+
+impl<M: DeltaPayload> DeltaHistory<M> {
+    /// Apply the updates received this frame and acknowledge them.
+    pub(crate) fn receive_deltas(
+        mut history: ResMut<Self>,
+        mut updates: ResMut<IncomingMessages<DeltaUpdate<M>>>,
+        mut incoming: ResMut<IncomingMessages<M>>,
+        mut acks: ResMut<OutgoingMessages<DeltaAck<M>>>,
+    ) {
+        for (meta, update) in updates.messages.drain(..) {
+            let sequence = update.sequence;
+            match history.apply(update) {
+                Some(state) => {
+                    incoming.messages.push_back((meta, state));
+                    acks.to_host.push((
+                        next_enqueued(),
+                        UNRELIABLE_CHANNEL_INDEX,
+                        DeltaAck::new(sequence),
+                    ));
+                }
+                None => trace!(
+                    "Discarded {} update {sequence}, which is stale or has an unknown baseline",
+                    M::reflect_name()
+                ),
+            }
+        }
+        // Only the most recent states are kept
+        let bound = incoming.bound;
+        while incoming.messages.len() > bound {
+            incoming.messages.pop_front();
+        }
+    }
+
+    /// Forget the states received from the previous host.
+    pub(crate) fn reset(mut history: ResMut<Self>) {
+        history.clear();
+    }
+}
@@ -1,36 +1,128 @@
-use crate::{events::SocketRecvEvent, protocol::Payload};
+use crate::{
+    events::ProtocolOverflow,
+    migration::Migrations,
+    order::OrderedMessages,
+    overflow::{Disconnections, OverflowPolicy},
+    protocol::{Payload, RtcPacketHeader},
+    sequence::Sequences,
+    socket::{MessageMeta, RoutedPackets},
+};
 use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::Packet;
 use std::collections::VecDeque;
 
-#[derive(Default, Debug, Resource)]
+#[derive(Debug, Resource)]
 pub struct IncomingMessages<M: Payload> {
+    pub decode: fn(&Packet) -> Option<M>,
+    /// Decoders for older versions of the payload
+    pub migrations: Migrations<M>,
     pub bound: usize,
-    pub messages: VecDeque<M>,
+    /// Payloads received from the host, with how each was received
+    pub messages: VecDeque<(MessageMeta, M)>,
+    /// The newest sequence number delivered from the host, if the protocol is
+    /// sequenced
+    pub(crate) sequences: Option<Sequences>,
+    /// Whether payloads are read in arrival order with other ordered
+    /// protocols, rather than buffered here
+    pub(crate) ordered: bool,
+    /// What is dropped when more than `bound` payloads are buffered
+    pub(crate) overflow: OverflowPolicy,
 }
 
 impl<M: Payload> IncomingMessages<M> {
-    pub fn receive_payloads(mut incoming: ResMut<Self>, mut events: EventReader<SocketRecvEvent>) {
-        let bound = incoming.bound;
-        let packets: Vec<_> = events
-            .read()
-            .map(|&SocketRecvEvent((_peer_id, ref packet))| packet)
-            .filter_map(M::from_packet)
-            .enumerate()
-            .take_while(|(read, _)| *read <= bound)
-            .map(|(_, packet)| packet)
+    pub(crate) fn new(decode: fn(&Packet) -> Option<M>, bound: usize) -> Self {
+        Self {
+            decode,
+            migrations: Migrations::default(),
+            bound,
+            messages: VecDeque::new(),
+            sequences: None,
+            ordered: false,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+
+    /// Decode a packet, migrating it if it is an older version of the payload.
+    fn decode_packet(&self, packet: &Packet) -> Option<M> {
+        match RtcPacketHeader::read(packet) {
+            Some((header, _)) if header.version != M::version() => {
+                self.migrations.decode(header.version, packet)
+            }
+            _ => (self.decode)(packet),
+        }
+    }
+
+    pub(crate) fn receive_payloads(
+        mut incoming: ResMut<Self>,
+        mut routed: ResMut<RoutedPackets>,
+        mut ordered: ResMut<OrderedMessages>,
+        mut disconnections: ResMut<Disconnections>,
+        mut overflow_wtr: EventWriter<ProtocolOverflow>,
+    ) {
+        let mut received: Vec<_> = routed.drain(M::id()).collect();
+        if let Some(sequences) = incoming.sequences.as_mut() {
+            // Only packets newer than the newest delivered are fresh
+            received.retain(|(peer_id, received)| {
+                received.sequence.is_none_or(|sequence| {
+                    let fresh = sequences.deliver(*peer_id, sequence);
+                    if !fresh {
+                        trace!("Dropped stale {} packet {sequence}", M::reflect_name());
+                    }
+                    fresh
+                })
+            });
+        }
+        let packets: Vec<_> = received
+            .into_iter()
+            .filter_map(|(peer_id, received)| {
+                let payload = incoming.decode_packet(&received.packet);
+                if payload.is_none() {
+                    warn!(
+                        "Failed to decode {} packet from {peer_id}",
+                        M::reflect_name()
+                    );
+                }
+                payload.map(|payload| (received.arrival, received.meta, payload))
+            })
             .collect();
         trace!("Read {} {} packets", packets.len(), M::reflect_name());
-        for packet in packets.into_iter() {
-            incoming.messages.push_back(packet);
+        let Some(host) = packets.first().map(|(_, meta, _)| meta.peer) else {
+            return;
+        };
+        let (bound, overflow) = (incoming.bound, incoming.overflow);
+        let dropped = if incoming.ordered {
+            let payloads = packets
+                .into_iter()
+                .map(|(arrival, _, payload)| (arrival, payload))
+                .collect();
+            ordered.push(host, payloads, bound, overflow)
+        } else {
+            let payloads = packets
+                .into_iter()
+                .map(|(_, meta, payload)| (meta, payload));
+            overflow.buffer(&mut incoming.messages, payloads, bound)
+        };
+        if dropped == 0 {
+            return;
         }
-        if incoming.messages.len() > bound {
-            warn!(
-                "The `{}` protocol is overflowing its bounded buffer ({bound}) and dropping packets! Is it being read?",
-                M::reflect_name()
-            );
-            while incoming.messages.len() > bound {
-                incoming.messages.pop_front();
-            }
+        debug!(
+            "The `{}` protocol dropped {dropped} packets with its bounded buffer ({bound}) full",
+            M::reflect_name()
+        );
+        overflow_wtr.send(ProtocolOverflow {
+            peer: host,
+            protocol: M::reflect_name(),
+            dropped,
+        });
+        if overflow == OverflowPolicy::IgnoreAndRequestDisconnect {
+            disconnections.request(host, M::reflect_name());
+        }
+    }
+
+    /// Forget the sequence numbers received from the previous host.
+    pub(crate) fn reset_sequences(mut incoming: ResMut<Self>) {
+        if let Some(sequences) = incoming.sequences.as_mut() {
+            *sequences = Sequences::default();
         }
     }
 }
@@ -1,60 +1,126 @@
 use crate::{
+    ack::MessageId,
     client::state::RtcState,
+    codec::CodecError,
+    compression::Compression,
+    events::NetworkSendError,
     protocol::Payload,
-    socket::{RtcSocket, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX},
+    rate::SendRate,
+    sequence::Sequences,
+    socket::{SocketSender, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX},
 };
 use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::Packet;
 
-#[derive(Default, Debug, Resource)]
+#[derive(Debug, Resource)]
 pub struct OutgoingMessages<M: Payload> {
-    pub reliable_to_host: Vec<M>,
-    pub unreliable_to_host: Vec<M>,
+    pub encode: fn(&M) -> Result<Packet, CodecError>,
+    /// How encoded payloads are compressed, if at all
+    pub compression: Option<Compression>,
+    /// The channel payloads are sent on unless another is given
+    pub channel: usize,
+    /// How urgently payloads are sent when a peer's bandwidth budget is
+    /// exceeded
+    pub priority: f32,
+    /// Payloads queued this frame, with the order they were queued in across
+    /// protocols and the channel to send each on
+    pub to_host: Vec<(u64, usize, M)>,
+    /// Packets already encoded by the `try_*` methods of the network writer
+    pub encoded: Vec<(u64, usize, Packet)>,
+    /// Payloads queued this frame by the `*_acked` methods of the network
+    /// writer, whose delivery is tracked
+    pub(crate) acked_to_host: Vec<(u64, MessageId, M)>,
+    /// How often payloads of the `*_with` methods of the network writer are
+    /// sent to the host, if limited
+    pub(crate) send_rate: Option<SendRate>,
+    /// The next sequence number, if the protocol is sequenced
+    pub(crate) sequences: Option<Sequences>,
 }
 
 impl<M: Payload> OutgoingMessages<M> {
+    pub(crate) fn new(encode: fn(&M) -> Result<Packet, CodecError>) -> Self {
+        Self {
+            encode,
+            compression: None,
+            channel: RELIABLE_CHANNEL_INDEX,
+            priority: 1.0,
+            to_host: vec![],
+            encoded: vec![],
+            acked_to_host: vec![],
+            send_rate: None,
+            sequences: None,
+        }
+    }
+
     /// Swaps the event buffers and clears the oldest event buffer. In general,
     /// this should be called once per frame/update.
     pub fn flush(&mut self) {
-        self.reliable_to_host.clear();
-        self.unreliable_to_host.clear();
+        self.to_host.clear();
+        self.encoded.clear();
+        self.acked_to_host.clear();
+    }
+
+    /// Encode a payload, compressing it if enabled for this protocol.
+    pub(crate) fn encode_packet(&self, message: &M) -> Result<Packet, CodecError> {
+        let packet = (self.encode)(message)?;
+        Ok(match self.compression {
+            Some(compression) => compression.compress(packet),
+            None => packet,
+        })
     }
 
     pub(crate) fn send_payloads(
         mut queue: ResMut<Self>,
-        mut socket: ResMut<RtcSocket>,
+        mut sender: SocketSender,
         state: Res<RtcState>,
+        mut errors: EventWriter<NetworkSendError>,
     ) {
         if let Some(host) = state.host_id {
             // Client is sending
-            for message in queue.reliable_to_host.iter() {
-                if socket
-                    .channel_mut(RELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), host)
-                    .is_err()
-                {
-                    error!("failed to send reliable packet to {host}: {message:?}");
-                }
-            }
-            if !queue.reliable_to_host.is_empty() {
-                trace!(
-                    "sent {} [R] {} packets",
-                    queue.reliable_to_host.len(),
-                    M::reflect_name()
-                );
-            }
-            for message in queue.unreliable_to_host.iter() {
-                if socket
-                    .channel_mut(UNRELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), host)
-                    .is_err()
-                {
-                    error!("failed to send unreliable packet to {host}: {message:?}");
+            let packets: Vec<_> = queue
+                .to_host
+                .iter()
+                .map(|(enqueued, channel, message)| {
+                    (*enqueued, *channel, queue.encode_packet(message), None)
+                })
+                .chain(queue.encoded.iter().map(|(enqueued, channel, packet)| {
+                    (*enqueued, *channel, Ok(packet.clone()), None)
+                }))
+                .chain(queue.acked_to_host.iter().map(|(enqueued, id, message)| {
+                    (
+                        *enqueued,
+                        UNRELIABLE_CHANNEL_INDEX,
+                        queue.encode_packet(message),
+                        Some(*id),
+                    )
+                }))
+                .collect();
+            for (enqueued, channel, packet, tracked) in packets {
+                let packet = match queue.sequences.as_mut() {
+                    Some(sequences) => packet.map(|packet| sequences.stamp(host, packet)),
+                    None => packet,
+                };
+                if let Err(kind) = sender.send(
+                    M::reflect_name(),
+                    queue.priority,
+                    enqueued,
+                    channel,
+                    host,
+                    packet,
+                    tracked,
+                ) {
+                    error!("failed to send packet to {host} on channel {channel}: {kind}");
+                    errors.send(NetworkSendError {
+                        peer: host,
+                        protocol: M::reflect_name(),
+                        kind,
+                    });
                 }
             }
-            if !queue.unreliable_to_host.is_empty() {
+            if !queue.to_host.is_empty() || !queue.encoded.is_empty() {
                 trace!(
-                    "sent {} [U] {} packets",
-                    queue.unreliable_to_host.len(),
+                    "sent {} {} packets",
+                    queue.to_host.len() + queue.encoded.len(),
                     M::reflect_name()
                 );
             }
@@ -1,4 +1,4 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use bevy_matchbox::prelude::PeerId;
 use instant::Duration;
 
@@ -14,7 +14,7 @@ pub enum RtcClientStatus {
     Connected,
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource, Default, Debug)]
 pub struct RtcState {
     /// The socket address, used for connecting/reconnecting
     pub addr: Option<String>,
@@ -26,4 +26,6 @@ pub struct RtcState {
     pub latency: Option<Duration>,
     /// The smooth latency to the server
     pub smoothed_latency: Option<Duration>,
+    /// The bytes estimated buffered to the server, by reliable channel
+    pub buffered: HashMap<usize, usize>,
 }
@@ -1,6 +1,20 @@
-use super::router::{IncomingMessages, OutgoingMessages};
-use crate::protocol::Payload;
+use super::{
+    router::{IncomingMessages, OutgoingMessages},
+    RtcState,
+};
+use crate::{
+    ack::MessageId,
+    backpressure::Buffered,
+    events::SendErrorKind,
+    order::next_enqueued,
+    protocol::Payload,
+    socket::{
+        check_channel, MessageMeta, RtcSocket, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX,
+    },
+};
 use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_matchbox::matchbox_socket::Packet;
+use instant::Instant;
 
 #[derive(SystemParam, Debug)]
 pub struct NetworkReader<'w, M: Payload> {
@@ -24,7 +38,13 @@ impl<'w, M: Payload> NetworkReader<'w, M> {
     }
 
     /// Consumes all messages in the buffer and iterate on them.
-    pub fn read(&mut self) -> std::collections::vec_deque::Drain<'_, M> {
+    pub fn read(&mut self) -> impl ExactSizeIterator<Item = M> + '_ {
+        self.incoming.messages.drain(..).map(|(_, message)| message)
+    }
+
+    /// Consumes all messages in the buffer with the channel, time and frame
+    /// each was received in, and its size on the wire.
+    pub fn read_with_meta(&mut self) -> std::collections::vec_deque::Drain<'_, (MessageMeta, M)> {
         self.incoming.messages.drain(..)
     }
 }
@@ -32,28 +52,138 @@ impl<'w, M: Payload> NetworkReader<'w, M> {
 #[derive(SystemParam, Debug)]
 pub struct NetworkWriter<'w, M: Payload> {
     pub(crate) outgoing: ResMut<'w, OutgoingMessages<M>>,
+    state: Res<'w, RtcState>,
+    socket: Option<Res<'w, RtcSocket>>,
+    buffered: Res<'w, Buffered>,
 }
 
 impl<'w, M: Payload> NetworkWriter<'w, M> {
-    /// Send a payload to the host with reliability. The payload is created with
-    /// lazy behavior, only when the send rate allows.
-    pub fn reliable_to_host_with(&mut self, message_fn: impl Fn() -> M) {
-        self.outgoing.reliable_to_host.push(message_fn());
+    /// Send a payload to the host with reliability. The payload is created
+    /// lazily, only if the send rate set with
+    /// [`AddProtocolExt::set_protocol_send_rate`](super::AddProtocolExt::set_protocol_send_rate)
+    /// allows sending this frame, and the channel is not congested, see
+    /// [`BackpressureConfig`](super::BackpressureConfig).
+    /// Otherwise `message_fn` is not called, and nothing is sent or queued for
+    /// later.
+    ///
+    /// Returns whether `message_fn` was called and the payload queued. A
+    /// queued unreliable payload may still be deferred, or dropped, by the
+    /// [`BandwidthConfig`](super::BandwidthConfig) budget.
+    pub fn reliable_to_host_with(&mut self, message_fn: impl FnOnce() -> M) -> bool {
+        self.queue_rated(RELIABLE_CHANNEL_INDEX, message_fn)
     }
 
     /// Send a payload to the host with no expectation of delivery. The payload
-    /// is created with lazy behavior, only when the send rate allows.
-    pub fn unreliable_to_host_with(&mut self, message_fn: impl Fn() -> M) {
-        self.outgoing.unreliable_to_host.push(message_fn());
+    /// is created lazily, only if the send rate allows sending this frame,
+    /// like [`NetworkWriter::reliable_to_host_with`]. Returns whether
+    /// `message_fn` was called and the payload queued.
+    pub fn unreliable_to_host_with(&mut self, message_fn: impl FnOnce() -> M) -> bool {
+        self.queue_rated(UNRELIABLE_CHANNEL_INDEX, message_fn)
+    }
+
+    /// Create and queue a payload if the channel is not congested and the send
+    /// rate allows sending it to the host now. Returns whether it was queued.
+    fn queue_rated(&mut self, channel: usize, message_fn: impl FnOnce() -> M) -> bool {
+        let Some(host) = self.state.host_id else {
+            return false;
+        };
+        if self.buffered.is_congested(host, channel) {
+            trace!("Congestion skipped a {} payload", M::reflect_name());
+            return false;
+        }
+        let allowed = self
+            .outgoing
+            .send_rate
+            .as_mut()
+            .is_none_or(|send_rate| send_rate.try_take(host, Instant::now()));
+        if !allowed {
+            trace!("Send rate skipped a {} payload", M::reflect_name());
+            return false;
+        }
+        let message = message_fn();
+        self.outgoing
+            .to_host
+            .push((next_enqueued(), channel, message));
+        true
     }
 
     /// Send a payload to the host with reliability.
     pub fn reliable_to_host(&mut self, message: M) {
-        self.outgoing.reliable_to_host.push(message);
+        self.outgoing
+            .to_host
+            .push((next_enqueued(), RELIABLE_CHANNEL_INDEX, message));
     }
 
     /// Send a payload to the host with no expectation of delivery.
     pub fn unreliable_to_host(&mut self, message: M) {
-        self.outgoing.unreliable_to_host.push(message);
+        self.outgoing
+            .to_host
+            .push((next_enqueued(), UNRELIABLE_CHANNEL_INDEX, message));
+    }
+
+    /// Send a payload to the host with no expectation of delivery, but report
+    /// whether it arrived with a [`DeliveryEvent`](super::DeliveryEvent)
+    /// carrying the returned ID.
+    pub fn unreliable_to_host_acked(&mut self, message: M) -> MessageId {
+        let id = MessageId::next();
+        self.outgoing
+            .acked_to_host
+            .push((next_enqueued(), id, message));
+        id
+    }
+
+    /// Send a payload to the host on the channel set for its protocol with
+    /// [`AddProtocolExt::set_protocol_channel`](super::AddProtocolExt::set_protocol_channel).
+    pub fn send_to_host(&mut self, message: M) {
+        let channel = self.outgoing.channel;
+        self.outgoing
+            .to_host
+            .push((next_enqueued(), channel, message));
+    }
+
+    /// Send a payload to the host on a channel of
+    /// [`RtcChannels`](super::RtcChannels).
+    pub fn send_to_host_on(&mut self, channel: usize, message: M) {
+        self.outgoing
+            .to_host
+            .push((next_enqueued(), channel, message));
+    }
+
+    /// Send a payload to the host with reliability, encoding it immediately.
+    /// Returns an error if it cannot be encoded, there is no open connection
+    /// to the host, or the channel is congested.
+    pub fn try_reliable_to_host(&mut self, message: M) -> Result<(), SendErrorKind> {
+        let packet = self.try_encode(RELIABLE_CHANNEL_INDEX, &message)?;
+        self.outgoing
+            .encoded
+            .push((next_enqueued(), RELIABLE_CHANNEL_INDEX, packet));
+        Ok(())
+    }
+
+    /// Send a payload to the host with no expectation of delivery, encoding it
+    /// immediately. Returns an error if it cannot be encoded, there is no
+    /// open connection to the host, or the channel is congested.
+    pub fn try_unreliable_to_host(&mut self, message: M) -> Result<(), SendErrorKind> {
+        let packet = self.try_encode(UNRELIABLE_CHANNEL_INDEX, &message)?;
+        self.outgoing
+            .encoded
+            .push((next_enqueued(), UNRELIABLE_CHANNEL_INDEX, packet));
+        Ok(())
+    }
+
+    fn try_encode(&self, channel: usize, message: &M) -> Result<Packet, SendErrorKind> {
+        let Some(host) = self.state.host_id else {
+            return Err(SendErrorKind::UnknownPeer);
+        };
+        match self.socket.as_ref() {
+            Some(socket) => check_channel(socket, channel)?,
+            None => return Err(SendErrorKind::ChannelClosed),
+        }
+        if self.buffered.is_congested(host, channel) {
+            return Err(SendErrorKind::Congested);
+        }
+        self.outgoing
+            .encode_packet(message)
+            .map_err(SendErrorKind::Serialization)
     }
 }
@@ -4,10 +4,16 @@ use super::{
     NetworkReader, NetworkWriter,
 };
 use crate::{
+    backpressure::{BackpressureConfig, Buffered, ReceiptPayload},
+    channel::RtcChannels,
+    events::CongestionEvent,
     latency::{LatencyTracer, LatencyTracerPayload},
+    manifest::ProtocolManifest,
+    overflow::{DisconnectPayload, Disconnections},
+    registry::ProtocolRegistry,
     socket::{RtcSocket, RtcSocketPlurality},
 };
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use bevy_matchbox::{
     matchbox_socket::{self, WebRtcSocket},
     prelude::*,
@@ -15,19 +21,16 @@ use bevy_matchbox::{
 use instant::Duration;
 
 /// Initialize the socket
-pub(crate) fn init_socket(mut commands: Commands, socket_res: Res<RtcState>) {
+pub(crate) fn init_socket(
+    mut commands: Commands,
+    socket_res: Res<RtcState>,
+    channels: Res<RtcChannels>,
+) {
     if let Some(addr) = socket_res.addr.as_ref() {
         debug!("connecting to: {addr:?}");
 
         // Create matchbox socket
-        let socker_builder = WebRtcSocket::builder(addr)
-            // Match UNRELIABLE_CHANNEL_INDEX
-            .add_channel(ChannelConfig {
-                ordered: true,
-                max_retransmits: Some(0),
-            })
-            // Match RELIABLE_CHANNEL_INDEX
-            .add_channel(ChannelConfig::reliable());
+        let socker_builder = channels.add_to(WebRtcSocket::builder(addr));
 
         // Open socket
         commands.open_socket(socker_builder);
@@ -53,6 +56,7 @@ pub(crate) fn reset_socket(
         id: None,
         latency: None,
         smoothed_latency: None,
+        buffered: HashMap::new(),
     };
 }
 
@@ -140,6 +144,64 @@ pub(crate) fn client_event_writer(
     }
 }
 
+/// Send the host the protocols registered by this client
+pub(crate) fn send_manifest(
+    registry: Res<ProtocolRegistry>,
+    mut writer: NetworkWriter<ProtocolManifest>,
+) {
+    writer.reliable_to_host(ProtocolManifest::new(&registry));
+}
+
+/// Disconnect from a host whose protocols do not match this client's
+pub(crate) fn read_manifest(
+    registry: Res<ProtocolRegistry>,
+    mut reader: NetworkReader<ProtocolManifest>,
+    mut event_wtr: EventWriter<RtcClientEvent>,
+    mut next_connection_state: ResMut<NextState<RtcClientStatus>>,
+) {
+    let local = ProtocolManifest::new(&registry);
+    for manifest in reader.read() {
+        let mismatches = local.mismatches(&manifest);
+        if mismatches.is_empty() {
+            debug!("host protocols match");
+            continue;
+        }
+        let reason = mismatches
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        error!("protocol mismatch with host: {reason}");
+        next_connection_state.set(RtcClientStatus::Disconnected);
+        event_wtr.send(RtcClientEvent::DisconnectedFromHost {
+            reason: Some(format!("Protocol mismatch with host: {reason}")),
+        });
+    }
+}
+
+/// Disconnect from a host which overflowed a protocol with the
+/// `IgnoreAndRequestDisconnect` policy, or which asked this client to
+/// disconnect
+pub(crate) fn read_disconnects(
+    mut disconnections: ResMut<Disconnections>,
+    mut reader: NetworkReader<DisconnectPayload>,
+    mut event_wtr: EventWriter<RtcClientEvent>,
+    mut next_connection_state: ResMut<NextState<RtcClientStatus>>,
+) {
+    let reason = match disconnections.take_pending().next() {
+        Some((_, protocol)) => format!("Host overflowed the `{protocol}` protocol"),
+        None => match reader.read().last() {
+            Some(disconnect) => format!("Disconnected by host: {}", disconnect.reason),
+            None => return,
+        },
+    };
+    error!("{reason}");
+    next_connection_state.set(RtcClientStatus::Disconnected);
+    event_wtr.send(RtcClientEvent::DisconnectedFromHost {
+        reason: Some(reason),
+    });
+}
+
 pub fn send_latency_tracers(state: Res<RtcState>, mut writer: NetworkWriter<LatencyTracerPayload>) {
     let peer_id = state.id.expect("expected peer id");
     writer.unreliable_to_host(LatencyTracerPayload::new(peer_id));
@@ -172,6 +234,57 @@ pub fn read_latency_tracers(
     }
 }
 
+/// Report the bytes received from the host if it asked, so it can estimate
+/// what it has buffered, or ask it to report if a high-water mark is set
+pub(crate) fn send_receipts(
+    state: Res<RtcState>,
+    channels: Res<RtcChannels>,
+    config: Res<BackpressureConfig>,
+    buffered: Res<Buffered>,
+    mut writer: NetworkWriter<ReceiptPayload>,
+) {
+    if let Some(host_id) = state.host_id {
+        if buffered.wants_receipts(host_id, &config) {
+            writer.unreliable_to_host(buffered.receipt(host_id, &channels));
+        }
+    }
+}
+
+/// Estimate the bytes buffered to the host from its reports
+pub(crate) fn read_receipts(
+    mut state: ResMut<RtcState>,
+    channels: Res<RtcChannels>,
+    config: Res<BackpressureConfig>,
+    mut buffered: ResMut<Buffered>,
+    mut reader: NetworkReader<ReceiptPayload>,
+    mut event_wtr: EventWriter<CongestionEvent>,
+) {
+    let Some(host_id) = state.host_id else {
+        return;
+    };
+    for receipt in reader.read() {
+        buffered.acknowledge(host_id, receipt);
+    }
+    let (estimates, events) = buffered.update(&[host_id], &channels, &config);
+    state.buffered = estimates
+        .into_iter()
+        .map(|((_, channel), buffered)| (channel, buffered))
+        .collect();
+    for event in events {
+        warn!(
+            "host {} on channel {} with {} bytes buffered",
+            if event.congested {
+                "congested"
+            } else {
+                "caught up"
+            },
+            event.channel,
+            event.buffered
+        );
+        event_wtr.send(event);
+    }
+}
+
 pub fn calculate_latency(
     time: Res<Time>,
     mut state: ResMut<RtcState>,
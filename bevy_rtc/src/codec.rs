@@ -0,0 +1,217 @@
+//! Wire formats for encoding payloads.
+//!
+//! Every protocol is encoded with one [`Codec`], chosen when the protocol is
+//! registered. The client and server must register a protocol with the same
+//! codec, which is tagged in every packet header.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::{self, Display};
+
+/// A wire format for encoding payloads.
+pub trait Codec: Send + Sync + 'static {
+    /// A tag identifying this codec in packet headers. Must be unique among
+    /// codecs.
+    const ID: u8;
+
+    /// A human readable name for this codec.
+    const NAME: &'static str;
+
+    /// Append the encoding of a value to a buffer.
+    fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError>;
+
+    /// Decode a value from bytes.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// An error encoding or decoding a payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError {
+    /// The name of the codec that failed
+    pub codec: &'static str,
+    /// The reason given by the codec
+    pub message: String,
+}
+
+impl CodecError {
+    fn new<C: Codec>(error: impl Display) -> Self {
+        Self {
+            codec: C::NAME,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} codec error: {}", self.codec, self.message)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Human readable JSON encoding, using `serde_json`. This is the default codec.
+pub struct Json;
+
+impl Codec for Json {
+    const ID: u8 = 0;
+    const NAME: &'static str = "json";
+
+    fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        serde_json::to_writer(buf, value).map_err(CodecError::new::<Self>)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::new::<Self>)
+    }
+}
+
+/// Binary encoding, using `bincode`.
+#[cfg(feature = "bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const ID: u8 = 1;
+    const NAME: &'static str = "bincode";
+
+    fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        bincode::serialize_into(buf, value).map_err(CodecError::new::<Self>)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::new::<Self>)
+    }
+}
+
+/// Compact, self-describing binary encoding, using `rmp-serde`.
+#[cfg(feature = "msgpack")]
+#[cfg_attr(docsrs, doc(cfg(feature = "msgpack")))]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const ID: u8 = 2;
+    const NAME: &'static str = "msgpack";
+
+    fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        rmp_serde::encode::write(buf, value).map_err(CodecError::new::<Self>)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(CodecError::new::<Self>)
+    }
+}
+
+/// Compact binary encoding with variable length integers, using `postcard`.
+#[cfg(feature = "postcard")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const ID: u8 = 3;
+    const NAME: &'static str = "postcard";
+
+    fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        *buf = postcard::to_extend(value, std::mem::take(buf)).map_err(CodecError::new::<Self>)?;
+        Ok(())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        postcard::from_bytes(bytes).map_err(CodecError::new::<Self>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Payload;
+    use serde::Deserialize;
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Sample {
+        id: u32,
+        name: String,
+        position: (f32, f32),
+        tags: Vec<u8>,
+        parent: Option<u64>,
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            id: 42,
+            name: "sample".to_string(),
+            position: (1.5, -2.25),
+            tags: vec![1, 2, 3],
+            parent: Some(u64::MAX),
+        }
+    }
+
+    /// Encodes like [`Json`], but with another tag.
+    struct Tagged;
+
+    impl Codec for Tagged {
+        const ID: u8 = u8::MAX;
+        const NAME: &'static str = "tagged";
+
+        fn encode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+            Json::encode(value, buf)
+        }
+
+        fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+            Json::decode(bytes)
+        }
+    }
+
+    fn assert_round_trips<C: Codec>() {
+        let packet = sample().to_packet::<C>().unwrap();
+        assert_eq!(Sample::from_packet::<C>(&packet), Some(sample()));
+        assert_eq!(Sample::from_packet::<Tagged>(&packet), None);
+
+        let error = C::decode::<Sample>(&[0xFF; 3]).unwrap_err();
+        assert_eq!(error.codec, C::NAME);
+    }
+
+    fn assert_rejected<A: Codec, B: Codec>() {
+        let packet = sample().to_packet::<A>().unwrap();
+        assert_eq!(Sample::from_packet::<B>(&packet), None);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        assert_round_trips::<Json>();
+    }
+
+    #[test]
+    fn packets_are_rejected_by_other_codecs() {
+        // The bodies are identical, so only the tag tells them apart
+        assert_rejected::<Json, Tagged>();
+        #[cfg(feature = "bincode")]
+        assert_rejected::<Json, Bincode>();
+        #[cfg(all(feature = "bincode", feature = "msgpack"))]
+        assert_rejected::<Bincode, MessagePack>();
+        #[cfg(all(feature = "msgpack", feature = "postcard"))]
+        assert_rejected::<MessagePack, Postcard>();
+        #[cfg(feature = "postcard")]
+        assert_rejected::<Postcard, Json>();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trips() {
+        assert_round_trips::<Bincode>();
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        assert_round_trips::<MessagePack>();
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips() {
+        assert_round_trips::<Postcard>();
+    }
+}
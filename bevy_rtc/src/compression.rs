@@ -0,0 +1,191 @@
+use crate::protocol::RtcPacketHeader;
+use matchbox_socket::Packet;
+
+/// Limits applied when decompressing received packets.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// The largest payload a compressed packet may expand to, in bytes.
+    /// Packets which would expand further are discarded.
+    pub max_decompressed_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            max_decompressed_size: 1024 * 1024,
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// How the packets of a protocol are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// The algorithm to compress with
+    pub algorithm: CompressionAlgorithm,
+    /// Payloads smaller than this many bytes are sent uncompressed
+    pub threshold: usize,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// The supported compression algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompressionAlgorithm {
+    /// DEFLATE, with a level from 0 (fastest) to 10 (smallest)
+    Deflate { level: u8 },
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl CompressionAlgorithm {
+    fn id(&self) -> u8 {
+        match self {
+            Self::Deflate { .. } => 0,
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl Compression {
+    /// Compress payloads of at least `threshold` bytes with DEFLATE.
+    pub fn deflate(threshold: usize) -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Deflate { level: 6 },
+            threshold,
+        }
+    }
+
+    /// Compress an encoded packet, if its payload is over the threshold and
+    /// compressing it saves space.
+    pub(crate) fn compress(&self, packet: Packet) -> Packet {
+        let Some((header, data)) = RtcPacketHeader::read(&packet) else {
+            return packet;
+        };
+        if data.len() < self.threshold {
+            return packet;
+        }
+        let compressed = match self.algorithm {
+            CompressionAlgorithm::Deflate { level } => {
+                miniz_oxide::deflate::compress_to_vec(data, level)
+            }
+        };
+        if compressed.len() + 1 >= data.len() {
+            return packet;
+        }
+        let mut buf = Vec::with_capacity(RtcPacketHeader::LEN + 1 + compressed.len());
+        RtcPacketHeader {
+            flags: header.flags | RtcPacketHeader::FLAG_COMPRESSED,
+            ..header
+        }
+        .write(&mut buf);
+        buf.push(self.algorithm.id());
+        buf.extend_from_slice(&compressed);
+        buf.into_boxed_slice()
+    }
+}
+
+/// Decompress a packet flagged as compressed, restoring the packet it was
+/// compressed from. `data` is the packet without its header. Returns `None` if
+/// it is malformed or expands past `max_decompressed_size`.
+pub(crate) fn decompress(
+    header: RtcPacketHeader,
+    data: &[u8],
+    config: &CompressionConfig,
+) -> Option<Packet> {
+    let (algorithm, compressed) = data.split_first()?;
+    let decompressed = match algorithm {
+        0 => miniz_oxide::inflate::decompress_to_vec_with_limit(
+            compressed,
+            config.max_decompressed_size,
+        )
+        .ok()?,
+        _ => return None,
+    };
+    let mut buf = Vec::with_capacity(RtcPacketHeader::LEN + decompressed.len());
+    RtcPacketHeader {
+        flags: header.flags & !RtcPacketHeader::FLAG_COMPRESSED,
+        ..header
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(&decompressed);
+    Some(buf.into_boxed_slice())
+}
+
+#[cfg(all(test, any(feature = "client", feature = "server")))]
+mod tests {
+    use super::*;
+
+    fn packet(data: &[u8]) -> Packet {
+        let mut buf = vec![];
+        RtcPacketHeader {
+            msg_id: 7,
+            version: 1,
+            codec: 0,
+            flags: RtcPacketHeader::FLAG_SEQUENCED,
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(data);
+        buf.into_boxed_slice()
+    }
+
+    fn decompressed(packet: &Packet, config: &CompressionConfig) -> Option<Packet> {
+        let (header, data) = RtcPacketHeader::read(packet).unwrap();
+        assert!(header.has_flag(RtcPacketHeader::FLAG_COMPRESSED));
+        decompress(header, data, config)
+    }
+
+    #[test]
+    fn round_trips() {
+        let original = packet(&b"position ".repeat(100));
+        let compressed = Compression::deflate(64).compress(original.clone());
+        assert!(compressed.len() < original.len());
+        assert_eq!(
+            decompressed(&compressed, &CompressionConfig::default()),
+            Some(original)
+        );
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let original = packet(&b"position ".repeat(100));
+        let compressed = Compression::deflate(10_000).compress(original.clone());
+        assert_eq!(compressed, original);
+    }
+
+    #[test]
+    fn incompressible_payloads_are_not_compressed() {
+        let noise: Vec<u8> = (0..256u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let original = packet(&noise);
+        assert_eq!(Compression::deflate(0).compress(original.clone()), original);
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        let original = packet(&[0; 4096]);
+        let compressed = Compression::deflate(0).compress(original);
+        let config = CompressionConfig {
+            max_decompressed_size: 1024,
+        };
+        assert_eq!(decompressed(&compressed, &config), None);
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected() {
+        let header = RtcPacketHeader {
+            msg_id: 7,
+            version: 0,
+            codec: 0,
+            flags: RtcPacketHeader::FLAG_COMPRESSED,
+        };
+        let config = CompressionConfig::default();
+        assert_eq!(decompress(header, &[], &config), None);
+        assert_eq!(decompress(header, &[9, 1, 2, 3], &config), None);
+        assert_eq!(decompress(header, &[0, 1, 2, 3], &config), None);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    protocol::{__private::generic_payload, DeltaPayload, Payload},
+    sequence::is_newer,
+};
+use bevy::prelude::*;
+#[cfg(feature = "server")]
+use bevy::utils::HashMap;
+#[cfg(feature = "server")]
+use matchbox_socket::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// The number of states kept to diff against or apply diffs to. Baselines
+/// older than this are discarded and the full state is sent instead.
+const HISTORY: usize = 32;
+
+/// A state sent to a peer, either in full or as a diff from a baseline the
+/// peer acknowledged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "")]
+pub(crate) struct DeltaUpdate<M: DeltaPayload> {
+    pub sequence: u16,
+    pub state: DeltaState<M>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "")]
+pub(crate) enum DeltaState<M: DeltaPayload> {
+    Full(M),
+    Diff { baseline: u16, delta: M::Delta },
+}
+
+impl<M: DeltaPayload> Payload for DeltaUpdate<M> {
+    fn id() -> u16 {
+        generic_payload::<Self>(|| format!("bevy_rtc::DeltaUpdate<{}>", M::reflect_name())).1
+    }
+
+    fn reflect_name() -> &'static str {
+        generic_payload::<Self>(|| format!("bevy_rtc::DeltaUpdate<{}>", M::reflect_name())).0
+    }
+
+    fn version() -> u8 {
+        M::version()
+    }
+}
+
+/// Acknowledges that a peer received a state, so it can be used as a
+/// baseline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "")]
+pub(crate) struct DeltaAck<M: DeltaPayload> {
+    pub sequence: u16,
+    #[serde(skip)]
+    _payload: std::marker::PhantomData<fn() -> M>,
+}
+
+#[cfg(feature = "client")]
+impl<M: DeltaPayload> DeltaAck<M> {
+    pub(crate) fn new(sequence: u16) -> Self {
+        Self {
+            sequence,
+            _payload: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<M: DeltaPayload> Payload for DeltaAck<M> {
+    fn id() -> u16 {
+        generic_payload::<Self>(|| format!("bevy_rtc::DeltaAck<{}>", M::reflect_name())).1
+    }
+
+    fn reflect_name() -> &'static str {
+        generic_payload::<Self>(|| format!("bevy_rtc::DeltaAck<{}>", M::reflect_name())).0
+    }
+}
+
+/// The states sent to each peer, and the most recent one each acknowledged.
+#[cfg(feature = "server")]
+#[derive(Resource, Debug)]
+pub(crate) struct DeltaBaselines<M: DeltaPayload> {
+    peers: HashMap<PeerId, PeerBaselines<M>>,
+}
+
+#[cfg(feature = "server")]
+impl<M: DeltaPayload> Default for DeltaBaselines<M> {
+    fn default() -> Self {
+        Self {
+            peers: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug)]
+struct PeerBaselines<M> {
+    next_sequence: u16,
+    sent: VecDeque<(u16, M)>,
+    acked: Option<(u16, M)>,
+}
+
+#[cfg(feature = "server")]
+impl<M> Default for PeerBaselines<M> {
+    fn default() -> Self {
+        Self {
+            next_sequence: 0,
+            sent: VecDeque::new(),
+            acked: None,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<M: DeltaPayload> DeltaBaselines<M> {
+    /// Prepare a state to send to a peer, diffing it against the last
+    /// baseline the peer acknowledged.
+    pub(crate) fn update(&mut self, peer: PeerId, state: &M) -> DeltaUpdate<M> {
+        let baselines = self.peers.entry(peer).or_default();
+        let sequence = baselines.next_sequence;
+        baselines.next_sequence = sequence.wrapping_add(1);
+        let state_update = match &baselines.acked {
+            Some((baseline, acked)) if (sequence.wrapping_sub(*baseline) as usize) < HISTORY => {
+                DeltaState::Diff {
+                    baseline: *baseline,
+                    delta: state.diff(acked),
+                }
+            }
+            _ => DeltaState::Full(state.clone()),
+        };
+        baselines.sent.push_back((sequence, state.clone()));
+        if baselines.sent.len() > HISTORY {
+            baselines.sent.pop_front();
+        }
+        DeltaUpdate {
+            sequence,
+            state: state_update,
+        }
+    }
+
+    /// Record that a peer received a state.
+    pub(crate) fn ack(&mut self, peer: PeerId, sequence: u16) {
+        let Some(baselines) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        if baselines
+            .acked
+            .as_ref()
+            .is_some_and(|(acked, _)| !is_newer(sequence, *acked))
+        {
+            return;
+        }
+        if let Some(index) = baselines.sent.iter().position(|(s, _)| *s == sequence) {
+            // Older states can no longer become the baseline
+            baselines.acked = baselines.sent.drain(..=index).last();
+        }
+    }
+
+    /// Forget peers which are no longer connected.
+    pub(crate) fn retain(&mut self, connected: impl Fn(&PeerId) -> bool) {
+        self.peers.retain(|peer, _| connected(peer));
+    }
+}
+
+/// The states received from the host, which diffs are applied to.
+#[cfg(feature = "client")]
+#[derive(Resource, Debug)]
+pub(crate) struct DeltaHistory<M: DeltaPayload> {
+    received: VecDeque<(u16, M)>,
+}
+
+#[cfg(feature = "client")]
+impl<M: DeltaPayload> Default for DeltaHistory<M> {
+    fn default() -> Self {
+        Self {
+            received: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl<M: DeltaPayload> DeltaHistory<M> {
+    /// Reconstruct the state sent in an update. Returns `None` if the update
+    /// is older than the latest state, or its baseline is unknown.
+    pub(crate) fn apply(&mut self, update: DeltaUpdate<M>) -> Option<M> {
+        if self
+            .received
+            .back()
+            .is_some_and(|(latest, _)| !is_newer(update.sequence, *latest))
+        {
+            return None;
+        }
+        let state = match update.state {
+            DeltaState::Full(state) => state,
+            DeltaState::Diff { baseline, delta } => {
+                let (_, baseline) = self.received.iter().find(|(s, _)| *s == baseline)?;
+                let mut state = baseline.clone();
+                state.apply(delta);
+                state
+            }
+        };
+        self.received.push_back((update.sequence, state.clone()));
+        if self.received.len() > HISTORY {
+            self.received.pop_front();
+        }
+        Some(state)
+    }
+
+    /// Forget all states, e.g. when reconnecting.
+    pub(crate) fn clear(&mut self) {
+        self.received.clear();
+    }
+}
+
+#[cfg(all(test, any(feature = "server", feature = "client")))]
+mod tests {
+    use super::*;
+    #[cfg(feature = "server")]
+    use uuid::Uuid;
+
+    #[cfg(feature = "server")]
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    #[derive(Payload, DeltaPayload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct State {
+        score: u32,
+        name: String,
+    }
+
+    fn state(score: u32) -> State {
+        State {
+            score,
+            name: "player".to_string(),
+        }
+    }
+
+    #[cfg(feature = "client")]
+    fn full(sequence: u16, score: u32) -> DeltaUpdate<State> {
+        DeltaUpdate {
+            sequence,
+            state: DeltaState::Full(state(score)),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    fn baseline(update: &DeltaUpdate<State>) -> Option<u16> {
+        match update.state {
+            DeltaState::Full(_) => None,
+            DeltaState::Diff { baseline, .. } => Some(baseline),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn full_states_are_sent_until_acknowledged() {
+        let mut baselines = DeltaBaselines::<State>::default();
+        assert_eq!(baseline(&baselines.update(PEER, &state(1))), None);
+        assert_eq!(baseline(&baselines.update(PEER, &state(2))), None);
+
+        baselines.ack(PEER, 1);
+        let update = baselines.update(PEER, &state(3));
+        assert_eq!(update.sequence, 2);
+        assert_eq!(baseline(&update), Some(1));
+
+        // Older acknowledgements do not replace the baseline
+        baselines.ack(PEER, 0);
+        assert_eq!(baseline(&baselines.update(PEER, &state(4))), Some(1));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn missing_baselines_send_full_states() {
+        let mut baselines = DeltaBaselines::<State>::default();
+        // Acknowledging a state never sent, or to an unknown peer, is ignored
+        baselines.ack(PEER, 5);
+        assert_eq!(baseline(&baselines.update(PEER, &state(1))), None);
+
+        baselines.ack(PEER, 0);
+        // The baseline falls out of the history
+        for score in 0..HISTORY as u32 {
+            baselines.update(PEER, &state(score));
+        }
+        assert_eq!(baseline(&baselines.update(PEER, &state(0))), None);
+
+        // Peers which left start over
+        baselines.retain(|_| false);
+        let update = baselines.update(PEER, &state(1));
+        assert_eq!((update.sequence, baseline(&update)), (0, None));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn diffs_need_their_baseline() {
+        let mut history = DeltaHistory::<State>::default();
+        let diff = DeltaUpdate {
+            sequence: 1,
+            state: DeltaState::Diff {
+                baseline: 0,
+                delta: state(2).diff(&state(1)),
+            },
+        };
+        assert_eq!(history.apply(diff.clone()), None);
+
+        assert_eq!(history.apply(full(0, 1)), Some(state(1)));
+        assert_eq!(history.apply(diff.clone()), Some(state(2)));
+        // Stale or repeated updates are dropped
+        assert_eq!(history.apply(diff), None);
+        assert_eq!(history.apply(full(0, 1)), None);
+
+        history.clear();
+        assert_eq!(history.apply(full(0, 3)), Some(state(3)));
+    }
+
+    #[cfg(all(feature = "server", feature = "client"))]
+    #[test]
+    fn diffs_reconstruct_the_state() {
+        let mut baselines = DeltaBaselines::<State>::default();
+        let mut history = DeltaHistory::<State>::default();
+        let mut sent = state(0);
+        for score in 0..100 {
+            sent.score = score / 3;
+            if score % 10 == 0 {
+                sent.name = format!("player {score}");
+            }
+            let update = baselines.update(PEER, &sent);
+            let sequence = update.sequence;
+            // Every other update is lost
+            if score % 2 == 1 {
+                continue;
+            }
+            assert_eq!(history.apply(update).as_ref(), Some(&sent));
+            baselines.ack(PEER, sequence);
+        }
+    }
+}
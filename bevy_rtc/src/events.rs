@@ -1,6 +1,135 @@
-use bevy::prelude::Event;
-use bevy_matchbox::matchbox_socket::{Packet, PeerId};
+use crate::{ack::MessageId, codec::CodecError};
+use matchbox_socket::PeerId;
+use std::fmt::{self, Display};
 
-/// The raw event to receive from a socket
-#[derive(Debug, Clone, Event)]
-pub struct SocketRecvEvent(pub (PeerId, Packet));
+#[cfg(any(feature = "client", feature = "server"))]
+/// Packets received from a peer this frame which could not be routed to a
+/// registered protocol.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Event)
+)]
+#[derive(Debug, Clone)]
+pub struct UnknownPayloadEvent {
+    /// The peer that sent the packets
+    pub peer: PeerId,
+    /// The payload ID of the packets, or `None` if the header was unreadable
+    pub msg_id: Option<u16>,
+    /// The number of packets dropped
+    pub count: usize,
+}
+
+/// Whether a payload sent with an `*_acked` method of a network writer
+/// reached a peer. Each is reported once per recipient.
+#[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Event)
+)]
+#[derive(Debug, Clone)]
+pub struct DeliveryEvent {
+    /// The recipient
+    pub peer: PeerId,
+    /// The name of the protocol of the payload
+    pub protocol: &'static str,
+    /// The ID returned when the payload was sent
+    pub id: MessageId,
+    pub status: DeliveryStatus,
+}
+
+/// The outcome of sending an acked payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The peer acknowledged the payload
+    Delivered,
+    /// The payload was not acknowledged in time, or could not be sent
+    Lost,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// A peer crossed the high-water mark of `BackpressureConfig` on a reliable
+/// channel. While congested, sends to it on the channel are refused.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Event)
+)]
+#[derive(Debug, Clone)]
+pub struct CongestionEvent {
+    pub peer: PeerId,
+    /// The index of the channel
+    pub channel: usize,
+    /// The estimated bytes buffered to the peer on the channel
+    pub buffered: usize,
+    /// Whether the peer became congested, or caught up
+    pub congested: bool,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Payloads received from a peer this frame which were dropped by the
+/// `OverflowPolicy` of their protocol, because its buffer was full.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Event)
+)]
+#[derive(Debug, Clone)]
+pub struct ProtocolOverflow {
+    /// The peer that sent the payloads
+    pub peer: PeerId,
+    /// The name of the protocol
+    pub protocol: &'static str,
+    /// The number of payloads dropped
+    pub dropped: usize,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// A payload which could not be sent to a peer.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Event)
+)]
+#[derive(Debug, Clone)]
+pub struct NetworkSendError {
+    /// The intended recipient
+    pub peer: PeerId,
+    /// The name of the protocol of the payload
+    pub protocol: &'static str,
+    /// Why the payload could not be sent
+    pub kind: SendErrorKind,
+}
+
+/// The reasons a payload can fail to send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendErrorKind {
+    /// The payload could not be encoded by its codec
+    Serialization(CodecError),
+    /// The socket channel is closed
+    ChannelClosed,
+    /// The socket has no channel at the index, see `RtcChannels`
+    UnknownChannel,
+    /// The recipient is not a connected peer
+    UnknownPeer,
+    /// The payload needs more fragments than can be sent
+    TooLarge,
+    /// The payload waited too long for the peer's bandwidth budget, see
+    /// `BandwidthConfig`
+    OverBudget,
+    /// Too much is buffered to the peer on the channel, see
+    /// `BackpressureConfig`
+    Congested,
+}
+
+impl Display for SendErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendErrorKind::Serialization(e) => write!(f, "serialization failed: {e}"),
+            SendErrorKind::ChannelClosed => write!(f, "channel closed"),
+            SendErrorKind::UnknownChannel => write!(f, "unknown channel"),
+            SendErrorKind::UnknownPeer => write!(f, "unknown peer"),
+            SendErrorKind::TooLarge => write!(f, "payload too large"),
+            SendErrorKind::OverBudget => write!(f, "bandwidth budget exceeded"),
+            SendErrorKind::Congested => write!(f, "peer congested"),
+        }
+    }
+}
+
+impl std::error::Error for SendErrorKind {}
@@ -0,0 +1,489 @@
+use crate::protocol::RtcPacketHeader;
+use instant::{Duration, Instant};
+use matchbox_socket::{Packet, PeerId};
+use std::collections::HashMap;
+use tracing::{trace, warn};
+
+/// Configures how packets too large for a single data channel message are
+/// split into fragments and reassembled.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Debug, Clone)]
+pub struct FragmentationConfig {
+    /// The largest data channel message sent, in bytes. Larger packets are
+    /// split into fragments, which on the unreliable channel leave room for
+    /// acknowledgements. 16 KiB is the largest message size supported by all
+    /// browsers.
+    pub max_packet_size: usize,
+    /// How long an incomplete message received on the unreliable channel is
+    /// kept before its fragments are discarded.
+    pub unreliable_timeout: Duration,
+    /// The most bytes of incomplete messages buffered for a single peer.
+    /// Messages that would exceed this are discarded.
+    pub max_buffered_bytes_per_peer: usize,
+}
+
+impl Default for FragmentationConfig {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 16 * 1024,
+            unreliable_timeout: Duration::from_secs(1),
+            max_buffered_bytes_per_peer: 1024 * 1024,
+        }
+    }
+}
+
+/// Identifies a fragment within the packet it was split from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FragmentHeader {
+    /// Identifies the packet this fragment belongs to
+    group: u16,
+    /// The position of this fragment
+    index: u16,
+    /// The number of fragments in the group
+    count: u16,
+}
+
+impl FragmentHeader {
+    const LEN: usize = 6;
+
+    fn read(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+        let (header, chunk) = data.split_at(Self::LEN);
+        let header = Self {
+            group: u16::from_le_bytes([header[0], header[1]]),
+            index: u16::from_le_bytes([header[2], header[3]]),
+            count: u16::from_le_bytes([header[4], header[5]]),
+        };
+        Some((header, chunk))
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.group.to_le_bytes());
+        buf.extend_from_slice(&self.index.to_le_bytes());
+        buf.extend_from_slice(&self.count.to_le_bytes());
+    }
+}
+
+/// Splits outgoing packets into fragments.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct Fragmenter {
+    next_group: u16,
+}
+
+impl Fragmenter {
+    /// Split a packet into fragments no larger than `max_packet_size`. Packets
+    /// which already fit are returned as is. Returns `None` if the packet
+    /// needs more fragments than can be numbered.
+    pub(crate) fn split(&mut self, packet: Packet, max_packet_size: usize) -> Option<Vec<Packet>> {
+        if packet.len() <= max_packet_size {
+            return Some(vec![packet]);
+        }
+        let Some((header, _)) = RtcPacketHeader::read(&packet) else {
+            return Some(vec![packet]);
+        };
+        let chunk_size = max_packet_size
+            .saturating_sub(RtcPacketHeader::LEN + FragmentHeader::LEN)
+            .max(1);
+        let count = u16::try_from(packet.len().div_ceil(chunk_size)).ok()?;
+        let group = self.next_group;
+        self.next_group = self.next_group.wrapping_add(1);
+
+        let fragments = packet
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut buf =
+                    Vec::with_capacity(RtcPacketHeader::LEN + FragmentHeader::LEN + chunk.len());
+                RtcPacketHeader {
+                    flags: header.flags | RtcPacketHeader::FLAG_FRAGMENT,
+                    ..header
+                }
+                .write(&mut buf);
+                FragmentHeader {
+                    group,
+                    index: index as u16,
+                    count,
+                }
+                .write(&mut buf);
+                buf.extend_from_slice(chunk);
+                buf.into_boxed_slice()
+            })
+            .collect();
+        Some(fragments)
+    }
+}
+
+/// Reassembles fragments received from peers into whole packets.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct Reassembler {
+    peers: HashMap<PeerId, PeerFragments>,
+}
+
+#[derive(Default, Debug)]
+struct PeerFragments {
+    /// Bytes buffered across all incomplete messages
+    buffered: usize,
+    messages: HashMap<u16, PartialMessage>,
+    /// Messages discarded for exceeding the buffer limit, whose remaining
+    /// fragments are dropped as they arrive
+    discarded: HashMap<u16, DiscardedMessage>,
+}
+
+#[derive(Debug)]
+struct DiscardedMessage {
+    reliable: bool,
+    discarded_at: Instant,
+    /// Fragments not yet received
+    remaining: usize,
+}
+
+#[derive(Debug)]
+struct PartialMessage {
+    reliable: bool,
+    first_received: Instant,
+    received: usize,
+    fragments: Vec<Option<Box<[u8]>>>,
+}
+
+impl Reassembler {
+    /// Buffer a fragment, returning the original packet once all of its
+    /// fragments have been received. `data` is the fragment without its
+    /// packet header.
+    pub(crate) fn insert(
+        &mut self,
+        peer: PeerId,
+        reliable: bool,
+        data: &[u8],
+        config: &FragmentationConfig,
+    ) -> Option<Packet> {
+        let Some((header, chunk)) = FragmentHeader::read(data) else {
+            warn!("Received a malformed fragment from {peer}");
+            return None;
+        };
+        if header.index >= header.count {
+            warn!("Received a malformed fragment from {peer}");
+            return None;
+        }
+        let peer_fragments = self.peers.entry(peer).or_default();
+        if let Some(discarded) = peer_fragments.discarded.get_mut(&header.group) {
+            discarded.remaining = discarded.remaining.saturating_sub(1);
+            if discarded.remaining == 0 {
+                peer_fragments.discarded.remove(&header.group);
+            }
+            return None;
+        }
+        let cost = match peer_fragments.messages.get(&header.group) {
+            Some(_) => chunk.len(),
+            None => PartialMessage::overhead(header.count) + chunk.len(),
+        };
+        if peer_fragments.buffered + cost > config.max_buffered_bytes_per_peer {
+            warn!(
+                "Discarding fragmented message from {peer}, which exceeds the limit of {} buffered bytes",
+                config.max_buffered_bytes_per_peer
+            );
+            let received = match peer_fragments.messages.remove(&header.group) {
+                Some(message) => {
+                    peer_fragments.buffered -= message.buffered();
+                    message.received
+                }
+                None => 0,
+            };
+            // The fragment just received is also accounted for
+            let remaining = (header.count as usize).saturating_sub(received + 1);
+            if remaining > 0 {
+                peer_fragments.discarded.insert(
+                    header.group,
+                    DiscardedMessage {
+                        reliable,
+                        discarded_at: Instant::now(),
+                        remaining,
+                    },
+                );
+            }
+            return None;
+        }
+
+        let message = peer_fragments
+            .messages
+            .entry(header.group)
+            .or_insert_with(|| PartialMessage {
+                reliable,
+                first_received: Instant::now(),
+                received: 0,
+                fragments: vec![None; header.count as usize],
+            });
+        if message.fragments.len() != header.count as usize {
+            warn!("Received a fragment from {peer} with a mismatched count");
+            return None;
+        }
+        let slot = &mut message.fragments[header.index as usize];
+        if slot.is_some() {
+            return None;
+        }
+        *slot = Some(chunk.into());
+        message.received += 1;
+        peer_fragments.buffered += cost;
+
+        if message.received < message.fragments.len() {
+            return None;
+        }
+        let message = peer_fragments.messages.remove(&header.group)?;
+        peer_fragments.buffered -= message.buffered();
+        Some(
+            message
+                .fragments
+                .into_iter()
+                .flatten()
+                .flat_map(Vec::from)
+                .collect(),
+        )
+    }
+
+    /// Discard incomplete unreliable messages which have timed out, and all
+    /// messages of peers which are no longer connected. Unreliable messages
+    /// discarded for exceeding the buffer limit are forgotten after the same
+    /// timeout, as their remaining fragments may never arrive.
+    pub(crate) fn prune(&mut self, connected: &[PeerId], config: &FragmentationConfig) {
+        self.peers.retain(|peer, _| connected.contains(peer));
+        for (peer, peer_fragments) in self.peers.iter_mut() {
+            let mut discarded = 0;
+            peer_fragments.messages.retain(|_, message| {
+                let expired = !message.reliable
+                    && message.first_received.elapsed() > config.unreliable_timeout;
+                if expired {
+                    discarded += message.buffered();
+                }
+                !expired
+            });
+            peer_fragments.discarded.retain(|_, message| {
+                message.reliable || message.discarded_at.elapsed() <= config.unreliable_timeout
+            });
+            if discarded > 0 {
+                trace!(
+                    "Discarded {discarded} bytes of incomplete unreliable fragments from {peer}"
+                );
+                peer_fragments.buffered -= discarded;
+            }
+        }
+    }
+}
+
+impl PartialMessage {
+    /// The memory needed to track a message's fragments, before any arrive.
+    fn overhead(count: u16) -> usize {
+        count as usize * std::mem::size_of::<Option<Box<[u8]>>>()
+    }
+
+    /// The memory used by this message.
+    fn buffered(&self) -> usize {
+        let received: usize = self
+            .fragments
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.len())
+            .sum();
+        Self::overhead(self.fragments.len() as u16) + received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    fn packet(len: usize) -> Packet {
+        let mut buf = vec![];
+        RtcPacketHeader {
+            msg_id: 7,
+            version: 0,
+            codec: 0,
+            flags: 0,
+        }
+        .write(&mut buf);
+        buf.extend((0..len).map(|i| i as u8));
+        buf.into_boxed_slice()
+    }
+
+    /// The fragment without its packet header, as the reassembler receives it.
+    fn data(fragment: &Packet) -> &[u8] {
+        let (header, data) = RtcPacketHeader::read(fragment).unwrap();
+        assert!(header.has_flag(RtcPacketHeader::FLAG_FRAGMENT));
+        data
+    }
+
+    #[test]
+    fn small_packets_are_not_split() {
+        let original = packet(10);
+        let fragments = Fragmenter::default().split(original.clone(), 64).unwrap();
+        assert_eq!(fragments, vec![original]);
+    }
+
+    #[test]
+    fn fragments_fit_and_reassemble() {
+        let config = FragmentationConfig::default();
+        let original = packet(100);
+        let fragments = Fragmenter::default().split(original.clone(), 32).unwrap();
+        assert!(fragments.len() > 1);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 32));
+
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(
+                reassembler.insert(PEER, true, data(fragment), &config),
+                None
+            );
+        }
+        assert_eq!(
+            reassembler.insert(PEER, true, data(last), &config),
+            Some(original)
+        );
+        assert_eq!(reassembler.peers[&PEER].buffered, 0);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let config = FragmentationConfig::default();
+        let original = packet(100);
+        let mut fragments = Fragmenter::default().split(original.clone(), 32).unwrap();
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let reassembled: Vec<Packet> = fragments
+            .iter()
+            .filter_map(|fragment| reassembler.insert(PEER, true, data(fragment), &config))
+            .collect();
+        assert_eq!(reassembled, vec![original]);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let config = FragmentationConfig::default();
+        let original = packet(100);
+        let fragments = Fragmenter::default().split(original.clone(), 32).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        let first = data(&fragments[0]);
+        assert_eq!(reassembler.insert(PEER, false, first, &config), None);
+        assert_eq!(reassembler.insert(PEER, false, first, &config), None);
+        let reassembled: Vec<Packet> = fragments[1..]
+            .iter()
+            .filter_map(|fragment| reassembler.insert(PEER, false, data(fragment), &config))
+            .collect();
+        assert_eq!(reassembled, vec![original]);
+        assert_eq!(reassembler.peers[&PEER].buffered, 0);
+    }
+
+    #[test]
+    fn interleaved_groups_reassemble_separately() {
+        let config = FragmentationConfig::default();
+        let mut fragmenter = Fragmenter::default();
+        let (first, second) = (packet(80), packet(90));
+        let first_fragments = fragmenter.split(first.clone(), 32).unwrap();
+        let second_fragments = fragmenter.split(second.clone(), 32).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        let reassembled: Vec<Packet> = second_fragments
+            .iter()
+            .zip(first_fragments.iter())
+            .flat_map(|(a, b)| [a, b])
+            .chain(second_fragments.iter().skip(first_fragments.len()))
+            .filter_map(|fragment| reassembler.insert(PEER, true, data(fragment), &config))
+            .collect();
+        assert_eq!(reassembled.len(), 2);
+        assert!(reassembled.contains(&first) && reassembled.contains(&second));
+    }
+
+    #[test]
+    fn messages_over_the_buffer_limit_are_discarded() {
+        let config = FragmentationConfig {
+            max_buffered_bytes_per_peer: 64,
+            ..Default::default()
+        };
+        let fragments = Fragmenter::default().split(packet(200), 32).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        let reassembled = fragments
+            .iter()
+            .filter_map(|fragment| reassembler.insert(PEER, true, data(fragment), &config))
+            .count();
+        assert_eq!(reassembled, 0);
+        assert!(reassembler.peers[&PEER].buffered <= 64);
+    }
+
+    #[test]
+    fn later_messages_reassemble_after_a_discarded_one() {
+        let config = FragmentationConfig {
+            max_buffered_bytes_per_peer: 128,
+            ..Default::default()
+        };
+        let mut fragmenter = Fragmenter::default();
+        let oversized = fragmenter.split(packet(100), 32).unwrap();
+        let original = packet(40);
+        let fitting = fragmenter.split(original.clone(), 32).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        for fragment in &oversized {
+            assert_eq!(
+                reassembler.insert(PEER, true, data(fragment), &config),
+                None
+            );
+        }
+        let peer_fragments = &reassembler.peers[&PEER];
+        assert_eq!(peer_fragments.buffered, 0);
+        assert!(peer_fragments.messages.is_empty());
+        assert!(peer_fragments.discarded.is_empty());
+
+        let reassembled: Vec<Packet> = fitting
+            .iter()
+            .filter_map(|fragment| reassembler.insert(PEER, true, data(fragment), &config))
+            .collect();
+        assert_eq!(reassembled, vec![original]);
+    }
+
+    #[test]
+    fn malformed_fragments_are_rejected() {
+        let config = FragmentationConfig::default();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert(PEER, true, &[0, 0, 1], &config), None);
+        // Index 2 of a group of 2
+        assert_eq!(
+            reassembler.insert(PEER, true, &[0, 0, 2, 0, 2, 0, 9], &config),
+            None
+        );
+    }
+
+    #[test]
+    fn unreliable_messages_time_out() {
+        let config = FragmentationConfig {
+            unreliable_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let fragments = Fragmenter::default().split(packet(100), 32).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        reassembler.insert(PEER, false, data(&fragments[0]), &config);
+        reassembler.insert(PEER, true, data(&fragments[0]), &config);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        reassembler.prune(&[PEER], &config);
+        assert!(reassembler.peers[&PEER].messages.is_empty());
+
+        reassembler.prune(&[], &config);
+        assert!(reassembler.peers.is_empty());
+    }
+}
@@ -0,0 +1,739 @@
+use super::ClientEvent;
+use crate::{
+    ack::Acks,
+    backpressure::{Buffered, ReceiptPayload},
+    channel::RtcChannels,
+    codec::{Codec, CodecError, Json},
+    compression::CompressionConfig,
+    events::SendErrorKind,
+    fragment::{FragmentationConfig, Fragmenter, Reassembler},
+    latency::{LatencyTracer, LatencyTracerPayload},
+    manifest::ProtocolManifest,
+    overflow::{DisconnectPayload, OverflowPolicy},
+    protocol::Payload,
+    registry::ProtocolRegistry,
+    sequence::Sequences,
+    socket::{
+        check_channel, unpack_message, MessageMeta, ReceivedPacket, RELIABLE_CHANNEL_INDEX,
+        UNRELIABLE_CHANNEL_INDEX,
+    },
+};
+use instant::{Duration, Instant};
+use matchbox_socket::{
+    MessageLoopFuture, MultipleChannels, Packet, PeerId, PeerState, WebRtcSocket,
+};
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::{HashMap, VecDeque},
+};
+use tracing::{debug, error, warn};
+
+/// How often latency tracers are sent to the host.
+const LATENCY_TRACER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the bytes received are reported to the host.
+const RECEIPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the async methods wait between polls of the socket.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+type Received = Box<dyn Any + Send + Sync>;
+
+/// A protocol registered on a headless client, with its received payloads.
+struct HeadlessProtocol {
+    type_id: TypeId,
+    name: &'static str,
+    encode: fn(&dyn Any) -> Result<Packet, CodecError>,
+    decode: fn(&Packet) -> Option<Received>,
+    received: VecDeque<Received>,
+    /// The most payloads buffered before the overflow policy applies
+    bound: usize,
+    overflow: OverflowPolicy,
+    /// The sequence numbers sent and delivered, if the protocol is sequenced
+    sequences: Option<(Sequences, Sequences)>,
+}
+
+impl HeadlessProtocol {
+    fn new<M: Payload, C: Codec>(bound: usize) -> Self {
+        Self {
+            type_id: TypeId::of::<M>(),
+            name: M::reflect_name(),
+            encode: |payload| {
+                payload
+                    .downcast_ref::<M>()
+                    .expect("payload type matches its protocol")
+                    .to_packet::<C>()
+            },
+            decode: |packet| {
+                M::from_packet::<C>(packet).map(|payload| Box::new(payload) as Received)
+            },
+            received: VecDeque::new(),
+            bound,
+            overflow: OverflowPolicy::default(),
+            sequences: None,
+        }
+    }
+}
+
+/// Builds an [`RtcClient`] with the protocols it reads and sends.
+pub struct RtcClientBuilder {
+    addr: String,
+    registry: ProtocolRegistry,
+    protocols: HashMap<u16, HeadlessProtocol>,
+    fragmentation: FragmentationConfig,
+    compression: CompressionConfig,
+    channels: RtcChannels,
+}
+
+impl RtcClientBuilder {
+    /// Register a protocol which is both read and sent, buffering every
+    /// payload received until it is read.
+    pub fn add_protocol<M: Payload>(self) -> Self {
+        self.add_protocol_with_codec::<M, Json>()
+    }
+
+    /// Register a protocol which is both read and sent, encoded with a
+    /// specific codec.
+    pub fn add_protocol_with_codec<M: Payload, C: Codec>(self) -> Self {
+        self.add_bounded_protocol_with_codec::<M, C>(usize::MAX)
+    }
+
+    /// Register a protocol which is both read and sent, buffering up to
+    /// `bound` payloads received. Payloads over the bound are handled by the
+    /// protocol's [`OverflowPolicy`], see
+    /// [`RtcClientBuilder::set_protocol_overflow`].
+    pub fn add_bounded_protocol<M: Payload>(self, bound: usize) -> Self {
+        self.add_bounded_protocol_with_codec::<M, Json>(bound)
+    }
+
+    /// Like [`RtcClientBuilder::add_bounded_protocol`], encoding and decoding
+    /// with a specific codec.
+    pub fn add_bounded_protocol_with_codec<M: Payload, C: Codec>(mut self, bound: usize) -> Self {
+        if self.protocols.contains_key(&M::id()) {
+            panic!("client already contains protocol: {}", type_name::<M>());
+        }
+        self.registry.register::<M, C>(true, true);
+        self.protocols
+            .insert(M::id(), HeadlessProtocol::new::<M, C>(bound));
+        self
+    }
+
+    /// Set what a registered protocol does with payloads received while its
+    /// bounded buffer is full. Defaults to [`OverflowPolicy::DropOldest`].
+    pub fn set_protocol_overflow<M: Payload>(mut self, overflow: OverflowPolicy) -> Self {
+        match self.protocols.get_mut(&M::id()) {
+            Some(protocol) if protocol.type_id == TypeId::of::<M>() => {
+                protocol.overflow = overflow;
+            }
+            _ => panic!("client does not contain protocol: {}", type_name::<M>()),
+        }
+        self
+    }
+
+    /// Number the payloads of a registered protocol, and drop those received
+    /// which are older than the newest delivered.
+    pub fn set_protocol_sequenced<M: Payload>(mut self) -> Self {
+        match self.protocols.get_mut(&M::id()) {
+            Some(protocol) if protocol.type_id == TypeId::of::<M>() => {
+                protocol.sequences = Some(Default::default());
+            }
+            _ => panic!("client does not contain protocol: {}", type_name::<M>()),
+        }
+        self
+    }
+
+    /// Configure how large packets are fragmented and reassembled.
+    pub fn fragmentation(mut self, config: FragmentationConfig) -> Self {
+        self.fragmentation = config;
+        self
+    }
+
+    /// Configure the limits applied when decompressing received packets.
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = config;
+        self
+    }
+
+    /// Configure the data channels opened on the socket, which must match the
+    /// host's.
+    pub fn channels(mut self, channels: RtcChannels) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// Open the socket. The returned future drives the connection, and must be
+    /// spawned on an async runtime, e.g. with `tokio::spawn`.
+    pub fn build(self) -> (RtcClient, MessageLoopFuture) {
+        let (socket, message_loop) = self
+            .channels
+            .add_to(WebRtcSocket::builder(&self.addr))
+            .build();
+        let now = Instant::now();
+        let client = RtcClient {
+            socket,
+            registry: self.registry,
+            protocols: self.protocols,
+            fragmentation: self.fragmentation,
+            compression: self.compression,
+            channels: self.channels,
+            fragmenter: Fragmenter::default(),
+            reassembler: Reassembler::default(),
+            acks: Acks::default(),
+            id: None,
+            host_id: None,
+            connected: false,
+            closed: false,
+            tracer: None,
+            latency: None,
+            smoothed_latency: None,
+            last_update: now,
+            last_tracer_sent: now,
+            buffered: Buffered::default(),
+            receipts_requested: false,
+            last_receipt_sent: now,
+            events: VecDeque::new(),
+        };
+        (client, message_loop)
+    }
+}
+
+/// A client which connects to a bevy_rtc server without a Bevy app, for bots
+/// and services. It uses the same channels and packet framing as
+/// `RtcClientPlugin`.
+///
+/// Call [`RtcClient::update`] routinely to process the socket, or use the
+/// async methods, which do so while they wait.
+///
+/// Payloads are sent as soon as a `send_*` method is called, without the
+/// batching, compression, bandwidth budget or send rates of the plugin.
+/// Batched and compressed packets from the host are still read.
+pub struct RtcClient {
+    socket: WebRtcSocket<MultipleChannels>,
+    registry: ProtocolRegistry,
+    protocols: HashMap<u16, HeadlessProtocol>,
+    fragmentation: FragmentationConfig,
+    compression: CompressionConfig,
+    channels: RtcChannels,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    /// Acknowledges datagrams from the host which track their delivery
+    acks: Acks,
+    id: Option<PeerId>,
+    host_id: Option<PeerId>,
+    connected: bool,
+    closed: bool,
+    tracer: Option<LatencyTracer>,
+    latency: Option<Duration>,
+    smoothed_latency: Option<Duration>,
+    last_update: Instant,
+    last_tracer_sent: Instant,
+    /// Counts the bytes received, to report them to the host
+    buffered: Buffered,
+    /// Whether the host sent reports, asking for them in return
+    receipts_requested: bool,
+    last_receipt_sent: Instant,
+    events: VecDeque<ClientEvent>,
+}
+
+impl RtcClient {
+    /// Start building a client which connects to a server. The format of the
+    /// addr should be ws://host:port or wss://host:port
+    pub fn builder(addr: impl Into<String>) -> RtcClientBuilder {
+        RtcClientBuilder {
+            addr: addr.into(),
+            registry: ProtocolRegistry::default(),
+            protocols: HashMap::new(),
+            fragmentation: FragmentationConfig::default(),
+            compression: CompressionConfig::default(),
+            channels: RtcChannels::default(),
+        }
+        .add_bounded_protocol::<LatencyTracerPayload>(2)
+        .add_bounded_protocol::<ProtocolManifest>(1)
+        .add_bounded_protocol::<ReceiptPayload>(4)
+        .add_bounded_protocol::<DisconnectPayload>(1)
+    }
+
+    /// The ID given by the signaling server
+    pub fn id(&self) -> Option<PeerId> {
+        self.id
+    }
+
+    /// The ID of the host
+    pub fn host_id(&self) -> Option<PeerId> {
+        self.host_id
+    }
+
+    /// Whether the client is connected to the host
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// The latency to the server
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// The smooth latency to the server
+    pub fn smoothed_latency(&self) -> Option<Duration> {
+        self.smoothed_latency
+    }
+
+    /// Process connection changes and received packets, and trace latency.
+    pub fn update(&mut self) {
+        if self.closed {
+            return;
+        }
+        let now = Instant::now();
+        let delta = now - self.last_update;
+        self.last_update = now;
+
+        self.update_connection();
+        if self.closed {
+            return;
+        }
+        self.receive();
+        if self.connected {
+            self.update_latency(now, delta);
+            self.update_receipts(now);
+        }
+    }
+
+    /// Take the oldest connection event.
+    pub fn poll_event(&mut self) -> Option<ClientEvent> {
+        self.events.pop_front()
+    }
+
+    /// Take all payloads received for a protocol.
+    pub fn read<M: Payload>(&mut self) -> impl Iterator<Item = M> + '_ {
+        self.protocol_mut::<M>("read")
+            .received
+            .drain(..)
+            .map(|payload| {
+                *payload
+                    .downcast::<M>()
+                    .expect("payload type matches its ID")
+            })
+    }
+
+    /// Send a payload to the host reliably.
+    pub fn send_reliable<M: Payload>(&mut self, payload: M) -> Result<(), SendErrorKind> {
+        self.send(RELIABLE_CHANNEL_INDEX, &payload)
+    }
+
+    /// Send a payload to the host unreliably.
+    pub fn send_unreliable<M: Payload>(&mut self, payload: M) -> Result<(), SendErrorKind> {
+        self.send(UNRELIABLE_CHANNEL_INDEX, &payload)
+    }
+
+    /// Send a payload to the host on a channel of [`RtcChannels`].
+    pub fn send_on<M: Payload>(&mut self, channel: usize, payload: M) -> Result<(), SendErrorKind> {
+        self.send(channel, &payload)
+    }
+
+    /// Disconnect from the host.
+    pub fn disconnect(&mut self) {
+        self.close(Some("Client requested to disconnect".to_string()));
+    }
+
+    /// Wait for the next connection event. Returns `None` once the client is
+    /// disconnected and all events were taken.
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        loop {
+            self.update();
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+            if self.closed {
+                return None;
+            }
+            futures_timer::Delay::new(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait for the next payload of a protocol. Returns `None` once the client
+    /// is disconnected and all payloads were taken.
+    pub async fn recv<M: Payload>(&mut self) -> Option<M> {
+        loop {
+            self.update();
+            if let Some(payload) = self.protocol_mut::<M>("read").received.pop_front() {
+                return Some(
+                    *payload
+                        .downcast::<M>()
+                        .expect("payload type matches its ID"),
+                );
+            }
+            if self.closed {
+                return None;
+            }
+            futures_timer::Delay::new(POLL_INTERVAL).await;
+        }
+    }
+
+    fn protocol_mut<M: Payload>(&mut self, action: &str) -> &mut HeadlessProtocol {
+        match self.protocols.get_mut(&M::id()) {
+            Some(protocol) if protocol.type_id == TypeId::of::<M>() => protocol,
+            _ => panic!("client does not {action} protocol: {}", type_name::<M>()),
+        }
+    }
+
+    fn send<M: Payload>(&mut self, channel: usize, payload: &M) -> Result<(), SendErrorKind> {
+        if self.closed {
+            return Err(SendErrorKind::ChannelClosed);
+        }
+        check_channel(&self.socket, channel)?;
+        let host = self
+            .host_id
+            .filter(|_| self.connected)
+            .ok_or(SendErrorKind::UnknownPeer)?;
+        let protocol = self.protocol_mut::<M>("send");
+        let mut packet = (protocol.encode)(payload).map_err(SendErrorKind::Serialization)?;
+        if let Some((sent, _)) = protocol.sequences.as_mut() {
+            packet = sent.stamp(host, packet);
+        }
+        let fragments = self
+            .fragmenter
+            .split(packet, self.fragmentation.max_packet_size)
+            .ok_or(SendErrorKind::TooLarge)?;
+        for fragment in fragments {
+            self.socket
+                .channel_mut(channel)
+                .try_send(fragment, host)
+                .map_err(|_| SendErrorKind::ChannelClosed)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, reason: Option<String>) {
+        if self.closed {
+            return;
+        }
+        self.socket.close();
+        self.closed = true;
+        self.connected = false;
+        self.tracer = None;
+        self.latency = None;
+        self.smoothed_latency = None;
+        self.events
+            .push_back(ClientEvent::DisconnectedFromHost { reason });
+    }
+
+    fn update_connection(&mut self) {
+        if let Some(id) = self.socket.id() {
+            if self.id.is_none() {
+                self.id.replace(id);
+                self.events.push_back(ClientEvent::IdAssigned(id));
+            }
+        }
+
+        match self.socket.try_update_peers() {
+            Ok(updates) => {
+                for (peer, state) in updates {
+                    match state {
+                        PeerState::Connected => {
+                            self.host_id.replace(peer);
+                            self.tracer = Some(LatencyTracer::new(peer));
+                            self.connected = true;
+                            self.events.push_back(ClientEvent::ConnectedToHost(peer));
+                            let manifest = ProtocolManifest::new(&self.registry);
+                            if let Err(e) = self.send(RELIABLE_CHANNEL_INDEX, &manifest) {
+                                error!("failed to send protocol manifest: {e}");
+                            }
+                        }
+                        PeerState::Disconnected => {
+                            self.close(Some("Server reset".to_string()));
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                error!("read channel error: {e:?}");
+            }
+        }
+
+        if self.socket.any_closed() {
+            self.close(Some("Connection closed".to_string()));
+        }
+    }
+
+    fn receive(&mut self) {
+        let messages = self.channels.receive(&mut self.socket);
+
+        let protocols = &mut self.protocols;
+        // Payloads are buffered together once all are decoded, so the overflow
+        // policy sees everything received in this update at once
+        let mut decoded: Vec<(u16, Vec<Received>)> = vec![];
+        for (channel, reliable, (peer, message)) in messages {
+            self.buffered.record_received(peer, channel, message.len());
+            // The client does not track deliveries of its own
+            let Some(message) = self.acks.open(peer, message, &mut vec![]) else {
+                continue;
+            };
+            let meta = MessageMeta {
+                peer,
+                channel,
+                reliable,
+                received_at: Instant::now(),
+                size: message.len(),
+                // There are no frames outside of an app
+                frame: 0,
+            };
+            let packets = unpack_message(
+                meta,
+                message,
+                |msg_id| protocols.contains_key(&msg_id),
+                &mut self.reassembler,
+                &self.fragmentation,
+                &self.compression,
+            );
+            for packet in packets {
+                match packet {
+                    Ok(ReceivedPacket {
+                        msg_id,
+                        packet,
+                        sequence,
+                        ..
+                    }) => {
+                        let protocol = protocols
+                            .get_mut(&msg_id)
+                            .expect("packets are only unpacked for registered protocols");
+                        if let (Some((_, delivered)), Some(sequence)) =
+                            (protocol.sequences.as_mut(), sequence)
+                        {
+                            if !delivered.deliver(peer, sequence) {
+                                continue;
+                            }
+                        }
+                        match (protocol.decode)(&packet) {
+                            Some(payload) => {
+                                match decoded.iter_mut().find(|(id, _)| *id == msg_id) {
+                                    Some((_, payloads)) => payloads.push(payload),
+                                    None => decoded.push((msg_id, vec![payload])),
+                                }
+                            }
+                            None => {
+                                warn!("Failed to decode a packet from {peer} (payload ID {msg_id})")
+                            }
+                        }
+                    }
+                    Err(msg_id) => {
+                        debug!("Dropped an unroutable packet from {peer} (payload ID {msg_id:?})");
+                    }
+                }
+            }
+        }
+        for (msg_id, payloads) in decoded {
+            let protocol = self
+                .protocols
+                .get_mut(&msg_id)
+                .expect("payloads are only decoded for registered protocols");
+            let dropped =
+                protocol
+                    .overflow
+                    .buffer(&mut protocol.received, payloads, protocol.bound);
+            if dropped == 0 {
+                continue;
+            }
+            let (protocol, overflow) = (protocol.name, protocol.overflow);
+            debug!(
+                "The `{protocol}` protocol dropped {dropped} packets with its bounded buffer full"
+            );
+            self.events
+                .push_back(ClientEvent::ProtocolOverflow { protocol, dropped });
+            if overflow == OverflowPolicy::IgnoreAndRequestDisconnect {
+                let reason = format!("Host overflowed the `{protocol}` protocol");
+                error!("{reason}");
+                self.close(Some(reason));
+            }
+        }
+        let connected: Vec<PeerId> = self.socket.connected_peers().collect();
+        self.reassembler.prune(&connected, &self.fragmentation);
+        let owed: Vec<PeerId> = self.acks.owed().collect();
+        for peer in owed {
+            let (_, packet) = self.acks.wrap(peer, vec![], &[]);
+            if self
+                .socket
+                .channel_mut(UNRELIABLE_CHANNEL_INDEX)
+                .try_send(packet, peer)
+                .is_err()
+            {
+                debug!("failed to send acknowledgements to {peer}: channel closed");
+            }
+        }
+
+        let manifests: Vec<ProtocolManifest> = self.read::<ProtocolManifest>().collect();
+        let local = ProtocolManifest::new(&self.registry);
+        for manifest in manifests {
+            let mismatches = local.mismatches(&manifest);
+            if mismatches.is_empty() {
+                debug!("host protocols match");
+                continue;
+            }
+            let reason = mismatches
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            error!("protocol mismatch with host: {reason}");
+            self.close(Some(format!("Protocol mismatch with host: {reason}")));
+        }
+
+        let disconnects: Vec<DisconnectPayload> = self.read::<DisconnectPayload>().collect();
+        if let Some(disconnect) = disconnects.last() {
+            let reason = format!("Disconnected by host: {}", disconnect.reason);
+            error!("{reason}");
+            self.close(Some(reason));
+        }
+    }
+
+    /// Report the bytes received to the host once it asks, by sending its own
+    /// reports, so it can estimate what it has buffered. The client does not
+    /// estimate its own.
+    fn update_receipts(&mut self, now: Instant) {
+        let Some(host_id) = self.host_id else {
+            return;
+        };
+        if self.read::<ReceiptPayload>().count() > 0 {
+            self.receipts_requested = true;
+        }
+        if self.receipts_requested && now - self.last_receipt_sent >= RECEIPT_INTERVAL {
+            self.last_receipt_sent = now;
+            let receipt = self.buffered.receipt(host_id, &self.channels);
+            let _ = self.send(UNRELIABLE_CHANNEL_INDEX, &receipt);
+        }
+    }
+
+    fn update_latency(&mut self, now: Instant, delta: Duration) {
+        let (Some(id), Some(host_id)) = (self.id, self.host_id) else {
+            return;
+        };
+        let tracers: Vec<LatencyTracerPayload> = self.read::<LatencyTracerPayload>().collect();
+        for payload in tracers {
+            if payload.from == id {
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.process(payload);
+                }
+            } else if payload.from == host_id {
+                // Server time payloads get sent right back to the server
+                let _ = self.send(UNRELIABLE_CHANNEL_INDEX, &payload);
+            } else {
+                warn!(
+                    "Invalid latency tracer from address: {}, ignoring",
+                    payload.from
+                );
+            }
+        }
+        if now - self.last_tracer_sent >= LATENCY_TRACER_INTERVAL {
+            self.last_tracer_sent = now;
+            let _ = self.send(UNRELIABLE_CHANNEL_INDEX, &LatencyTracerPayload::new(id));
+        }
+
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        tracer.update_latency();
+        match tracer.last_latency.map(Duration::from_secs_f32) {
+            Some(last_latency) => {
+                self.latency.replace(last_latency);
+                let current_smoothed = self.smoothed_latency.get_or_insert(last_latency);
+                const AVG_SECS: f32 = 1.0; // 1 second average
+                let alpha = 1.0 - f32::exp(-delta.as_secs_f32() / AVG_SECS);
+                let current_f32 = current_smoothed.as_secs_f32() * (1.0 - alpha);
+                let delta = last_latency.as_secs_f32() * alpha;
+                *current_smoothed = Duration::from_secs_f32(current_f32 + delta);
+            }
+            None => {
+                self.latency = None;
+                self.smoothed_latency = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Ping(u8);
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Chat(String);
+
+    fn client() -> (RtcClient, MessageLoopFuture) {
+        RtcClient::builder("ws://127.0.0.1:3536")
+            .add_protocol::<Ping>()
+            .add_protocol::<Chat>()
+            .build()
+    }
+
+    /// Buffer a payload as though it was received from the host.
+    fn receive<M: Payload>(client: &mut RtcClient, payload: M) {
+        client
+            .protocol_mut::<M>("read")
+            .received
+            .push_back(Box::new(payload));
+    }
+
+    #[test]
+    #[should_panic(expected = "client already contains protocol")]
+    fn duplicate_protocols_panic() {
+        let _ = RtcClient::builder("ws://127.0.0.1:3536")
+            .add_protocol::<Ping>()
+            .add_bounded_protocol::<Ping>(1);
+    }
+
+    #[test]
+    fn sends_are_refused_before_connecting() {
+        let (mut client, _message_loop) = client();
+        assert_eq!(
+            client.send_reliable(Ping(1)),
+            Err(SendErrorKind::UnknownPeer)
+        );
+        assert_eq!(
+            client.send_on(7, Ping(1)),
+            Err(SendErrorKind::UnknownChannel)
+        );
+        client.disconnect();
+        assert_eq!(
+            client.send_unreliable(Ping(1)),
+            Err(SendErrorKind::ChannelClosed)
+        );
+    }
+
+    #[test]
+    fn payloads_are_read_by_type() {
+        let (mut client, _message_loop) = client();
+        receive(&mut client, Ping(1));
+        receive(&mut client, Chat("hi".to_string()));
+        receive(&mut client, Ping(2));
+
+        assert_eq!(
+            client.read::<Ping>().collect::<Vec<_>>(),
+            [Ping(1), Ping(2)]
+        );
+        assert_eq!(client.read::<Ping>().count(), 0);
+
+        let mut recv = pin!(client.recv::<Chat>());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(matches!(
+            recv.as_mut().poll(&mut cx),
+            Poll::Ready(Some(Chat(message))) if message == "hi"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "client does not read protocol")]
+    fn reading_an_unregistered_protocol_panics() {
+        #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
+        struct Unregistered;
+
+        let (mut client, _message_loop) = client();
+        let _ = client.read::<Unregistered>();
+    }
+}
@@ -0,0 +1,20 @@
+use matchbox_socket::PeerId;
+
+/// Connection and overflow events of an [`RtcClient`](super::RtcClient)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The signaling server assigned the socket a unique ID
+    IdAssigned(PeerId),
+    /// The socket has successfully connected to a host
+    ConnectedToHost(PeerId),
+    /// The socket disconnected from the host
+    DisconnectedFromHost { reason: Option<String> },
+    /// Payloads received from the host were dropped by the `OverflowPolicy`
+    /// of their protocol, because its buffer was full
+    ProtocolOverflow {
+        /// The name of the protocol
+        protocol: &'static str,
+        /// The number of payloads dropped
+        dropped: usize,
+    },
+}
@@ -0,0 +1,11 @@
+mod client;
+mod events;
+
+pub use crate::{
+    channel::{ChannelKind, RtcChannels},
+    events::SendErrorKind,
+    overflow::OverflowPolicy,
+};
+pub use client::{RtcClient, RtcClientBuilder};
+pub use events::ClientEvent;
+pub use matchbox_socket::MessageLoopFuture;
@@ -1,13 +1,7 @@
-use bevy::prelude::*;
-use bevy_matchbox::matchbox_socket::PeerId;
 use instant::Duration;
+use matchbox_socket::PeerId;
 use serde::{Deserialize, Serialize};
 
-// A name import hack to ensure the Payload macro works correctly.
-mod bevy_rtc {
-    pub use crate::protocol;
-}
-
 /// A packet containing information to track a peer's latency
 #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
 pub struct LatencyTracerPayload {
@@ -29,7 +23,11 @@ impl LatencyTracerPayload {
     }
 }
 
-#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Component)
+)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LatencyTracer {
     /// the peer being tracked
     pub peer_id: PeerId,
@@ -3,13 +3,38 @@
 #[cfg(all(target_arch = "wasm32", feature = "server"))]
 compile_error!("The 'server' feature is not supported on the wasm32 target architecture.");
 
+pub(crate) mod ack;
+pub(crate) mod backpressure;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) mod bandwidth;
+pub(crate) mod batch;
+pub mod channel;
+pub mod codec;
+pub(crate) mod compression;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) mod delta;
 pub(crate) mod events;
+pub(crate) mod fragment;
 pub(crate) mod latency;
+pub(crate) mod manifest;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) mod migration;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) mod order;
+pub(crate) mod overflow;
 pub mod protocol;
+pub(crate) mod quantize;
+#[cfg(any(feature = "client", feature = "server"))]
+pub(crate) mod rate;
+pub(crate) mod registry;
+pub(crate) mod sequence;
 pub(crate) mod socket;
+pub mod typescript;
 
 // Re-exports
+#[cfg(any(feature = "client", feature = "server"))]
 pub use bevy_matchbox;
+pub use matchbox_socket;
 
 #[cfg(feature = "server")]
 #[cfg_attr(docsrs, doc(cfg(feature = "server")))]
@@ -18,3 +43,7 @@ pub mod server;
 #[cfg(feature = "client")]
 #[cfg_attr(docsrs, doc(cfg(feature = "client")))]
 pub mod client;
+
+#[cfg(feature = "headless")]
+#[cfg_attr(docsrs, doc(cfg(feature = "headless")))]
+pub mod headless;
@@ -0,0 +1,353 @@
+use crate::registry::ProtocolRegistry;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// The protocols registered by a peer, exchanged when connecting so
+/// mismatched builds are caught before any payloads are lost.
+#[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
+#[payload(name = "bevy_rtc::ProtocolManifest")]
+pub(crate) struct ProtocolManifest {
+    pub protocols: Vec<ManifestEntry>,
+}
+
+/// A protocol as registered by a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ManifestEntry {
+    pub id: u16,
+    pub name: String,
+    pub codec: String,
+    pub version: u8,
+    /// Older versions which are migrated when read
+    pub migrations: Vec<u8>,
+    pub reads: bool,
+    pub sends: bool,
+}
+
+/// A difference between the protocols registered by this app and a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestMismatch {
+    /// The peer sends a protocol which is not read here
+    NotReadLocally { id: u16, name: String },
+    /// A protocol sent from here is not read by the peer
+    NotReadByPeer { id: u16, name: String },
+    /// Both sides use the payload ID for differently named payloads
+    Name {
+        id: u16,
+        local: String,
+        remote: String,
+    },
+    /// Both sides register the payload with different codecs
+    Codec {
+        id: u16,
+        name: String,
+        local: String,
+        remote: String,
+    },
+    /// A version of the payload is sent which the other side cannot migrate
+    Version {
+        id: u16,
+        name: String,
+        local: u8,
+        remote: u8,
+    },
+}
+
+impl ManifestMismatch {
+    /// The payload ID of the mismatched protocol.
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::NotReadLocally { id, .. }
+            | Self::NotReadByPeer { id, .. }
+            | Self::Name { id, .. }
+            | Self::Codec { id, .. }
+            | Self::Version { id, .. } => *id,
+        }
+    }
+}
+
+impl Display for ManifestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotReadLocally { id, name } => {
+                write!(f, "`{name}` ({id}) is sent by the peer but not read here")
+            }
+            Self::NotReadByPeer { id, name } => {
+                write!(
+                    f,
+                    "`{name}` ({id}) is sent from here but not read by the peer"
+                )
+            }
+            Self::Name { id, local, remote } => {
+                write!(
+                    f,
+                    "payload ID {id} is `{local}` here but `{remote}` on the peer"
+                )
+            }
+            Self::Codec {
+                id,
+                name,
+                local,
+                remote,
+            } => write!(
+                f,
+                "`{name}` ({id}) uses the {local} codec here but {remote} on the peer"
+            ),
+            Self::Version {
+                id,
+                name,
+                local,
+                remote,
+            } => write!(
+                f,
+                "`{name}` ({id}) is version {local} here but version {remote} on the peer"
+            ),
+        }
+    }
+}
+
+impl ManifestEntry {
+    /// Whether packets of a version of this payload can be read.
+    fn accepts(&self, version: u8) -> bool {
+        self.version == version || self.migrations.contains(&version)
+    }
+}
+
+impl ProtocolManifest {
+    /// Describe the protocols in a registry.
+    pub(crate) fn new(registry: &ProtocolRegistry) -> Self {
+        let protocols = registry
+            .iter()
+            .map(|(id, protocol)| ManifestEntry {
+                id,
+                name: protocol.name.to_string(),
+                codec: protocol.codec.to_string(),
+                version: protocol.version,
+                migrations: protocol.migrations.clone(),
+                reads: protocol.readable,
+                sends: protocol.sendable,
+            })
+            .collect();
+        Self { protocols }
+    }
+
+    /// The entry for a payload ID.
+    fn get(&self, id: u16) -> Option<&ManifestEntry> {
+        self.protocols.iter().find(|entry| entry.id == id)
+    }
+
+    /// Compare this manifest against a peer's. Protocols which neither side
+    /// sends to the other are not compared.
+    pub(crate) fn mismatches(&self, remote: &Self) -> Vec<ManifestMismatch> {
+        let mut mismatches = vec![];
+        for local in self.protocols.iter() {
+            // Whether payloads of this ID are sent from either side to the other
+            let exchanged = |remote: &ManifestEntry| {
+                (local.sends && remote.reads) || (remote.sends && local.reads)
+            };
+            match remote.get(local.id) {
+                Some(remote) if exchanged(remote) && remote.name != local.name => {
+                    mismatches.push(ManifestMismatch::Name {
+                        id: local.id,
+                        local: local.name.clone(),
+                        remote: remote.name.clone(),
+                    });
+                }
+                Some(remote) if exchanged(remote) && remote.codec != local.codec => {
+                    mismatches.push(ManifestMismatch::Codec {
+                        id: local.id,
+                        name: local.name.clone(),
+                        local: local.codec.clone(),
+                        remote: remote.codec.clone(),
+                    });
+                }
+                Some(remote)
+                    if (local.sends && remote.reads && !remote.accepts(local.version))
+                        || (remote.sends && local.reads && !local.accepts(remote.version)) =>
+                {
+                    mismatches.push(ManifestMismatch::Version {
+                        id: local.id,
+                        name: local.name.clone(),
+                        local: local.version,
+                        remote: remote.version,
+                    });
+                }
+                remote if local.sends && !remote.is_some_and(|remote| remote.reads) => {
+                    mismatches.push(ManifestMismatch::NotReadByPeer {
+                        id: local.id,
+                        name: local.name.clone(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        for remote in remote.protocols.iter().filter(|entry| entry.sends) {
+            let read = self.get(remote.id).is_some_and(|local| local.reads);
+            if !read && !mismatches.iter().any(|m| m.id() == remote.id) {
+                mismatches.push(ManifestMismatch::NotReadLocally {
+                    id: remote.id,
+                    name: remote.name.clone(),
+                });
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u16, name: &str, reads: bool, sends: bool) -> ManifestEntry {
+        ManifestEntry {
+            id,
+            name: name.to_string(),
+            codec: "Json".to_string(),
+            version: 0,
+            migrations: vec![],
+            reads,
+            sends,
+        }
+    }
+
+    fn manifest(protocols: Vec<ManifestEntry>) -> ProtocolManifest {
+        ProtocolManifest { protocols }
+    }
+
+    #[test]
+    fn matching_manifests() {
+        let local = manifest(vec![
+            entry(1, "Ping", true, true),
+            entry(2, "Chat", true, false),
+        ]);
+        let remote = manifest(vec![
+            entry(1, "Ping", true, true),
+            entry(2, "Chat", false, true),
+        ]);
+        assert_eq!(local.mismatches(&remote), vec![]);
+        assert_eq!(remote.mismatches(&local), vec![]);
+    }
+
+    #[test]
+    fn protocols_neither_side_sends_are_not_compared() {
+        let local = manifest(vec![entry(1, "Ping", true, false)]);
+        let remote = manifest(vec![entry(3, "Pong", true, false)]);
+        assert_eq!(local.mismatches(&remote), vec![]);
+    }
+
+    #[test]
+    fn unread_protocols() {
+        let local = manifest(vec![entry(1, "Ping", false, true)]);
+        let remote = manifest(vec![entry(2, "Pong", false, true)]);
+        assert_eq!(
+            local.mismatches(&remote),
+            vec![
+                ManifestMismatch::NotReadByPeer {
+                    id: 1,
+                    name: "Ping".to_string()
+                },
+                ManifestMismatch::NotReadLocally {
+                    id: 2,
+                    name: "Pong".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn name_and_codec_mismatches() {
+        let local = manifest(vec![
+            entry(1, "Ping", true, true),
+            entry(2, "Chat", true, true),
+        ]);
+        let mut chat = entry(2, "Chat", true, true);
+        chat.codec = "Bincode".to_string();
+        let remote = manifest(vec![entry(1, "Pong", true, true), chat]);
+        assert_eq!(
+            local.mismatches(&remote),
+            vec![
+                ManifestMismatch::Name {
+                    id: 1,
+                    local: "Ping".to_string(),
+                    remote: "Pong".to_string(),
+                },
+                ManifestMismatch::Codec {
+                    id: 2,
+                    name: "Chat".to_string(),
+                    local: "Json".to_string(),
+                    remote: "Bincode".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn names_and_codecs_are_not_compared_unless_exchanged() {
+        let mut chat = entry(2, "Chat", true, false);
+        chat.codec = "Bincode".to_string();
+        let local = manifest(vec![entry(1, "Ping", true, false), chat]);
+        let remote = manifest(vec![
+            entry(1, "Pong", true, false),
+            entry(2, "Chat", true, false),
+        ]);
+        assert_eq!(local.mismatches(&remote), vec![]);
+        assert_eq!(remote.mismatches(&local), vec![]);
+    }
+
+    #[test]
+    fn names_and_codecs_are_compared_when_sent_one_way() {
+        let mut chat = entry(2, "Chat", true, false);
+        chat.codec = "Bincode".to_string();
+        let local = manifest(vec![
+            entry(1, "Ping", false, true),
+            entry(2, "Chat", false, true),
+        ]);
+        let remote = manifest(vec![entry(1, "Pong", true, false), chat]);
+        assert_eq!(
+            local.mismatches(&remote),
+            vec![
+                ManifestMismatch::Name {
+                    id: 1,
+                    local: "Ping".to_string(),
+                    remote: "Pong".to_string(),
+                },
+                ManifestMismatch::Codec {
+                    id: 2,
+                    name: "Chat".to_string(),
+                    local: "Json".to_string(),
+                    remote: "Bincode".to_string(),
+                },
+            ]
+        );
+        assert_eq!(remote.mismatches(&local).len(), 2);
+    }
+
+    #[test]
+    fn versions_are_accepted_through_migrations() {
+        let mut old = entry(1, "Ping", true, true);
+        old.version = 1;
+        let mut new = entry(1, "Ping", true, true);
+        new.version = 2;
+        let version_mismatch = ManifestMismatch::Version {
+            id: 1,
+            name: "Ping".to_string(),
+            local: 2,
+            remote: 1,
+        };
+        assert_eq!(
+            manifest(vec![new.clone()]).mismatches(&manifest(vec![old.clone()])),
+            vec![version_mismatch.clone()]
+        );
+
+        // The old version is migrated here, but the peer cannot read the new
+        // version
+        new.migrations = vec![1];
+        assert_eq!(
+            manifest(vec![new.clone()]).mismatches(&manifest(vec![old.clone()])),
+            vec![version_mismatch]
+        );
+
+        // Neither matters if the new version is never sent
+        new.sends = false;
+        assert_eq!(manifest(vec![new]).mismatches(&manifest(vec![old])), vec![]);
+    }
+}
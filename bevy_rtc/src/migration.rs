@@ -0,0 +1,84 @@
+use crate::{codec::Codec, protocol::Payload};
+use bevy::utils::HashMap;
+use bevy_matchbox::matchbox_socket::Packet;
+use std::fmt::{self, Debug};
+
+type Decoder<M> = Box<dyn Fn(&Packet) -> Option<M> + Send + Sync>;
+
+/// Decoders for the older versions of a payload, which migrate them to the
+/// current version.
+pub struct Migrations<M: Payload> {
+    decoders: HashMap<u8, Decoder<M>>,
+}
+
+impl<M: Payload> Default for Migrations<M> {
+    fn default() -> Self {
+        Self {
+            decoders: HashMap::new(),
+        }
+    }
+}
+
+impl<M: Payload> Debug for Migrations<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
+
+impl<M: Payload> Migrations<M> {
+    /// Accept `Old` payloads encoded with the codec `C`, migrating them with
+    /// `migrate`.
+    pub(crate) fn add<Old: Payload, C: Codec>(&mut self, migrate: fn(Old) -> M) {
+        self.decoders.insert(
+            Old::version(),
+            Box::new(move |packet| Old::from_packet::<C>(packet).map(migrate)),
+        );
+    }
+
+    /// Decode a packet of an older version, if a migration from it exists.
+    pub(crate) fn decode(&self, version: u8, packet: &Packet) -> Option<M> {
+        self.decoders.get(&version)?(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Json;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
+    #[payload(name = "test.Move", version = 1)]
+    struct MoveV1 {
+        x: f32,
+    }
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[payload(name = "test.Move", version = 2)]
+    struct Move {
+        x: f32,
+        y: f32,
+    }
+
+    fn migrations() -> Migrations<Move> {
+        let mut migrations = Migrations::default();
+        migrations.add::<MoveV1, Json>(|old| Move { x: old.x, y: 0.0 });
+        migrations
+    }
+
+    #[test]
+    fn older_versions_are_migrated() {
+        let packet = MoveV1 { x: 3.0 }.to_packet::<Json>().unwrap();
+        assert_eq!(
+            migrations().decode(MoveV1::version(), &packet),
+            Some(Move { x: 3.0, y: 0.0 })
+        );
+    }
+
+    #[test]
+    fn unknown_versions_are_not_decoded() {
+        let packet = MoveV1 { x: 3.0 }.to_packet::<Json>().unwrap();
+        assert_eq!(migrations().decode(3, &packet), None);
+        assert_eq!(Migrations::<Move>::default().decode(1, &packet), None);
+    }
+}
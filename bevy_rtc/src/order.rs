@@ -0,0 +1,216 @@
+use crate::{overflow::OverflowPolicy, protocol::Payload};
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use matchbox_socket::PeerId;
+use std::{
+    any::{Any, TypeId},
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The position of a payload in the order payloads of every protocol are
+/// queued to be sent, so they are sent in that order.
+pub(crate) fn next_enqueued() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A payload received from a peer on an ordered protocol, see
+/// [`NetworkMessages`].
+pub struct NetworkMessage {
+    /// The peer which sent the payload
+    pub peer: PeerId,
+    protocol: &'static str,
+    payload: Box<dyn Any + Send + Sync>,
+}
+
+impl NetworkMessage {
+    /// The name of the protocol of the payload.
+    pub fn protocol(&self) -> &'static str {
+        self.protocol
+    }
+
+    /// Whether the payload is of type `M`.
+    pub fn is<M: Payload>(&self) -> bool {
+        self.payload.is::<M>()
+    }
+
+    /// The payload, if it is of type `M`.
+    pub fn downcast_ref<M: Payload>(&self) -> Option<&M> {
+        self.payload.downcast_ref()
+    }
+
+    /// Take the payload if it is of type `M`, or return the message as it was.
+    pub fn downcast<M: Payload>(self) -> Result<M, Self> {
+        match self.payload.downcast() {
+            Ok(payload) => Ok(*payload),
+            Err(payload) => Err(Self { payload, ..self }),
+        }
+    }
+}
+
+impl fmt::Debug for NetworkMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NetworkMessage")
+            .field("peer", &self.peer)
+            .field("protocol", &self.protocol)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Payloads received on ordered protocols, with the order they arrived in.
+///
+/// Each protocol's payloads from each peer are buffered separately, already
+/// in arrival order, and merged when read.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct OrderedMessages {
+    buffers: HashMap<(PeerId, TypeId), VecDeque<(u64, NetworkMessage)>>,
+}
+
+impl OrderedMessages {
+    /// Buffer payloads from a peer with the positions they arrived at, bounded
+    /// to `bound` payloads of their protocol from the peer. Returns how many
+    /// payloads were dropped by the overflow policy.
+    pub(crate) fn push<M: Payload>(
+        &mut self,
+        peer: PeerId,
+        received: Vec<(u64, M)>,
+        bound: usize,
+        overflow: OverflowPolicy,
+    ) -> usize {
+        let buffer = self.buffers.entry((peer, TypeId::of::<M>())).or_default();
+        let received = received.into_iter().map(|(arrival, payload)| {
+            let message = NetworkMessage {
+                peer,
+                protocol: M::reflect_name(),
+                payload: Box::new(payload),
+            };
+            (arrival, message)
+        });
+        overflow.buffer(buffer, received, bound)
+    }
+
+    /// Drop the payloads of peers which are no longer connected.
+    pub(crate) fn prune(&mut self, connected: &[PeerId]) {
+        self.buffers.retain(|(peer, _), _| connected.contains(peer));
+    }
+
+    fn len(&self) -> usize {
+        self.buffers.values().map(VecDeque::len).sum()
+    }
+
+    /// Take every payload in arrival order, merging the buffers.
+    fn drain(&mut self) -> impl Iterator<Item = NetworkMessage> {
+        let mut buffers: Vec<_> = self.buffers.drain().map(|(_, buffer)| buffer).collect();
+        let mut next: BinaryHeap<Reverse<(u64, usize)>> = buffers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, buffer)| Some(Reverse((buffer.front()?.0, index))))
+            .collect();
+        std::iter::from_fn(move || {
+            let Reverse((_, index)) = next.pop()?;
+            let (_, message) = buffers[index].pop_front()?;
+            if let Some((arrival, _)) = buffers[index].front() {
+                next.push(Reverse((*arrival, index)));
+            }
+            Some(message)
+        })
+    }
+}
+
+/// A [`SystemParam`] for reading the payloads of every protocol registered
+/// with `set_protocol_ordered`, in the order they arrived.
+///
+/// Payloads on the same ordered channel, such as the reliable channel, are
+/// read in the order they were sent, even across protocols. Otherwise they are
+/// read in the order the socket delivered them, which need not be the order
+/// they were sent.
+#[derive(SystemParam, Debug)]
+pub struct NetworkMessages<'w> {
+    messages: ResMut<'w, OrderedMessages>,
+}
+
+impl<'w> NetworkMessages<'w> {
+    /// Returns the number of payloads waiting to be read.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns true if there are no payloads waiting to be read.
+    pub fn is_empty(&self) -> bool {
+        self.messages.buffers.values().all(VecDeque::is_empty)
+    }
+
+    /// Consumes all payloads waiting to be read, in the order they arrived.
+    pub fn read(&mut self) -> impl Iterator<Item = NetworkMessage> + '_ {
+        self.messages.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+    const OTHER: PeerId = PeerId(Uuid::from_u128(2));
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Spawn(u8);
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Move(u8);
+
+    fn read(messages: &mut OrderedMessages) -> Vec<(PeerId, &'static str)> {
+        messages
+            .drain()
+            .map(|message| (message.peer, message.protocol()))
+            .collect()
+    }
+
+    #[test]
+    fn payloads_are_read_in_arrival_order_across_protocols() {
+        let mut messages = OrderedMessages::default();
+        let policy = OverflowPolicy::DropOldest;
+        messages.push(PEER, vec![(0, Spawn(0)), (3, Spawn(1))], 8, policy);
+        messages.push(PEER, vec![(1, Move(0)), (4, Move(1))], 8, policy);
+        messages.push(OTHER, vec![(2, Move(2))], 8, policy);
+        assert_eq!(messages.len(), 5);
+
+        let mut read = messages.drain();
+        let first = read.next().unwrap();
+        assert_eq!(first.downcast::<Spawn>().unwrap(), Spawn(0));
+        let second = read.next().unwrap();
+        assert_eq!(second.downcast_ref::<Move>(), Some(&Move(0)));
+        let peers: Vec<PeerId> = read.map(|message| message.peer).collect();
+        assert_eq!(peers, [OTHER, PEER, PEER]);
+        assert_eq!(messages.len(), 0);
+    }
+
+    #[test]
+    fn each_protocol_and_peer_is_bounded_separately() {
+        let mut messages = OrderedMessages::default();
+        let policy = OverflowPolicy::DropOldest;
+        assert_eq!(
+            messages.push(PEER, vec![(0, Spawn(0)), (1, Spawn(1))], 1, policy),
+            1
+        );
+        assert_eq!(messages.push(PEER, vec![(2, Move(0))], 1, policy), 0);
+        assert_eq!(messages.push(OTHER, vec![(3, Spawn(2))], 1, policy), 0);
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn payloads_of_departed_peers_are_dropped() {
+        let mut messages = OrderedMessages::default();
+        let policy = OverflowPolicy::DropOldest;
+        messages.push(PEER, vec![(0, Spawn(0))], 8, policy);
+        messages.push(OTHER, vec![(1, Spawn(1)), (2, Spawn(2))], 8, policy);
+
+        messages.prune(&[OTHER]);
+        let spawn = Spawn::reflect_name();
+        assert_eq!(read(&mut messages), [(OTHER, spawn), (OTHER, spawn)]);
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(any(feature = "client", feature = "server"))]
+use matchbox_socket::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+#[cfg(any(feature = "client", feature = "server"))]
+use std::collections::{HashMap, HashSet};
+
+/// What a protocol does with payloads received while its bounded buffer is
+/// full, see `set_protocol_overflow`. Each time payloads are dropped, a
+/// `ProtocolOverflow` event is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest buffered payloads to make room for new ones
+    #[default]
+    DropOldest,
+    /// Drop new payloads until the buffer is read
+    DropNewest,
+    /// Drop every buffered payload and keep only the newest one received
+    KeepLatest,
+    /// Drop new payloads, ignore everything else from the peer which sent
+    /// them, and ask it to disconnect. The socket cannot close the data
+    /// channels of a single peer, so the server relies on the peer complying,
+    /// as `bevy_rtc` clients do, and sends `RtcServerEvent::PeerOverflowed` to
+    /// act on peers which do not. Clients disconnect from the host themselves.
+    IgnoreAndRequestDisconnect,
+}
+
+impl OverflowPolicy {
+    /// Buffer received payloads up to `bound`, returning how many payloads
+    /// were dropped.
+    pub(crate) fn buffer<T>(
+        self,
+        buffer: &mut VecDeque<T>,
+        received: impl IntoIterator<Item = T>,
+        bound: usize,
+    ) -> usize {
+        let mut dropped = 0;
+        let mut overflowed = false;
+        for payload in received {
+            match self {
+                _ if buffer.len() < bound && !overflowed => buffer.push_back(payload),
+                OverflowPolicy::DropOldest => buffer.push_back(payload),
+                // Once overflowed, every later payload replaces the one kept
+                OverflowPolicy::KeepLatest => {
+                    dropped += buffer.len();
+                    buffer.clear();
+                    buffer.push_back(payload);
+                    overflowed = true;
+                }
+                OverflowPolicy::DropNewest | OverflowPolicy::IgnoreAndRequestDisconnect => {
+                    dropped += 1
+                }
+            }
+            while buffer.len() > bound {
+                buffer.pop_front();
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+}
+
+/// Asks a peer to disconnect, since the socket cannot disconnect a single
+/// peer.
+#[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone)]
+#[payload(name = "bevy_rtc::DisconnectPayload")]
+pub(crate) struct DisconnectPayload {
+    pub reason: String,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Peers to disconnect for overflowing a protocol with the
+/// [`OverflowPolicy::IgnoreAndRequestDisconnect`] policy. Packets from them
+/// are ignored until they leave.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct Disconnections {
+    /// The peers to disconnect, with the protocol each overflowed
+    pending: HashMap<PeerId, &'static str>,
+    ignored: HashSet<PeerId>,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl Disconnections {
+    /// Ignore a peer and ask it to disconnect for overflowing a protocol.
+    pub(crate) fn request(&mut self, peer: PeerId, protocol: &'static str) {
+        if self.ignored.insert(peer) {
+            self.pending.insert(peer, protocol);
+        }
+    }
+
+    /// Whether packets from a peer are ignored.
+    pub(crate) fn is_ignored(&self, peer: PeerId) -> bool {
+        self.ignored.contains(&peer)
+    }
+
+    /// Take the peers to disconnect, with the protocol each overflowed.
+    pub(crate) fn take_pending(&mut self) -> impl Iterator<Item = (PeerId, &'static str)> + '_ {
+        self.pending.drain()
+    }
+
+    /// Forget peers which are no longer connected.
+    pub(crate) fn prune(&mut self, connected: &[PeerId]) {
+        self.pending.retain(|peer, _| connected.contains(peer));
+        self.ignored.retain(|peer| connected.contains(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Buffer payloads 3 to 5 into a full buffer of 1 and 2, bounded to 3.
+    fn overflow(policy: OverflowPolicy) -> (Vec<u8>, usize) {
+        let mut buffer = VecDeque::from([1, 2]);
+        let dropped = policy.buffer(&mut buffer, [3, 4, 5], 3);
+        (buffer.into(), dropped)
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        assert_eq!(overflow(OverflowPolicy::DropOldest), (vec![3, 4, 5], 2));
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_oldest() {
+        assert_eq!(overflow(OverflowPolicy::DropNewest), (vec![1, 2, 3], 2));
+    }
+
+    #[test]
+    fn keep_latest_keeps_only_the_newest() {
+        assert_eq!(overflow(OverflowPolicy::KeepLatest), (vec![5], 4));
+    }
+
+    #[test]
+    fn keep_latest_does_not_depend_on_how_payloads_arrive() {
+        let mut buffer = VecDeque::new();
+        let dropped = OverflowPolicy::KeepLatest.buffer(&mut buffer, [1, 2, 3, 4, 5], 3);
+        assert_eq!((Vec::from(buffer), dropped), (vec![5], 4));
+    }
+
+    #[test]
+    fn ignore_and_request_disconnect_drops_the_newest() {
+        assert_eq!(
+            overflow(OverflowPolicy::IgnoreAndRequestDisconnect),
+            (vec![1, 2, 3], 2)
+        );
+    }
+
+    #[test]
+    fn nothing_is_dropped_within_the_bound() {
+        for policy in [
+            OverflowPolicy::DropOldest,
+            OverflowPolicy::DropNewest,
+            OverflowPolicy::KeepLatest,
+            OverflowPolicy::IgnoreAndRequestDisconnect,
+        ] {
+            let mut buffer = VecDeque::new();
+            assert_eq!(policy.buffer(&mut buffer, [1, 2, 3], 3), 0);
+            assert_eq!(buffer, [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn zero_bound_buffers_nothing() {
+        let mut buffer = VecDeque::new();
+        assert_eq!(OverflowPolicy::DropOldest.buffer(&mut buffer, [1, 2], 0), 2);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,16 +1,131 @@
-use bevy_matchbox::matchbox_socket::Packet;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::{
+    codec::{Codec, CodecError},
+    typescript::TypeScriptType,
+};
+use matchbox_socket::Packet;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 // Note: Intentional name collision with the trait Payload!
 // This is done commonly, like `serde::Serialize` is a trait and a derive macro.
-pub use proc_macro_payload::Payload;
+pub use proc_macro_payload::{DeltaPayload, Payload};
 
-#[derive(Deserialize, Serialize)]
-#[serde(bound = "M: DeserializeOwned")]
-pub struct RtcPacket<M: Payload> {
+/// The header prepended to every packet. It is independent of the codec, so
+/// packets can be routed before their payload is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcPacketHeader {
+    /// The ID of the payload
     pub msg_id: u16,
-    pub data: M,
+    /// The schema version of the payload
+    pub version: u8,
+    /// The ID of the codec the payload is encoded with
+    pub codec: u8,
+    /// Flags describing how the rest of the packet is framed
+    pub flags: u8,
+}
+
+impl RtcPacketHeader {
+    /// The encoded length of the header, in bytes.
+    pub const LEN: usize = 5;
+
+    /// The packet is one fragment of a larger packet.
+    pub const FLAG_FRAGMENT: u8 = 1 << 0;
+    /// The packet is a batch of length-prefixed packets.
+    pub const FLAG_BATCH: u8 = 1 << 1;
+    /// The payload is compressed, following a byte identifying the algorithm.
+    pub const FLAG_COMPRESSED: u8 = 1 << 2;
+    /// The payload follows a sequence number, from a sequenced protocol.
+    pub const FLAG_SEQUENCED: u8 = 1 << 3;
+    /// The packet is a datagram carrying acknowledgements, following its
+    /// sequence number and those it acknowledges.
+    pub const FLAG_ACKED: u8 = 1 << 4;
+
+    /// Split a packet into its header and encoded payload.
+    pub fn read(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < Self::LEN {
+            return None;
+        }
+        let (header, data) = packet.split_at(Self::LEN);
+        let header = Self {
+            msg_id: u16::from_le_bytes([header[0], header[1]]),
+            version: header[2],
+            codec: header[3],
+            flags: header[4],
+        };
+        Some((header, data))
+    }
+
+    /// Append the header to a buffer.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.msg_id.to_le_bytes());
+        buf.push(self.version);
+        buf.push(self.codec);
+        buf.push(self.flags);
+    }
+
+    /// Whether a flag is set.
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Derive a payload ID from a name using FNV-1a. Unlike `DefaultHasher`, the
+/// result is guaranteed to be the same across Rust versions and platforms.
+pub const fn stable_id(name: &str) -> u16 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+    let bytes = name.as_bytes();
+    let mut hash = FNV_OFFSET;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    // Fold all 64 bits into the 16 bit ID
+    (hash ^ (hash >> 16) ^ (hash >> 32) ^ (hash >> 48)) as u16
+}
+
+/// Items used by the `Payload` derive macro.
+#[doc(hidden)]
+pub mod __private {
+    use std::collections::HashMap;
+    use std::{
+        any::TypeId,
+        cell::RefCell,
+        sync::{Mutex, OnceLock},
+    };
+
+    pub use crate::{
+        codec::{Codec, CodecError},
+        quantize::{BitReader, BitWriter, PackBits, Packed, Quantize},
+    };
+    pub use serde::{self, de::DeserializeOwned, Deserialize, Serialize};
+
+    /// The name and ID of an instance of a generic payload, built once per
+    /// type. Each thread caches them, so the lock is only taken the first time
+    /// a thread uses the type, rather than on every encode and decode.
+    pub fn generic_payload<T: 'static>(build: impl FnOnce() -> String) -> (&'static str, u16) {
+        thread_local! {
+            static CACHED: RefCell<HashMap<TypeId, (&'static str, u16)>> = Default::default();
+        }
+        static NAMES: OnceLock<Mutex<HashMap<TypeId, (&'static str, u16)>>> = OnceLock::new();
+
+        let type_id = TypeId::of::<T>();
+        if let Some(cached) = CACHED.with(|cached| cached.borrow().get(&type_id).copied()) {
+            return cached;
+        }
+        let built = *NAMES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(type_id)
+            .or_insert_with(|| {
+                let name: &'static str = Box::leak(build().into_boxed_str());
+                (name, super::stable_id(name))
+            });
+        CACHED.with(|cached| cached.borrow_mut().insert(type_id, built));
+        built
+    }
 }
 
 pub trait Payload:
@@ -20,39 +135,125 @@ pub trait Payload:
 
     fn reflect_name() -> &'static str;
 
-    #[cfg(not(feature = "binary"))]
-    fn from_packet(packet: &Packet) -> Option<Self> {
-        serde_json::from_slice::<RtcPacket<Self>>(packet)
-            .ok()
-            .filter(|packet| packet.msg_id == Self::id())
-            .map(|packet| packet.data)
+    /// The schema version of the payload, set with `#[payload(version = N)]`.
+    fn version() -> u8 {
+        0
     }
 
-    #[cfg(feature = "binary")]
-    fn from_packet(packet: &Packet) -> Option<Self> {
-        bincode::deserialize::<RtcPacket<Self>>(packet)
-            .ok()
-            .filter(|packet| packet.msg_id == Self::id())
-            .map(|packet| packet.data)
+    fn from_packet<C: Codec>(packet: &Packet) -> Option<Self> {
+        RtcPacketHeader::read(packet)
+            .filter(|(header, _)| {
+                header.msg_id == Self::id()
+                    && header.version == Self::version()
+                    && header.codec == C::ID
+            })
+            .and_then(|(_, data)| Self::decode_body::<C>(data).ok())
     }
 
-    #[cfg(not(feature = "binary"))]
-    fn to_packet(&self) -> Packet {
-        let packet = RtcPacket {
+    /// Encode the payload by reference, directly after its header.
+    fn to_packet<C: Codec>(&self) -> Result<Packet, CodecError> {
+        let mut buf = vec![];
+        RtcPacketHeader {
             msg_id: Self::id(),
-            data: self.clone(),
-        };
+            version: Self::version(),
+            codec: C::ID,
+            flags: 0,
+        }
+        .write(&mut buf);
+        self.encode_body::<C>(&mut buf)?;
+        Ok(buf.into_boxed_slice())
+    }
 
-        serde_json::to_string(&packet).unwrap().as_bytes().into()
+    /// Encode the payload without its header. Overridden by the derive to
+    /// pack fields with `#[payload(quantize(...))]` or `#[payload(bits = N)]`.
+    fn encode_body<C: Codec>(&self, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        C::encode(self, buf)
     }
 
-    #[cfg(feature = "binary")]
-    fn to_packet(&self) -> Packet {
-        let packet = RtcPacket {
-            msg_id: Self::id(),
-            data: self.clone(),
-        };
+    /// Decode a payload encoded by [`Payload::encode_body`].
+    fn decode_body<C: Codec>(bytes: &[u8]) -> Result<Self, CodecError> {
+        C::decode(bytes)
+    }
+
+    /// The TypeScript type of the payload's JSON form, used by
+    /// [`typescript::bindings`](crate::typescript::bindings).
+    #[doc(hidden)]
+    fn typescript() -> TypeScriptType {
+        TypeScriptType::default()
+    }
+}
+
+/// A payload which is sent as the changes since the last state each peer
+/// acknowledged, rather than in full. Derive it on structs whose fields are
+/// `PartialEq` to send only the fields which changed.
+pub trait DeltaPayload: Payload {
+    /// The changes between two states
+    type Delta: Debug + Clone + Send + Sync + for<'a> Deserialize<'a> + Serialize + 'static;
+
+    /// The changes from `baseline` to this state.
+    fn diff(&self, baseline: &Self) -> Self::Delta;
+
+    /// Apply changes to this state.
+    fn apply(&mut self, delta: Self::Delta);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Json;
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    struct Inner;
 
-        bincode::serialize(&packet).unwrap().into_boxed_slice()
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    struct Wrapper<T, const N: usize>(Vec<T>);
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    #[payload(bound = "")]
+    struct Snapshot<T> {
+        value: T,
+    }
+
+    #[test]
+    fn generic_payloads_are_named_by_their_parameters() {
+        type Wrapped = Wrapper<Inner, 3>;
+        let name = "bevy_rtc::protocol::tests::Wrapper<bevy_rtc::protocol::tests::Inner, 3>";
+        assert_eq!(Wrapped::reflect_name(), name);
+        assert_eq!(Wrapped::id(), stable_id(name));
+        assert_ne!(Wrapped::id(), Wrapper::<Inner, 4>::id());
+
+        // Every thread shares the same name
+        let other = std::thread::spawn(Wrapped::reflect_name).join().unwrap();
+        assert!(std::ptr::eq(other, Wrapped::reflect_name()));
+    }
+
+    #[test]
+    fn generic_payloads_with_a_bound_take_any_parameter() {
+        let name = "bevy_rtc::protocol::tests::Snapshot<u32>";
+        assert_eq!(Snapshot::<u32>::reflect_name(), name);
+        assert_eq!(Snapshot::<u32>::id(), stable_id(name));
+        assert_ne!(Snapshot::<u32>::id(), Snapshot::<[f32; 3]>::id());
+
+        let packet = Snapshot { value: 7u32 }.to_packet::<Json>().unwrap();
+        let snapshot = Snapshot::<u32>::from_packet::<Json>(&packet).unwrap();
+        assert_eq!(snapshot.value, 7);
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let header = RtcPacketHeader {
+            msg_id: 0xBEEF,
+            version: 2,
+            codec: 1,
+            flags: RtcPacketHeader::FLAG_BATCH | RtcPacketHeader::FLAG_ACKED,
+        };
+        let mut buf = vec![];
+        header.write(&mut buf);
+        buf.push(42);
+        assert_eq!(buf.len(), RtcPacketHeader::LEN + 1);
+        assert_eq!(RtcPacketHeader::read(&buf), Some((header, &[42][..])));
+        assert!(header.has_flag(RtcPacketHeader::FLAG_ACKED));
+        assert!(!header.has_flag(RtcPacketHeader::FLAG_FRAGMENT));
+        assert_eq!(RtcPacketHeader::read(&buf[..2]), None);
     }
 }
@@ -0,0 +1,313 @@
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+
+/// Fields packed into as few bits as their `#[payload(...)]` attributes allow,
+/// serialized as a single byte string.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Packed(pub Vec<u8>);
+
+impl Serialize for Packed {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Packed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PackedVisitor;
+
+        impl<'de> Visitor<'de> for PackedVisitor {
+            type Value = Packed;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("packed bytes")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Packed, E> {
+                Ok(Packed(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Packed, E> {
+                Ok(Packed(v))
+            }
+
+            // Self-describing formats such as JSON encode bytes as a sequence
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Packed, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(Packed(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(PackedVisitor)
+    }
+}
+
+/// Writes values of arbitrary bit widths, least significant bit first.
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    /// Write the low `bits` bits of a value.
+    pub fn write(&mut self, value: u64, bits: u32) {
+        for i in 0..bits {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 == 1 {
+                *self.bytes.last_mut().expect("a byte was pushed") |= 1 << (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+
+    pub fn finish(self) -> Packed {
+        Packed(self.bytes)
+    }
+}
+
+/// Reads values written by a [`BitWriter`].
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(packed: &'a Packed) -> Self {
+        Self {
+            bytes: &packed.0,
+            pos: 0,
+        }
+    }
+
+    /// Read a value of `bits` bits, or `None` if too few bits remain.
+    pub fn read(&mut self, bits: u32) -> Option<u64> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.bytes.get(self.pos / 8)?;
+            value |= u64::from(byte >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+/// The largest value that fits in `bits` bits.
+fn max_value(bits: u32) -> u64 {
+    u64::MAX >> (64 - bits)
+}
+
+/// Floats which can be quantized to a fixed number of bits within a range.
+pub trait Quantize: Copy {
+    /// Map the value, clamped to `min..=max`, onto the integers that fit in
+    /// `bits` bits.
+    fn quantize(self, min: f64, max: f64, bits: u32) -> u64;
+
+    /// Map a quantized value back into `min..=max`.
+    fn dequantize(value: u64, min: f64, max: f64, bits: u32) -> Self;
+}
+
+impl Quantize for f64 {
+    fn quantize(self, min: f64, max: f64, bits: u32) -> u64 {
+        let steps = max_value(bits) as f64;
+        let normalized = ((self - min) / (max - min)).clamp(0.0, 1.0);
+        // NaN is clamped to NaN, and saturates to 0 when cast
+        (normalized * steps).round() as u64
+    }
+
+    fn dequantize(value: u64, min: f64, max: f64, bits: u32) -> Self {
+        let steps = max_value(bits) as f64;
+        min + (value.min(max_value(bits)) as f64 / steps) * (max - min)
+    }
+}
+
+impl Quantize for f32 {
+    fn quantize(self, min: f64, max: f64, bits: u32) -> u64 {
+        f64::from(self).quantize(min, max, bits)
+    }
+
+    fn dequantize(value: u64, min: f64, max: f64, bits: u32) -> Self {
+        f64::dequantize(value, min, max, bits) as f32
+    }
+}
+
+/// Bools and integers which can be packed into a fixed number of bits.
+/// Values which do not fit are clamped.
+pub trait PackBits: Copy {
+    fn pack(self, bits: u32) -> u64;
+
+    fn unpack(value: u64, bits: u32) -> Self;
+}
+
+impl PackBits for bool {
+    fn pack(self, _bits: u32) -> u64 {
+        u64::from(self)
+    }
+
+    fn unpack(value: u64, _bits: u32) -> Self {
+        value != 0
+    }
+}
+
+macro_rules! impl_pack_bits_unsigned {
+    ($($ty:ty),*) => {$(
+        impl PackBits for $ty {
+            fn pack(self, bits: u32) -> u64 {
+                (self as u64).min(max_value(bits))
+            }
+
+            fn unpack(value: u64, _bits: u32) -> Self {
+                value.min(<$ty>::MAX as u64) as $ty
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_pack_bits_signed {
+    ($($ty:ty),*) => {$(
+        impl PackBits for $ty {
+            fn pack(self, bits: u32) -> u64 {
+                let value = match bits {
+                    64.. => self as i64,
+                    _ => {
+                        let half = 1i64 << (bits - 1);
+                        (self as i64).clamp(-half, half - 1)
+                    }
+                };
+                // Zigzag encode, so small negative numbers stay small
+                ((value << 1) ^ (value >> 63)) as u64
+            }
+
+            fn unpack(value: u64, _bits: u32) -> Self {
+                let value = ((value >> 1) as i64) ^ -((value & 1) as i64);
+                value.clamp(<$ty>::MIN as i64, <$ty>::MAX as i64) as $ty
+            }
+        }
+    )*};
+}
+
+impl_pack_bits_unsigned!(u8, u16, u32, u64);
+impl_pack_bits_signed!(i8, i16, i32, i64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::{Codec, Json},
+        protocol::Payload,
+    };
+
+    #[test]
+    fn bits_round_trip() {
+        let values = [(1, 1), (5, 3), (0, 7), (0xABCD, 16), (u64::MAX, 64), (3, 2)];
+        let mut writer = BitWriter::default();
+        for (value, bits) in values {
+            writer.write(value, bits);
+        }
+        let packed = writer.finish();
+        // 93 bits fit in 12 bytes
+        assert_eq!(packed.0.len(), 12);
+
+        let mut reader = BitReader::new(&packed);
+        for (value, bits) in values {
+            assert_eq!(reader.read(bits), Some(value));
+        }
+        // The padding of the last byte reads as zeros
+        assert_eq!(reader.read(3), Some(0));
+        assert_eq!(reader.read(1), None);
+    }
+
+    #[test]
+    fn floats_quantize_within_a_step() {
+        let (min, max, bits) = (-100.0, 100.0, 16);
+        let step = (max - min) / max_value(bits) as f64;
+        for value in [-100.0, -33.3, 0.0, 0.1, 42.4242, 100.0] {
+            let quantized = f64::quantize(value, min, max, bits);
+            assert!(quantized <= max_value(bits));
+            let dequantized = f64::dequantize(quantized, min, max, bits);
+            assert!((dequantized - value).abs() <= step / 2.0);
+        }
+        let value = 0.25f32;
+        let dequantized = f32::dequantize(value.quantize(0.0, 1.0, 8), 0.0, 1.0, 8);
+        assert!((dequantized - value).abs() <= 1.0 / 255.0);
+    }
+
+    #[test]
+    fn floats_are_clamped_to_the_range() {
+        assert_eq!(f64::quantize(-5.0, 0.0, 1.0, 8), 0);
+        assert_eq!(f64::quantize(5.0, 0.0, 1.0, 8), 255);
+        assert_eq!(f64::quantize(f64::INFINITY, 0.0, 1.0, 8), 255);
+        assert_eq!(f64::quantize(f64::NAN, 0.0, 1.0, 8), 0);
+        // Out of range values read from a peer are clamped too
+        assert_eq!(f64::dequantize(1000, 0.0, 1.0, 8), 1.0);
+    }
+
+    #[test]
+    fn integers_are_clamped_to_the_bit_width() {
+        assert_eq!(u8::unpack(200u8.pack(3), 3), 7);
+        assert_eq!(u32::unpack(5u32.pack(3), 3), 5);
+        assert_eq!(u64::unpack(u64::MAX.pack(64), 64), u64::MAX);
+        assert_eq!(u8::unpack(u64::MAX, 64), u8::MAX);
+
+        for (value, expected) in [(-5i8, -4), (-4, -4), (-1, -1), (0, 0), (3, 3), (100, 3)] {
+            let packed = value.pack(3);
+            assert!(packed <= max_value(3));
+            assert_eq!(i8::unpack(packed, 3), expected);
+        }
+        assert_eq!(i64::unpack(i64::MIN.pack(64), 64), i64::MIN);
+        assert_eq!(i64::unpack(i64::MAX.pack(64), 64), i64::MAX);
+        assert!(bool::unpack(true.pack(1), 1));
+        assert!(!bool::unpack(false.pack(1), 1));
+    }
+
+    #[derive(proc_macro_payload::Payload, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Move {
+        player: String,
+        #[payload(quantize(min = -100.0, max = 100.0, bits = 16))]
+        x: f32,
+        #[payload(bits = 3)]
+        direction: u8,
+        #[payload(bits = 1)]
+        running: bool,
+        #[payload(bits = 4)]
+        turn: i8,
+    }
+
+    #[test]
+    fn packed_payloads_round_trip() {
+        let sent = Move {
+            player: "player".to_string(),
+            x: 150.0,
+            direction: 5,
+            running: true,
+            turn: -3,
+        };
+        let packet = sent.to_packet::<Json>().unwrap();
+        let received = Move::from_packet::<Json>(&packet).unwrap();
+        assert_eq!(
+            received,
+            Move {
+                x: 100.0,
+                ..sent.clone()
+            }
+        );
+
+        // Truncated packed fields are rejected
+        let mut buf = vec![];
+        Json::encode(
+            &serde_json::json!({ "player": "player", "__packed": [0] }),
+            &mut buf,
+        )
+        .unwrap();
+        assert!(Move::decode_body::<Json>(&buf).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use bevy::utils::HashMap;
+use bevy_matchbox::matchbox_socket::PeerId;
+use instant::{Duration, Instant};
+
+/// Limits how often lazy payloads of a protocol are sent to each peer.
+#[derive(Debug)]
+pub(crate) struct SendRate {
+    interval: Duration,
+    /// When each peer may next be sent a payload
+    next: HashMap<PeerId, Instant>,
+}
+
+impl SendRate {
+    /// A rate of `per_second` payloads to each peer.
+    ///
+    /// # Panics
+    /// If `per_second` is not positive and finite.
+    pub(crate) fn new(per_second: f64) -> Self {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "send rate must be positive, got {per_second}"
+        );
+        Self {
+            interval: Duration::from_secs_f64(1.0 / per_second),
+            next: HashMap::new(),
+        }
+    }
+
+    /// Take the slot of a peer if it is available, returning `false` if a
+    /// payload was sent to it too recently.
+    pub(crate) fn try_take(&mut self, peer: PeerId, now: Instant) -> bool {
+        let next = self.next.entry(peer).or_insert(now);
+        if now < *next {
+            return false;
+        }
+        // Keep to the rate on average when frames do not line up with it, but
+        // do not build up a burst of slots while nothing is sent.
+        *next = if now.duration_since(*next) < self.interval {
+            *next + self.interval
+        } else {
+            now + self.interval
+        };
+        true
+    }
+
+    /// Forget the peers for which `keep` returns `false`.
+    #[cfg(feature = "server")]
+    pub(crate) fn retain(&mut self, keep: impl Fn(&PeerId) -> bool) {
+        self.next.retain(|peer, _| keep(peer));
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::typescript::TypeScriptType;
+use crate::{codec::Codec, protocol::Payload};
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+
+/// The payload types registered as protocols, keyed by payload ID.
+#[cfg_attr(
+    any(feature = "client", feature = "server"),
+    derive(bevy::prelude::Resource)
+)]
+#[derive(Default, Debug)]
+pub(crate) struct ProtocolRegistry {
+    protocols: HashMap<u16, RegisteredProtocol>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RegisteredProtocol {
+    /// The type of the payload
+    pub type_id: TypeId,
+    /// The full type name of the payload, for diagnostics
+    pub type_name: &'static str,
+    /// The name the payload is reflected as
+    pub name: &'static str,
+    /// The schema version of the payload
+    pub version: u8,
+    /// The older versions of the payload which can be migrated from
+    pub migrations: Vec<u8>,
+    /// The name of the codec the payload is encoded with
+    pub codec: &'static str,
+    /// Whether a buffer exists to receive this payload
+    pub readable: bool,
+    /// Whether this payload is sent
+    pub sendable: bool,
+    /// Describes the payload for TypeScript bindings
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub typescript: fn() -> TypeScriptType,
+}
+
+impl ProtocolRegistry {
+    /// Register a payload type under its ID, encoded with the codec `C`.
+    /// Protocols registered as readable have received packets routed to them,
+    /// and those registered as sendable are expected to be read by peers.
+    ///
+    /// # Panics
+    /// Panics if a different payload type was already registered with the
+    /// same ID, since packets of both types would be indistinguishable, or if
+    /// the payload was already registered with a different codec.
+    pub(crate) fn register<M: Payload, C: Codec>(&mut self, readable: bool, sendable: bool) {
+        let registered = self
+            .protocols
+            .entry(M::id())
+            .or_insert_with(|| RegisteredProtocol {
+                type_id: TypeId::of::<M>(),
+                type_name: type_name::<M>(),
+                name: M::reflect_name(),
+                version: M::version(),
+                migrations: vec![],
+                codec: C::NAME,
+                readable: false,
+                sendable: false,
+                #[cfg(any(feature = "client", feature = "server"))]
+                typescript: M::typescript,
+            });
+        if registered.type_id != TypeId::of::<M>() {
+            panic!(
+                "payload ID collision: `{}` and `{}` both use ID {}, use `#[payload(id = ...)]` or `#[payload(name = ...)]` to disambiguate",
+                registered.type_name,
+                type_name::<M>(),
+                M::id()
+            );
+        }
+        if registered.codec != C::NAME {
+            panic!(
+                "`{}` is already registered with the {} codec, not {}",
+                registered.type_name,
+                registered.codec,
+                C::NAME
+            );
+        }
+        registered.readable |= readable;
+        registered.sendable |= sendable;
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Record that `Old` payloads encoded with the codec `C` are migrated to
+    /// the registered payload `M`.
+    ///
+    /// # Panics
+    /// Panics if `M` is not registered with the codec `C`, or if `Old` does not
+    /// share its payload ID with an older version.
+    pub(crate) fn register_migration<Old: Payload, M: Payload, C: Codec>(&mut self) {
+        let Some(registered) = self
+            .protocols
+            .get_mut(&M::id())
+            .filter(|registered| registered.type_id == TypeId::of::<M>())
+        else {
+            panic!(
+                "`{}` must be registered as a protocol before migrations to it",
+                type_name::<M>()
+            );
+        };
+        if registered.codec != C::NAME {
+            panic!(
+                "`{}` is registered with the {} codec, not {}",
+                registered.type_name,
+                registered.codec,
+                C::NAME
+            );
+        }
+        if Old::id() != M::id() {
+            panic!(
+                "`{}` must have the payload ID of `{}` to migrate to it, use `#[payload(name = \"{}\")]`",
+                type_name::<Old>(),
+                registered.type_name,
+                registered.name
+            );
+        }
+        if Old::version() >= M::version() || registered.migrations.contains(&Old::version()) {
+            panic!(
+                "`{}` is not an older version of `{}` without a migration, use `#[payload(version = ...)]`",
+                type_name::<Old>(),
+                registered.type_name
+            );
+        }
+        registered.migrations.push(Old::version());
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    /// Whether received packets with this payload ID have a protocol to be
+    /// routed to.
+    pub(crate) fn is_readable(&self, msg_id: u16) -> bool {
+        self.protocols
+            .get(&msg_id)
+            .is_some_and(|protocol| protocol.readable)
+    }
+
+    /// The registered protocols, in order of payload ID.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u16, &RegisteredProtocol)> + '_ {
+        let mut protocols: Vec<_> = self.protocols.iter().map(|(id, p)| (*id, p)).collect();
+        protocols.sort_by_key(|(id, _)| *id);
+        protocols.into_iter()
+    }
+}
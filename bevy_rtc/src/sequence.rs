@@ -0,0 +1,140 @@
+use crate::protocol::RtcPacketHeader;
+use matchbox_socket::{Packet, PeerId};
+use std::collections::HashMap;
+
+/// The length of the sequence number following the header of a sequenced
+/// packet.
+const SEQUENCE_LEN: usize = 2;
+
+/// Whether sequence number `a` is more recent than `b`, accounting for wrap
+/// around.
+pub(crate) fn is_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+/// Stamp an encoded packet with a sequence number, directly after its header.
+pub(crate) fn stamp(packet: Packet, sequence: u16) -> Packet {
+    let Some((header, data)) = RtcPacketHeader::read(&packet) else {
+        return packet;
+    };
+    let mut buf = Vec::with_capacity(packet.len() + SEQUENCE_LEN);
+    RtcPacketHeader {
+        flags: header.flags | RtcPacketHeader::FLAG_SEQUENCED,
+        ..header
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(&sequence.to_le_bytes());
+    buf.extend_from_slice(data);
+    buf.into_boxed_slice()
+}
+
+/// Remove the sequence number from a packet flagged as sequenced, restoring
+/// the packet it was stamped on. `data` is the packet without its header.
+/// Returns `None` if it is too short to hold one.
+pub(crate) fn strip(header: RtcPacketHeader, data: &[u8]) -> Option<(u16, Packet)> {
+    if data.len() < SEQUENCE_LEN {
+        return None;
+    }
+    let (sequence, data) = data.split_at(SEQUENCE_LEN);
+    let mut buf = Vec::with_capacity(RtcPacketHeader::LEN + data.len());
+    RtcPacketHeader {
+        flags: header.flags & !RtcPacketHeader::FLAG_SEQUENCED,
+        ..header
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(data);
+    Some((
+        u16::from_le_bytes([sequence[0], sequence[1]]),
+        buf.into_boxed_slice(),
+    ))
+}
+
+/// The sequence numbers of a sequenced protocol, per peer. Senders number the
+/// packets sent to each peer, and readers keep the newest number delivered
+/// from each peer to drop anything older.
+#[derive(Debug, Default)]
+pub(crate) struct Sequences {
+    peers: HashMap<PeerId, u16>,
+}
+
+impl Sequences {
+    /// Stamp a packet with the next sequence number for a peer.
+    pub(crate) fn stamp(&mut self, peer: PeerId, packet: Packet) -> Packet {
+        let next = self.peers.entry(peer).or_default();
+        let sequence = *next;
+        *next = next.wrapping_add(1);
+        stamp(packet, sequence)
+    }
+
+    /// Record a sequence number received from a peer. Returns `false` if it
+    /// is not newer than the newest delivered.
+    pub(crate) fn deliver(&mut self, peer: PeerId, sequence: u16) -> bool {
+        match self.peers.get_mut(&peer) {
+            Some(newest) if !is_newer(sequence, *newest) => false,
+            Some(newest) => {
+                *newest = sequence;
+                true
+            }
+            None => {
+                self.peers.insert(peer, sequence);
+                true
+            }
+        }
+    }
+
+    /// Forget the peers for which `keep` returns `false`.
+    #[cfg(feature = "server")]
+    pub(crate) fn retain(&mut self, keep: impl Fn(&PeerId) -> bool) {
+        self.peers.retain(|peer, _| keep(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    #[test]
+    fn newer_accounts_for_wraparound() {
+        assert!(is_newer(1, 0));
+        assert!(!is_newer(0, 1));
+        assert!(!is_newer(7, 7));
+        assert!(is_newer(0, u16::MAX));
+        assert!(!is_newer(u16::MAX, 0));
+        assert!(is_newer(5, u16::MAX - 5));
+        // Numbers half the range or more ahead are taken to have wrapped
+        assert!(!is_newer(u16::MAX / 2 + 1, 0));
+        assert!(is_newer(0, 40_000));
+    }
+
+    #[test]
+    fn stale_sequences_are_dropped_across_wraparound() {
+        let mut sequences = Sequences::default();
+        assert!(sequences.deliver(PEER, u16::MAX - 1));
+        assert!(sequences.deliver(PEER, 0));
+        assert!(!sequences.deliver(PEER, u16::MAX));
+        assert!(!sequences.deliver(PEER, 0));
+        assert!(sequences.deliver(PEER, 1));
+    }
+
+    #[test]
+    fn stamps_round_trip() {
+        let mut buf = vec![];
+        let header = RtcPacketHeader {
+            msg_id: 3,
+            version: 1,
+            codec: 0,
+            flags: 0,
+        };
+        header.write(&mut buf);
+        buf.extend_from_slice(b"payload");
+        let packet: Packet = buf.into_boxed_slice();
+
+        let stamped = stamp(packet.clone(), u16::MAX);
+        let (stamped_header, data) = RtcPacketHeader::read(&stamped).unwrap();
+        assert!(stamped_header.has_flag(RtcPacketHeader::FLAG_SEQUENCED));
+        assert_eq!(strip(stamped_header, data), Some((u16::MAX, packet)));
+    }
+}
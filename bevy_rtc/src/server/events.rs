@@ -1,3 +1,4 @@
+use crate::manifest::ManifestMismatch;
 use bevy::ecs::event::Event;
 use bevy_matchbox::matchbox_socket::PeerId;
 
@@ -10,4 +11,22 @@ pub enum RtcServerEvent {
     ClientJoined(PeerId),
     /// A peer has left this server
     ClientLeft(PeerId),
+    /// A peer registered protocols which do not match this server's, usually
+    /// because it is running a different build. `bevy_rtc` clients disconnect
+    /// themselves on a mismatch, but the server stays connected to the peer
+    /// and keeps exchanging payloads with it, so a client which does not check
+    /// its manifest stays connected unless this event is handled.
+    ProtocolMismatch {
+        peer: PeerId,
+        mismatches: Vec<ManifestMismatch>,
+    },
+    /// A peer overflowed a protocol with the
+    /// `OverflowPolicy::IgnoreAndRequestDisconnect` policy. Its packets are
+    /// ignored and it was asked to disconnect, but the socket cannot close its
+    /// data channels, so it stays connected until it complies.
+    PeerOverflowed {
+        peer: PeerId,
+        /// The name of the protocol
+        protocol: &'static str,
+    },
 }
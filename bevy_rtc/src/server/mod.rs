@@ -5,6 +5,23 @@ mod state;
 mod system_params;
 mod systems;
 
+pub use crate::{
+    ack::{AckConfig, MessageId},
+    backpressure::BackpressureConfig,
+    bandwidth::BandwidthConfig,
+    batch::BatchingConfig,
+    channel::{ChannelKind, RtcChannels},
+    compression::{Compression, CompressionAlgorithm, CompressionConfig},
+    events::{
+        CongestionEvent, DeliveryEvent, DeliveryStatus, NetworkSendError, ProtocolOverflow,
+        SendErrorKind, UnknownPayloadEvent,
+    },
+    fragment::FragmentationConfig,
+    manifest::ManifestMismatch,
+    order::{NetworkMessage, NetworkMessages},
+    overflow::OverflowPolicy,
+    socket::MessageMeta,
+};
 pub use events::RtcServerEvent;
 pub use plugin::RtcServerPlugin;
 pub use router::AddProtocolExt;
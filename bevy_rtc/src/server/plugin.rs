@@ -1,7 +1,19 @@
 use crate::{
-    events::SocketRecvEvent,
+    ack::{AckConfig, Acks},
+    backpressure::{BackpressureConfig, Buffered, ReceiptPayload},
+    bandwidth::{Bandwidth, BandwidthConfig},
+    batch::BatchingConfig,
+    channel::RtcChannels,
+    compression::CompressionConfig,
+    events::{
+        CongestionEvent, DeliveryEvent, NetworkSendError, ProtocolOverflow, UnknownPayloadEvent,
+    },
+    fragment::{FragmentationConfig, Fragmenter, Reassembler},
     latency::LatencyTracerPayload,
-    socket::{common_socket_reader, RtcSocket},
+    manifest::ProtocolManifest,
+    order::OrderedMessages,
+    overflow::{DisconnectPayload, Disconnections},
+    socket::{common_socket_reader, common_socket_writer, Outbox, RoutedPackets, RtcSocket},
 };
 use bevy::{prelude::*, time::common_conditions::on_timer};
 use instant::Duration;
@@ -17,9 +29,32 @@ pub struct RtcServerPlugin {
 
 impl Plugin for RtcServerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<SocketRecvEvent>()
+        app.add_event::<UnknownPayloadEvent>()
+            .add_event::<NetworkSendError>()
+            .add_event::<DeliveryEvent>()
+            .add_event::<CongestionEvent>()
+            .add_event::<ProtocolOverflow>()
+            .init_resource::<RoutedPackets>()
+            .init_resource::<OrderedMessages>()
+            .init_resource::<Disconnections>()
+            .init_resource::<FragmentationConfig>()
+            .init_resource::<Fragmenter>()
+            .init_resource::<Reassembler>()
+            .init_resource::<BatchingConfig>()
+            .init_resource::<BandwidthConfig>()
+            .init_resource::<Bandwidth>()
+            .init_resource::<AckConfig>()
+            .init_resource::<Acks>()
+            .init_resource::<BackpressureConfig>()
+            .init_resource::<Buffered>()
+            .init_resource::<CompressionConfig>()
+            .init_resource::<RtcChannels>()
+            .init_resource::<Outbox>()
             .add_event::<RtcServerEvent>()
             .add_bounded_protocol::<LatencyTracerPayload>(2)
+            .add_bounded_protocol::<ProtocolManifest>(1)
+            .add_bounded_protocol::<ReceiptPayload>(4)
+            .add_bounded_protocol::<DisconnectPayload>(1)
             .init_state::<RtcServerStatus>()
             .insert_resource(RtcState::new((Ipv4Addr::UNSPECIFIED, self.port).into()))
             .add_systems(
@@ -38,11 +73,20 @@ impl Plugin for RtcServerPlugin {
                     .chain()
                     .run_if(resource_exists::<RtcSocket>),
             )
+            .add_systems(
+                Last,
+                common_socket_writer.run_if(resource_exists::<RtcSocket>),
+            )
             .add_systems(
                 Update,
                 (
+                    systems::send_manifests,
+                    systems::read_manifests,
+                    systems::send_disconnects,
                     systems::read_latency_tracers,
                     systems::send_latency_tracers.run_if(on_timer(Duration::from_millis(100))),
+                    systems::read_receipts,
+                    systems::send_receipts.run_if(on_timer(Duration::from_millis(100))),
                 )
                     .run_if(in_state(RtcServerStatus::Ready)),
             );
@@ -0,0 +1,53 @@
+use super::{IncomingMessages, OutgoingMessages};
+use crate::{
+    delta::{DeltaAck, DeltaBaselines, DeltaUpdate},
+    protocol::DeltaPayload,
+    server::RtcState,
+};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+
+impl<M: DeltaPayload> DeltaBaselines<M> {
+    /// Diff the states queued this frame against each recipient's baseline,
+    /// and queue the updates to be sent.
+    pub(crate) fn send_deltas(
+        mut baselines: ResMut<Self>,
+        mut states: ResMut<OutgoingMessages<M>>,
+        mut acks: ResMut<IncomingMessages<DeltaAck<M>>>,
+        mut updates: ResMut<OutgoingMessages<DeltaUpdate<M>>>,
+        state: Res<RtcState>,
+    ) {
+        baselines.retain(|peer| state.peers.contains(peer));
+        for (peer, acks) in acks.messages.iter_mut() {
+            for (_, ack) in acks.drain(..) {
+                baselines.ack(*peer, ack.sequence);
+            }
+        }
+        updates.compression = states.compression;
+
+        let peers: Vec<PeerId> = state.peers().collect();
+        for (enqueued, channel, message) in states.to_all.iter() {
+            for peer in peers.iter() {
+                let update = baselines.update(*peer, message);
+                updates.to_peer.push((*enqueued, *channel, *peer, update));
+            }
+        }
+        for (enqueued, channel, except, message) in states.to_all_except.iter() {
+            for peer in peers.iter().filter(|p| *p != except) {
+                let update = baselines.update(*peer, message);
+                updates.to_peer.push((*enqueued, *channel, *peer, update));
+            }
+        }
+        for (enqueued, channel, peer, message) in states.to_peer.iter() {
+            let update = baselines.update(*peer, message);
+            updates.to_peer.push((*enqueued, *channel, *peer, update));
+        }
+        if !states.encoded.is_empty() {
+            warn!(
+                "Dropping {} payloads sent with `try_*`, which delta protocols do not support",
+                M::reflect_name()
+            );
+        }
+        states.flush();
+    }
+}
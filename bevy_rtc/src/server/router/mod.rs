@@ -1,11 +1,19 @@
+mod delta;
 mod receive;
 mod send;
 
 use crate::{
-    protocol::Payload,
-    socket::{common_socket_reader, RtcSocket},
+    codec::{Codec, Json},
+    compression::Compression,
+    delta::{DeltaAck, DeltaBaselines, DeltaUpdate},
+    overflow::OverflowPolicy,
+    protocol::{DeltaPayload, Payload},
+    rate::SendRate,
+    registry::ProtocolRegistry,
+    sequence::Sequences,
+    socket::{common_socket_reader, common_socket_writer, RtcSocket},
 };
-use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy::prelude::*;
 
 pub use receive::IncomingMessages;
 pub use send::OutgoingMessages;
@@ -26,87 +34,290 @@ pub trait AddProtocolExt {
     /// Register a protocol for sending and receiving. Use a growable buffer
     /// for receiving.
     fn add_unbounded_protocol<M: Payload>(&mut self) -> &mut Self;
+    /// Like [`AddProtocolExt::add_sendonly_protocol`], encoding payloads with
+    /// the codec `C`.
+    fn add_sendonly_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self;
+    /// Like [`AddProtocolExt::add_readonly_bounded_protocol`], decoding
+    /// payloads with the codec `C`.
+    fn add_readonly_bounded_protocol_with_codec<M: Payload, C: Codec>(
+        &mut self,
+        bound: usize,
+    ) -> &mut Self;
+    /// Like [`AddProtocolExt::add_readonly_unbounded_protocol`], decoding
+    /// payloads with the codec `C`.
+    fn add_readonly_unbounded_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self;
+    /// Like [`AddProtocolExt::add_bounded_protocol`], encoding and decoding
+    /// payloads with the codec `C`.
+    fn add_bounded_protocol_with_codec<M: Payload, C: Codec>(&mut self, bound: usize) -> &mut Self;
+    /// Like [`AddProtocolExt::add_unbounded_protocol`], encoding and decoding
+    /// payloads with the codec `C`.
+    fn add_unbounded_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self;
+    /// Accept an older version of a readable protocol, converting it with
+    /// `migrate`. `Old` must share the payload ID of `M` and have an older
+    /// `#[payload(version = ...)]`.
+    fn add_payload_migration<Old: Payload, M: Payload>(
+        &mut self,
+        migrate: fn(Old) -> M,
+    ) -> &mut Self;
+    /// Register a protocol which is sent as the changes since the last state
+    /// each peer acknowledged, rather than in full. Payloads are sent with the
+    /// [`NetworkWriter`](crate::server::NetworkWriter) as usual, but the
+    /// `try_*` methods are not supported.
+    fn add_delta_protocol<M: DeltaPayload>(&mut self) -> &mut Self;
+    /// Like [`AddProtocolExt::add_delta_protocol`], encoding payloads with the
+    /// codec `C`.
+    fn add_delta_protocol_with_codec<M: DeltaPayload, C: Codec>(&mut self) -> &mut Self;
+    /// Compress the payloads of a sendable protocol. Peers decompress them
+    /// regardless of how they registered the protocol.
+    fn set_protocol_compression<M: Payload>(&mut self, compression: Compression) -> &mut Self;
+    /// Set the channel the `send_*` methods of the
+    /// [`NetworkWriter`](crate::server::NetworkWriter) use for a sendable
+    /// protocol, as an index into [`RtcChannels`](crate::server::RtcChannels).
+    /// Defaults to the reliable channel.
+    fn set_protocol_channel<M: Payload>(&mut self, channel: usize) -> &mut Self;
+    /// Number the payloads of a protocol sent to each peer, and drop those
+    /// read from each peer which are older than the newest already delivered.
+    /// Set it on both the client and server.
+    fn set_protocol_sequenced<M: Payload>(&mut self) -> &mut Self;
+    /// Set how urgently the payloads of a sendable protocol are sent when a
+    /// peer's [`BandwidthConfig`](crate::server::BandwidthConfig) budget is
+    /// exceeded. Deferred payloads gain their priority again each frame they
+    /// wait. Defaults to 1.
+    fn set_protocol_priority<M: Payload>(&mut self, priority: f32) -> &mut Self;
+    /// Limit the payloads of a sendable protocol queued with the `*_with`
+    /// methods of the [`NetworkWriter`](crate::server::NetworkWriter) to
+    /// `per_second` to each peer. Their closures are only called when a payload
+    /// will be sent, and otherwise dropped without sending anything later.
+    /// Other payloads are not limited.
+    ///
+    /// # Panics
+    /// If `per_second` is not positive.
+    fn set_protocol_send_rate<M: Payload>(&mut self, per_second: f64) -> &mut Self;
+    /// Deliver the payloads of a readable protocol through
+    /// [`NetworkMessages`](crate::server::NetworkMessages) rather than its
+    /// [`NetworkReader`](crate::server::NetworkReader), in the order they
+    /// arrived relative to the payloads of other ordered protocols. The bound
+    /// of the protocol still applies to the payloads buffered from each peer.
+    /// Delta protocols are not supported.
+    fn set_protocol_ordered<M: Payload>(&mut self) -> &mut Self;
+    /// Set what a readable protocol drops when more payloads are received
+    /// from a peer than its bound. Defaults to
+    /// [`OverflowPolicy::DropOldest`](crate::server::OverflowPolicy::DropOldest).
+    fn set_protocol_overflow<M: Payload>(&mut self, policy: OverflowPolicy) -> &mut Self;
+    /// Like [`AddProtocolExt::add_payload_migration`], for protocols
+    /// registered with the codec `C`.
+    fn add_payload_migration_with_codec<Old: Payload, M: Payload, C: Codec>(
+        &mut self,
+        migrate: fn(Old) -> M,
+    ) -> &mut Self;
 }
 
 impl AddProtocolExt for App {
     fn add_sendonly_protocol<M: Payload>(&mut self) -> &mut Self {
+        self.add_sendonly_protocol_with_codec::<M, Json>()
+    }
+
+    fn add_readonly_unbounded_protocol<M: Payload>(&mut self) -> &mut Self {
+        self.add_readonly_bounded_protocol::<M>(usize::MAX)
+    }
+
+    fn add_readonly_bounded_protocol<M: Payload>(&mut self, bound: usize) -> &mut Self {
+        self.add_readonly_bounded_protocol_with_codec::<M, Json>(bound)
+    }
+
+    fn add_unbounded_protocol<M: Payload>(&mut self) -> &mut Self {
+        self.add_bounded_protocol::<M>(usize::MAX)
+    }
+
+    fn add_bounded_protocol<M: Payload>(&mut self, bound: usize) -> &mut Self {
+        self.add_bounded_protocol_with_codec::<M, Json>(bound)
+    }
+
+    fn add_payload_migration<Old: Payload, M: Payload>(
+        &mut self,
+        migrate: fn(Old) -> M,
+    ) -> &mut Self {
+        self.add_payload_migration_with_codec::<Old, M, Json>(migrate)
+    }
+
+    fn add_delta_protocol<M: DeltaPayload>(&mut self) -> &mut Self {
+        self.add_delta_protocol_with_codec::<M, Json>()
+    }
+
+    fn add_sendonly_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self {
         if self.world.contains_resource::<OutgoingMessages<M>>() {
             panic!("server already contains resource: {}", M::reflect_name());
         }
-        self.insert_resource(OutgoingMessages::<M> {
-            reliable_to_all: vec![],
-            unreliable_to_all: vec![],
-            reliable_to_all_except: vec![],
-            unreliable_to_all_except: vec![],
-            reliable_to_peer: vec![],
-            unreliable_to_peer: vec![],
-        })
-        .add_systems(
-            Last,
-            OutgoingMessages::<M>::send_payloads.run_if(resource_exists::<RtcSocket>),
-        );
+        self.world
+            .get_resource_or_insert_with(ProtocolRegistry::default)
+            .register::<M, C>(false, true);
+        self.insert_resource(OutgoingMessages::<M>::new(M::to_packet::<C>))
+            .add_systems(
+                Last,
+                OutgoingMessages::<M>::send_payloads
+                    .before(common_socket_writer)
+                    .run_if(resource_exists::<RtcSocket>),
+            );
 
         self
     }
 
-    fn add_readonly_unbounded_protocol<M: Payload>(&mut self) -> &mut Self {
-        self.add_readonly_bounded_protocol::<M>(usize::MAX)
+    fn add_readonly_unbounded_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self {
+        self.add_readonly_bounded_protocol_with_codec::<M, C>(usize::MAX)
     }
 
-    fn add_readonly_bounded_protocol<M: Payload>(&mut self, bound: usize) -> &mut Self {
+    fn add_readonly_bounded_protocol_with_codec<M: Payload, C: Codec>(
+        &mut self,
+        bound: usize,
+    ) -> &mut Self {
         if self.world.contains_resource::<IncomingMessages<M>>() {
             panic!("server already contains resource: {}", M::reflect_name());
         }
-        self.insert_resource(IncomingMessages::<M> {
-            messages: HashMap::new(),
-            bound,
-        })
-        .add_systems(
-            First,
-            IncomingMessages::<M>::receive_payloads
-                .after(common_socket_reader)
-                .run_if(resource_exists::<RtcSocket>),
-        );
+        self.world
+            .get_resource_or_insert_with(ProtocolRegistry::default)
+            .register::<M, C>(true, false);
+        self.insert_resource(IncomingMessages::<M>::new(M::from_packet::<C>, bound))
+            .add_systems(
+                First,
+                IncomingMessages::<M>::receive_payloads
+                    .after(common_socket_reader)
+                    .run_if(resource_exists::<RtcSocket>),
+            );
 
         self
     }
 
-    fn add_unbounded_protocol<M: Payload>(&mut self) -> &mut Self {
-        self.add_bounded_protocol::<M>(usize::MAX)
+    fn add_unbounded_protocol_with_codec<M: Payload, C: Codec>(&mut self) -> &mut Self {
+        self.add_bounded_protocol_with_codec::<M, C>(usize::MAX)
     }
 
-    fn add_bounded_protocol<M: Payload>(&mut self, bound: usize) -> &mut Self
-    where
-        M: Payload,
-    {
+    fn add_bounded_protocol_with_codec<M: Payload, C: Codec>(&mut self, bound: usize) -> &mut Self {
         if self.world.contains_resource::<IncomingMessages<M>>()
             || self.world.contains_resource::<OutgoingMessages<M>>()
         {
             panic!("server already contains resource: {}", M::reflect_name());
         }
-        self.insert_resource(IncomingMessages::<M> {
-            messages: HashMap::new(),
-            bound,
-        })
-        .insert_resource(OutgoingMessages::<M> {
-            reliable_to_all: vec![],
-            unreliable_to_all: vec![],
-            reliable_to_all_except: vec![],
-            unreliable_to_all_except: vec![],
-            reliable_to_peer: vec![],
-            unreliable_to_peer: vec![],
-        })
-        .add_systems(
-            First,
-            IncomingMessages::<M>::receive_payloads
-                .after(common_socket_reader)
-                .run_if(resource_exists::<RtcSocket>),
-        )
-        .add_systems(
-            Last,
-            OutgoingMessages::<M>::send_payloads.run_if(resource_exists::<RtcSocket>),
-        );
+        self.world
+            .get_resource_or_insert_with(ProtocolRegistry::default)
+            .register::<M, C>(true, true);
+        self.insert_resource(IncomingMessages::<M>::new(M::from_packet::<C>, bound))
+            .insert_resource(OutgoingMessages::<M>::new(M::to_packet::<C>))
+            .add_systems(
+                First,
+                IncomingMessages::<M>::receive_payloads
+                    .after(common_socket_reader)
+                    .run_if(resource_exists::<RtcSocket>),
+            )
+            .add_systems(
+                Last,
+                OutgoingMessages::<M>::send_payloads
+                    .before(common_socket_writer)
+                    .run_if(resource_exists::<RtcSocket>),
+            );
+
+        self
+    }
+
+    fn add_payload_migration_with_codec<Old: Payload, M: Payload, C: Codec>(
+        &mut self,
+        migrate: fn(Old) -> M,
+    ) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(ProtocolRegistry::default)
+            .register_migration::<Old, M, C>();
+        match self.world.get_resource_mut::<IncomingMessages<M>>() {
+            Some(mut incoming) => incoming.migrations.add::<Old, C>(migrate),
+            None => panic!("server does not read protocol: {}", M::reflect_name()),
+        }
+        self
+    }
+
+    fn set_protocol_compression<M: Payload>(&mut self, compression: Compression) -> &mut Self {
+        match self.world.get_resource_mut::<OutgoingMessages<M>>() {
+            Some(mut outgoing) => outgoing.compression = Some(compression),
+            None => panic!("server does not send protocol: {}", M::reflect_name()),
+        }
+        self
+    }
+
+    fn set_protocol_channel<M: Payload>(&mut self, channel: usize) -> &mut Self {
+        match self.world.get_resource_mut::<OutgoingMessages<M>>() {
+            Some(mut outgoing) => outgoing.channel = channel,
+            None => panic!("server does not send protocol: {}", M::reflect_name()),
+        }
+        self
+    }
+
+    fn set_protocol_priority<M: Payload>(&mut self, priority: f32) -> &mut Self {
+        match self.world.get_resource_mut::<OutgoingMessages<M>>() {
+            Some(mut outgoing) => outgoing.priority = priority,
+            None => panic!("server does not send protocol: {}", M::reflect_name()),
+        }
+        self
+    }
 
+    fn set_protocol_send_rate<M: Payload>(&mut self, per_second: f64) -> &mut Self {
+        match self.world.get_resource_mut::<OutgoingMessages<M>>() {
+            Some(mut outgoing) => outgoing.send_rate = Some(SendRate::new(per_second)),
+            None => panic!("server does not send protocol: {}", M::reflect_name()),
+        }
         self
     }
+
+    fn set_protocol_ordered<M: Payload>(&mut self) -> &mut Self {
+        match self.world.get_resource_mut::<IncomingMessages<M>>() {
+            Some(mut incoming) => incoming.ordered = true,
+            None => panic!("server does not read protocol: {}", M::reflect_name()),
+        }
+        self
+    }
+
+    fn set_protocol_overflow<M: Payload>(&mut self, policy: OverflowPolicy) -> &mut Self {
+        match self.world.get_resource_mut::<IncomingMessages<M>>() {
+            Some(mut incoming) => incoming.overflow = policy,
+            None => panic!("server does not read protocol: {}", M::reflect_name()),
+        }
+        self
+    }
+
+    fn set_protocol_sequenced<M: Payload>(&mut self) -> &mut Self {
+        let sends = match self.world.get_resource_mut::<OutgoingMessages<M>>() {
+            Some(mut outgoing) => {
+                outgoing.sequences = Some(Sequences::default());
+                true
+            }
+            None => false,
+        };
+        let reads = match self.world.get_resource_mut::<IncomingMessages<M>>() {
+            Some(mut incoming) => {
+                incoming.sequences = Some(Sequences::default());
+                true
+            }
+            None => false,
+        };
+        if !sends && !reads {
+            panic!(
+                "server does not send or read protocol: {}",
+                M::reflect_name()
+            );
+        }
+        self
+    }
+
+    fn add_delta_protocol_with_codec<M: DeltaPayload, C: Codec>(&mut self) -> &mut Self {
+        if self.world.contains_resource::<OutgoingMessages<M>>() {
+            panic!("server already contains resource: {}", M::reflect_name());
+        }
+
+        self.add_sendonly_protocol_with_codec::<DeltaUpdate<M>, C>()
+            .add_readonly_bounded_protocol_with_codec::<DeltaAck<M>, C>(16)
+            .init_resource::<DeltaBaselines<M>>()
+            .insert_resource(OutgoingMessages::<M>::new(M::to_packet::<C>))
+            .add_systems(
+                Last,
+                DeltaBaselines::<M>::send_deltas
+                    .before(OutgoingMessages::<DeltaUpdate<M>>::send_payloads)
+                    .run_if(resource_exists::<RtcSocket>),
+            )
+    }
 }
@@ -1,47 +1,131 @@
-use crate::{events::SocketRecvEvent, protocol::Payload};
+use crate::{
+    events::ProtocolOverflow,
+    migration::Migrations,
+    order::OrderedMessages,
+    overflow::{Disconnections, OverflowPolicy},
+    protocol::{Payload, RtcPacketHeader},
+    sequence::Sequences,
+    server::RtcState,
+    socket::{MessageMeta, RoutedPackets},
+};
 use bevy::{prelude::*, utils::hashbrown::HashMap};
-use bevy_matchbox::prelude::PeerId;
+use bevy_matchbox::{matchbox_socket::Packet, prelude::PeerId};
 use std::collections::VecDeque;
 
-#[derive(Default, Debug, Resource)]
+#[derive(Debug, Resource)]
 pub struct IncomingMessages<M: Payload> {
+    pub decode: fn(&Packet) -> Option<M>,
+    /// Decoders for older versions of the payload
+    pub migrations: Migrations<M>,
     pub bound: usize,
-    pub messages: HashMap<PeerId, VecDeque<M>>,
+    /// Payloads received from each peer, with how each was received
+    pub messages: HashMap<PeerId, VecDeque<(MessageMeta, M)>>,
+    /// The newest sequence number delivered from each peer, if the protocol
+    /// is sequenced
+    pub(crate) sequences: Option<Sequences>,
+    /// Whether payloads are read in arrival order with other ordered
+    /// protocols, rather than buffered here
+    pub(crate) ordered: bool,
+    /// What is dropped when more than `bound` payloads are buffered from a
+    /// peer
+    pub(crate) overflow: OverflowPolicy,
 }
 
 impl<M: Payload> IncomingMessages<M> {
-    pub fn receive_payloads(mut incoming: ResMut<Self>, mut events: EventReader<SocketRecvEvent>) {
-        let bound = incoming.bound;
-        let packets: HashMap<PeerId, Vec<M>> = events.read().fold(
-            HashMap::new(),
-            |mut acc, &SocketRecvEvent((peer_id, ref packet))| {
-                let buf = acc.entry(peer_id).or_insert(vec![]);
-                if buf.len() >= bound {
-                    return acc;
-                }
-                if let Some(packet) = M::from_packet(packet) {
-                    buf.push(packet);
-                }
-                acc
-            },
-        );
+    pub(crate) fn new(decode: fn(&Packet) -> Option<M>, bound: usize) -> Self {
+        Self {
+            decode,
+            migrations: Migrations::default(),
+            bound,
+            messages: HashMap::new(),
+            sequences: None,
+            ordered: false,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+
+    /// Decode a packet, migrating it if it is an older version of the payload.
+    fn decode_packet(&self, packet: &Packet) -> Option<M> {
+        match RtcPacketHeader::read(packet) {
+            Some((header, _)) if header.version != M::version() => {
+                self.migrations.decode(header.version, packet)
+            }
+            _ => (self.decode)(packet),
+        }
+    }
+
+    pub(crate) fn receive_payloads(
+        mut incoming: ResMut<Self>,
+        mut routed: ResMut<RoutedPackets>,
+        mut ordered: ResMut<OrderedMessages>,
+        mut disconnections: ResMut<Disconnections>,
+        mut overflow_wtr: EventWriter<ProtocolOverflow>,
+        state: Res<RtcState>,
+    ) {
+        // Forget peers which left, with anything of theirs left unread
+        incoming
+            .messages
+            .retain(|peer, _| state.peers.contains(peer));
+        if let Some(sequences) = incoming.sequences.as_mut() {
+            sequences.retain(|peer| state.peers.contains(peer));
+        }
+        let packets: HashMap<PeerId, Vec<(u64, MessageMeta, M)>> =
+            routed
+                .drain(M::id())
+                .fold(HashMap::new(), |mut acc, (peer_id, received)| {
+                    if let (Some(sequences), Some(sequence)) =
+                        (incoming.sequences.as_mut(), received.sequence)
+                    {
+                        if !sequences.deliver(peer_id, sequence) {
+                            trace!(
+                                "Dropped stale {} packet {sequence} from {peer_id}",
+                                M::reflect_name()
+                            );
+                            return acc;
+                        }
+                    }
+                    match incoming.decode_packet(&received.packet) {
+                        Some(packet) => acc.entry(peer_id).or_default().push((
+                            received.arrival,
+                            received.meta,
+                            packet,
+                        )),
+                        None => warn!(
+                            "Failed to decode {} packet from {peer_id}",
+                            M::reflect_name()
+                        ),
+                    }
+                    acc
+                });
+        let (bound, overflow) = (incoming.bound, incoming.overflow);
         for (peer_id, payloads) in packets {
-            // Get or insert the VecDeque for the peer_id
-            let messages_for_peer = incoming
-                .messages
-                .entry(peer_id)
-                .or_insert_with(VecDeque::new);
-            for payload in payloads.into_iter() {
-                messages_for_peer.push_back(payload);
+            let dropped = if incoming.ordered {
+                let payloads = payloads
+                    .into_iter()
+                    .map(|(arrival, _, payload)| (arrival, payload))
+                    .collect();
+                ordered.push(peer_id, payloads, bound, overflow)
+            } else {
+                let messages_for_peer = incoming.messages.entry(peer_id).or_default();
+                let payloads = payloads
+                    .into_iter()
+                    .map(|(_, meta, payload)| (meta, payload));
+                overflow.buffer(messages_for_peer, payloads, bound)
+            };
+            if dropped == 0 {
+                continue;
             }
-            if messages_for_peer.len() > bound {
-                warn!(
-                    "The `{}` protocol is overflowing its bounded buffer ({bound}) and dropping packets! Is it being read?",
-                    M::reflect_name()
-                );
-                while messages_for_peer.len() > bound {
-                    messages_for_peer.pop_front();
-                }
+            debug!(
+                "The `{}` protocol dropped {dropped} packets from {peer_id} with its bounded buffer ({bound}) full",
+                M::reflect_name()
+            );
+            overflow_wtr.send(ProtocolOverflow {
+                peer: peer_id,
+                protocol: M::reflect_name(),
+                dropped,
+            });
+            if overflow == OverflowPolicy::IgnoreAndRequestDisconnect {
+                disconnections.request(peer_id, M::reflect_name());
             }
         }
     }
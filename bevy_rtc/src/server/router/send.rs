@@ -1,143 +1,224 @@
 use crate::{
+    ack::MessageId,
+    codec::CodecError,
+    compression::Compression,
+    events::{NetworkSendError, SendErrorKind},
     protocol::Payload,
-    socket::{RtcSocket, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX},
+    rate::SendRate,
+    sequence::Sequences,
+    socket::{SocketSender, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX},
 };
 use bevy::prelude::*;
-use bevy_matchbox::prelude::PeerId;
+use bevy_matchbox::{matchbox_socket::Packet, prelude::PeerId};
 
-#[derive(Default, Debug, Resource)]
+#[derive(Debug, Resource)]
 pub struct OutgoingMessages<M: Payload> {
-    pub reliable_to_all: Vec<M>,
-    pub unreliable_to_all: Vec<M>,
-    pub reliable_to_all_except: Vec<(PeerId, M)>,
-    pub unreliable_to_all_except: Vec<(PeerId, M)>,
-    pub reliable_to_peer: Vec<(PeerId, M)>,
-    pub unreliable_to_peer: Vec<(PeerId, M)>,
+    pub encode: fn(&M) -> Result<Packet, CodecError>,
+    /// How encoded payloads are compressed, if at all
+    pub compression: Option<Compression>,
+    /// The channel payloads are sent on unless another is given
+    pub channel: usize,
+    /// How urgently payloads are sent when a peer's bandwidth budget is
+    /// exceeded
+    pub priority: f32,
+    /// Payloads queued this frame, with the order they were queued in across
+    /// protocols and the channel to send each on
+    pub to_all: Vec<(u64, usize, M)>,
+    pub to_all_except: Vec<(u64, usize, PeerId, M)>,
+    pub to_peer: Vec<(u64, usize, PeerId, M)>,
+    /// Packets already encoded by the `try_*` methods of the network writer
+    pub encoded: Vec<(u64, usize, PeerId, Packet)>,
+    /// Payloads queued this frame by the `*_acked` methods of the network
+    /// writer, whose delivery is tracked
+    pub(crate) acked_to_all: Vec<(u64, MessageId, M)>,
+    pub(crate) acked_to_all_except: Vec<(u64, MessageId, PeerId, M)>,
+    pub(crate) acked_to_peer: Vec<(u64, MessageId, PeerId, M)>,
+    /// Payloads queued this frame by the `*_with` methods of the network
+    /// writer, with the peers the send rate allowed them to
+    pub(crate) rated: Vec<(u64, usize, Vec<PeerId>, M)>,
+    /// How often payloads of the `*_with` methods of the network writer are
+    /// sent to each peer, if limited
+    pub(crate) send_rate: Option<SendRate>,
+    /// The next sequence number for each peer, if the protocol is sequenced
+    pub(crate) sequences: Option<Sequences>,
 }
 
 impl<M: Payload> OutgoingMessages<M> {
+    pub(crate) fn new(encode: fn(&M) -> Result<Packet, CodecError>) -> Self {
+        Self {
+            encode,
+            compression: None,
+            channel: RELIABLE_CHANNEL_INDEX,
+            priority: 1.0,
+            to_all: vec![],
+            to_all_except: vec![],
+            to_peer: vec![],
+            encoded: vec![],
+            acked_to_all: vec![],
+            acked_to_all_except: vec![],
+            acked_to_peer: vec![],
+            rated: vec![],
+            send_rate: None,
+            sequences: None,
+        }
+    }
+
     /// Swaps the event buffers and clears the oldest event buffer. In general,
     /// this should be called once per frame/update.
     pub fn flush(&mut self) {
-        self.reliable_to_all.clear();
-        self.unreliable_to_all.clear();
-        self.reliable_to_all_except.clear();
-        self.unreliable_to_all_except.clear();
-        self.reliable_to_peer.clear();
-        self.unreliable_to_peer.clear();
+        self.to_all.clear();
+        self.to_all_except.clear();
+        self.to_peer.clear();
+        self.encoded.clear();
+        self.acked_to_all.clear();
+        self.acked_to_all_except.clear();
+        self.acked_to_peer.clear();
+        self.rated.clear();
     }
 
-    pub(crate) fn send_payloads(mut queue: ResMut<Self>, mut socket: ResMut<RtcSocket>) {
-        // Server is sending
-        for message in queue.reliable_to_all.iter() {
-            let peers: Vec<PeerId> = socket.connected_peers().collect();
-            peers.into_iter().for_each(|peer| {
-                if socket
-                    .channel_mut(RELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), peer)
-                    .is_err()
-                {
-                    error!("failed to send reliable packet to {peer}: {message:?}");
-                }
-            })
+    /// Encode a payload, compressing it if enabled for this protocol.
+    pub(crate) fn encode_packet(&self, message: &M) -> Result<Packet, CodecError> {
+        let packet = (self.encode)(message)?;
+        Ok(match self.compression {
+            Some(compression) => compression.compress(packet),
+            None => packet,
+        })
+    }
+
+    pub(crate) fn send_payloads(
+        mut queue: ResMut<Self>,
+        mut sender: SocketSender,
+        mut errors: EventWriter<NetworkSendError>,
+    ) {
+        let peers: Vec<PeerId> = sender.connected_peers().collect();
+        let priority = queue.priority;
+        let mut sequences = queue.sequences.take();
+        if let Some(sequences) = sequences.as_mut() {
+            sequences.retain(|peer| peers.contains(peer));
         }
-        if !queue.reliable_to_all.is_empty() {
-            trace!(
-                "sent {} [R;N] {} packets",
-                queue.reliable_to_all.len(),
-                M::reflect_name()
-            );
+        if let Some(send_rate) = queue.send_rate.as_mut() {
+            send_rate.retain(|peer| peers.contains(peer));
         }
-        for message in queue.unreliable_to_all.iter() {
-            let peers: Vec<PeerId> = socket.connected_peers().collect();
-            peers.into_iter().for_each(|peer| {
-                if socket
-                    .channel_mut(UNRELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), peer)
-                    .is_err()
-                {
-                    error!("failed to send unreliable packet to {peer}: {message:?}");
-                }
-            })
+        let mut send = |enqueued: u64,
+                        channel: usize,
+                        peer: PeerId,
+                        packet: Result<Packet, CodecError>,
+                        tracked: Option<MessageId>| {
+            let result = if peers.contains(&peer) {
+                let packet = match sequences.as_mut() {
+                    Some(sequences) => packet.map(|packet| sequences.stamp(peer, packet)),
+                    None => packet,
+                };
+                sender.send(
+                    M::reflect_name(),
+                    priority,
+                    enqueued,
+                    channel,
+                    peer,
+                    packet,
+                    tracked,
+                )
+            } else {
+                Err(SendErrorKind::UnknownPeer)
+            };
+            if let Err(kind) = result {
+                error!("failed to send packet to {peer} on channel {channel}: {kind}");
+                errors.send(NetworkSendError {
+                    peer,
+                    protocol: M::reflect_name(),
+                    kind,
+                });
+            }
+        };
+
+        // Server is sending. Broadcast payloads are encoded once and the
+        // packet is copied to each recipient.
+        for (enqueued, channel, message) in queue.to_all.iter() {
+            let packet = queue.encode_packet(message);
+            for peer in peers.iter() {
+                send(*enqueued, *channel, *peer, packet.clone(), None);
+            }
         }
-        if !queue.unreliable_to_all.is_empty() {
+        if !queue.to_all.is_empty() {
             trace!(
-                "sent {} [U;N] {} packets",
-                queue.unreliable_to_all.len(),
+                "sent {} [N] {} packets",
+                queue.to_all.len(),
                 M::reflect_name()
             );
         }
-        for (peer, message) in queue.reliable_to_all_except.iter() {
-            let peers: Vec<PeerId> = socket.connected_peers().filter(|p| p != peer).collect();
-            peers.into_iter().for_each(|peer| {
-                if socket
-                    .channel_mut(RELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), peer)
-                    .is_err()
-                {
-                    error!("failed to send reliable packet to {peer}: {message:?}");
-                }
-            });
+        for (enqueued, channel, except, message) in queue.to_all_except.iter() {
+            let packet = queue.encode_packet(message);
+            for peer in peers.iter().filter(|p| *p != except) {
+                send(*enqueued, *channel, *peer, packet.clone(), None);
+            }
         }
-        if !queue.reliable_to_all_except.is_empty() {
+        if !queue.to_all_except.is_empty() {
             trace!(
-                "sent {} [R;N-1] {} packets",
-                queue.reliable_to_all_except.len(),
+                "sent {} [N-1] {} packets",
+                queue.to_all_except.len(),
                 M::reflect_name()
             );
         }
-        for (peer, message) in queue.unreliable_to_all_except.iter() {
-            let peers: Vec<PeerId> = socket.connected_peers().filter(|p| p != peer).collect();
-            peers.into_iter().for_each(|peer| {
-                if socket
-                    .channel_mut(UNRELIABLE_CHANNEL_INDEX)
-                    .try_send(message.to_packet(), peer)
-                    .is_err()
-                {
-                    error!("failed to send unreliable packet to {peer}: {message:?}");
-                }
-            });
+        for (enqueued, channel, peer, message) in queue.to_peer.iter() {
+            send(
+                *enqueued,
+                *channel,
+                *peer,
+                queue.encode_packet(message),
+                None,
+            );
         }
-        if !queue.unreliable_to_all_except.is_empty() {
+        if !queue.to_peer.is_empty() {
             trace!(
-                "sent {} [U;N-1] {} packets",
-                queue.unreliable_to_all_except.len(),
+                "sent {} [1] {} packets",
+                queue.to_peer.len(),
                 M::reflect_name()
             );
         }
-        for (peer, message) in queue.reliable_to_peer.iter() {
-            if socket
-                .channel_mut(RELIABLE_CHANNEL_INDEX)
-                .try_send(message.to_packet(), *peer)
-                .is_err()
-            {
-                error!("failed to send reliable packet to {peer}: {message:?}");
-            }
+        for (enqueued, channel, peer, packet) in queue.encoded.iter() {
+            send(*enqueued, *channel, *peer, Ok(packet.clone()), None);
         }
-        if !queue.reliable_to_peer.is_empty() {
-            trace!(
-                "sent {} [R] {} packets",
-                queue.reliable_to_peer.len(),
-                M::reflect_name()
-            );
+        for (enqueued, id, message) in queue.acked_to_all.iter() {
+            let packet = queue.encode_packet(message);
+            for peer in peers.iter() {
+                send(
+                    *enqueued,
+                    UNRELIABLE_CHANNEL_INDEX,
+                    *peer,
+                    packet.clone(),
+                    Some(*id),
+                );
+            }
         }
-        for (peer, message) in queue.unreliable_to_peer.iter() {
-            if socket
-                .channel_mut(UNRELIABLE_CHANNEL_INDEX)
-                .try_send(message.to_packet(), *peer)
-                .is_err()
-            {
-                error!("failed to send unreliable packet to {peer}: {message:?}");
+        for (enqueued, id, except, message) in queue.acked_to_all_except.iter() {
+            let packet = queue.encode_packet(message);
+            for peer in peers.iter().filter(|p| *p != except) {
+                send(
+                    *enqueued,
+                    UNRELIABLE_CHANNEL_INDEX,
+                    *peer,
+                    packet.clone(),
+                    Some(*id),
+                );
             }
         }
-        if !queue.unreliable_to_peer.is_empty() {
-            trace!(
-                "sent {} [U] {} packets",
-                queue.unreliable_to_peer.len(),
-                M::reflect_name()
+        for (enqueued, id, peer, message) in queue.acked_to_peer.iter() {
+            send(
+                *enqueued,
+                UNRELIABLE_CHANNEL_INDEX,
+                *peer,
+                queue.encode_packet(message),
+                Some(*id),
             );
         }
+        for (enqueued, channel, recipients, message) in queue.rated.iter() {
+            let packet = queue.encode_packet(message);
+            for peer in recipients.iter() {
+                send(*enqueued, *channel, *peer, packet.clone(), None);
+            }
+        }
 
+        queue.sequences = sequences;
         queue.flush();
     }
 }
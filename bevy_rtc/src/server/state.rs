@@ -17,7 +17,7 @@ pub enum RtcServerStatus {
     Ready,
 }
 
-#[derive(Resource)]
+#[derive(Resource, Debug)]
 pub struct RtcState {
     /// The socket address bound
     pub addr: SocketAddr,
@@ -33,6 +33,9 @@ pub struct RtcState {
 
     /// A map of smoothed user latencies
     pub(crate) smoothed_latencies: HashMap<PeerId, Option<Duration>>,
+
+    /// A map of the bytes estimated buffered to users, by reliable channel
+    pub(crate) buffered: HashMap<(PeerId, usize), usize>,
 }
 
 impl RtcState {
@@ -43,6 +46,7 @@ impl RtcState {
             peers: HashSet::new(),
             latencies: HashMap::new(),
             smoothed_latencies: HashMap::new(),
+            buffered: HashMap::new(),
         }
     }
 
@@ -76,4 +80,10 @@ impl RtcState {
     pub fn get_smoothed_latency_for(&self, peer_id: PeerId) -> Option<Duration> {
         *self.smoothed_latencies.get(&peer_id)?
     }
+
+    /// Return the bytes estimated buffered to a peer on a reliable channel,
+    /// if they report what they receive
+    pub fn get_buffered_amount_for(&self, peer_id: PeerId, channel: usize) -> Option<usize> {
+        self.buffered.get(&(peer_id, channel)).copied()
+    }
 }
@@ -1,7 +1,21 @@
-use super::router::{IncomingMessages, OutgoingMessages};
-use crate::protocol::Payload;
+use super::{
+    router::{IncomingMessages, OutgoingMessages},
+    RtcState,
+};
+use crate::{
+    ack::MessageId,
+    backpressure::Buffered,
+    events::SendErrorKind,
+    order::next_enqueued,
+    protocol::Payload,
+    socket::{
+        check_channel, MessageMeta, RtcSocket, RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX,
+    },
+};
 use bevy::{ecs::system::SystemParam, prelude::*};
-use bevy_matchbox::prelude::PeerId;
+use bevy_matchbox::{matchbox_socket::Packet, prelude::PeerId};
+use instant::Instant;
+use std::collections::VecDeque;
 
 /// A [`SystemParam`] for reading payloads of a particular type.
 #[derive(SystemParam, Debug)]
@@ -17,110 +31,446 @@ impl<'w, M: Payload> NetworkReader<'w, M> {
 
     /// Returns the number of messages waiting in the buffer without draining them.
     pub fn len(&self) -> usize {
-        self.incoming.messages.len()
+        self.incoming.messages.values().map(VecDeque::len).sum()
     }
 
-    /// Returns the number of messages waiting in the buffer without draining them.
+    /// Returns true if no messages are waiting in the buffer.
     pub fn is_empty(&self) -> bool {
-        self.incoming.messages.is_empty()
+        self.incoming.messages.values().all(VecDeque::is_empty)
     }
 
-    /// Consumes all messages in the buffer and iterate on them.
-    pub fn read(&mut self) -> Vec<(PeerId, M)> {
+    /// Returns the number of messages waiting from a peer without draining
+    /// them.
+    pub fn len_for(&self, peer: PeerId) -> usize {
+        self.incoming
+            .messages
+            .get(&peer)
+            .map_or(0, |messages| messages.len())
+    }
+
+    /// Iterates over the peers with messages waiting in the buffer.
+    pub fn peers_with_messages(&self) -> impl Iterator<Item = PeerId> + '_ {
         self.incoming
             .messages
-            .drain()
-            .fold(vec![], |mut v, (peer, payloads)| {
-                v.extend(payloads.into_iter().map(|p| (peer, p)));
-                v
+            .iter()
+            .filter(|(_, messages)| !messages.is_empty())
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Iterates over the messages waiting in the buffer without draining them,
+    /// grouped by peer in the order each peer's were received.
+    pub fn peek(&self) -> impl Iterator<Item = (PeerId, &M)> + '_ {
+        self.incoming
+            .messages
+            .iter()
+            .flat_map(|(peer, messages)| messages.iter().map(move |(_, message)| (*peer, message)))
+    }
+
+    /// Consumes the messages waiting from a peer and iterate on them, in the
+    /// order they were received.
+    pub fn read_from(&mut self, peer: PeerId) -> impl Iterator<Item = M> + '_ {
+        self.incoming
+            .messages
+            .get_mut(&peer)
+            .into_iter()
+            .flat_map(|messages| messages.drain(..).map(|(_, message)| message))
+    }
+
+    /// Consumes all messages in the buffer and iterate on them, grouped by
+    /// peer. Unlike [`NetworkReader::read`], this does not allocate.
+    pub fn drain(&mut self) -> impl Iterator<Item = (PeerId, M)> + '_ {
+        self.incoming
+            .messages
+            .iter_mut()
+            .flat_map(|(peer, messages)| {
+                messages.drain(..).map(move |(_, message)| (*peer, message))
             })
     }
+
+    /// Consumes all messages in the buffer and collects them. This allocates
+    /// a `Vec` every call, see [`NetworkReader::drain`] to iterate without
+    /// allocating.
+    pub fn read(&mut self) -> Vec<(PeerId, M)> {
+        self.drain().collect()
+    }
+
+    /// Consumes all messages in the buffer and iterate on them, with the
+    /// peer, channel, time and frame each was received in, and its size on
+    /// the wire. Like [`NetworkReader::drain`], this does not allocate.
+    pub fn read_with_meta(&mut self) -> impl Iterator<Item = (MessageMeta, M)> + '_ {
+        self.incoming
+            .messages
+            .values_mut()
+            .flat_map(|messages| messages.drain(..))
+    }
 }
 
 #[derive(SystemParam, Debug)]
 pub struct NetworkWriter<'w, M: Payload> {
     pub(crate) outgoing: ResMut<'w, OutgoingMessages<M>>,
+    state: Res<'w, RtcState>,
+    socket: Option<Res<'w, RtcSocket>>,
+    buffered: Res<'w, Buffered>,
 }
 
 impl<'w, M: Payload> NetworkWriter<'w, M> {
     /// Send a payload to all connected peers with reliability.
     pub fn reliable_to_all(&mut self, message: M) {
-        self.outgoing.reliable_to_all.push(message);
+        self.outgoing
+            .to_all
+            .push((next_enqueued(), RELIABLE_CHANNEL_INDEX, message));
     }
 
     /// Send a payload to all connected peers with no expectation of delivery.
     pub fn unreliable_to_all(&mut self, message: M) {
-        self.outgoing.unreliable_to_all.push(message);
+        self.outgoing
+            .to_all
+            .push((next_enqueued(), UNRELIABLE_CHANNEL_INDEX, message));
     }
 
     /// Send a payload to a peer with reliability.
     pub fn reliable_to_peer(&mut self, peer_id: PeerId, message: M) {
-        self.outgoing.reliable_to_peer.push((peer_id, message));
+        self.outgoing
+            .to_peer
+            .push((next_enqueued(), RELIABLE_CHANNEL_INDEX, peer_id, message));
     }
 
     /// Send a payload to a peer with no expectation of delivery.
     pub fn unreliable_to_peer(&mut self, peer_id: PeerId, message: M) {
-        self.outgoing.unreliable_to_peer.push((peer_id, message));
+        self.outgoing
+            .to_peer
+            .push((next_enqueued(), UNRELIABLE_CHANNEL_INDEX, peer_id, message));
     }
 
     /// Send a payload to all connected peers except one with reliability.
     pub fn reliable_to_all_except(&mut self, peer_id: PeerId, message: M) {
-        self.outgoing
-            .reliable_to_all_except
-            .push((peer_id, message));
+        self.outgoing.to_all_except.push((
+            next_enqueued(),
+            RELIABLE_CHANNEL_INDEX,
+            peer_id,
+            message,
+        ));
     }
 
     /// Send a payload to all connected peers except one with no expectation of
     /// delivery.
     pub fn unreliable_to_all_except(&mut self, peer_id: PeerId, message: M) {
+        self.outgoing.to_all_except.push((
+            next_enqueued(),
+            UNRELIABLE_CHANNEL_INDEX,
+            peer_id,
+            message,
+        ));
+    }
+
+    /// Send a payload to all connected peers with no expectation of delivery,
+    /// but report whether it reached each of them with a
+    /// [`DeliveryEvent`](super::DeliveryEvent) carrying the returned ID.
+    pub fn unreliable_to_all_acked(&mut self, message: M) -> MessageId {
+        let id = MessageId::next();
+        self.outgoing
+            .acked_to_all
+            .push((next_enqueued(), id, message));
+        id
+    }
+
+    /// Send a payload to a peer with no expectation of delivery, but report
+    /// whether it arrived with a [`DeliveryEvent`](super::DeliveryEvent)
+    /// carrying the returned ID.
+    pub fn unreliable_to_peer_acked(&mut self, peer_id: PeerId, message: M) -> MessageId {
+        let id = MessageId::next();
+        self.outgoing
+            .acked_to_peer
+            .push((next_enqueued(), id, peer_id, message));
+        id
+    }
+
+    /// Send a payload to all connected peers except one with no expectation of
+    /// delivery, but report whether it reached each of them with a
+    /// [`DeliveryEvent`](super::DeliveryEvent) carrying the returned ID.
+    pub fn unreliable_to_all_except_acked(&mut self, peer_id: PeerId, message: M) -> MessageId {
+        let id = MessageId::next();
         self.outgoing
-            .unreliable_to_all_except
-            .push((peer_id, message));
+            .acked_to_all_except
+            .push((next_enqueued(), id, peer_id, message));
+        id
     }
 
     /// Send a payload to all connected peers with reliability. The payload is
-    /// created with lazy behavior, only when the send rate allows.
-    pub fn reliable_to_all_with(&mut self, message_fn: impl Fn() -> M) {
-        self.outgoing.reliable_to_all.push(message_fn());
+    /// created lazily, only if the send rate set with
+    /// [`AddProtocolExt::set_protocol_send_rate`](super::AddProtocolExt::set_protocol_send_rate)
+    /// allows sending to any of them this frame, and is sent to those it
+    /// allows. Peers congested on the channel, see
+    /// [`BackpressureConfig`](super::BackpressureConfig), are
+    /// skipped too. Otherwise `message_fn` is not called, and nothing is sent
+    /// or queued for later.
+    ///
+    /// Returns whether `message_fn` was called and the payload queued. A
+    /// queued unreliable payload may still be deferred, or dropped, by the
+    /// [`BandwidthConfig`](super::BandwidthConfig) budget.
+    pub fn reliable_to_all_with(&mut self, message_fn: impl FnOnce() -> M) -> bool {
+        let peers: Vec<PeerId> = self.state.peers.iter().copied().collect();
+        self.queue_rated(RELIABLE_CHANNEL_INDEX, peers, message_fn)
     }
 
     /// Send a payload to all connected peers with no expectation of delivery.
-    /// The payload is created with lazy behavior, only when the send rate
-    /// allows.
-    pub fn unreliable_to_all_with(&mut self, message_fn: impl Fn() -> M) {
-        self.outgoing.unreliable_to_all.push(message_fn());
+    /// The payload is created lazily, only for the peers the send rate allows
+    /// sending to this frame, like
+    /// [`NetworkWriter::reliable_to_all_with`]. Returns whether `message_fn`
+    /// was called and the payload queued.
+    pub fn unreliable_to_all_with(&mut self, message_fn: impl FnOnce() -> M) -> bool {
+        let peers: Vec<PeerId> = self.state.peers.iter().copied().collect();
+        self.queue_rated(UNRELIABLE_CHANNEL_INDEX, peers, message_fn)
     }
 
-    /// Send a payload to a peer with reliability. The payload is
-    /// created with lazy behavior, only when the send rate allows.
-    pub fn reliable_to_peer_with(&mut self, peer_id: PeerId, message_fn: impl Fn() -> M) {
-        self.outgoing.reliable_to_peer.push((peer_id, message_fn()));
+    /// Send a payload to a peer with reliability. The payload is created
+    /// lazily, only if the send rate allows sending to the peer this frame and
+    /// it is not congested, like [`NetworkWriter::reliable_to_all_with`].
+    /// Returns whether `message_fn` was called and the payload queued.
+    pub fn reliable_to_peer_with(
+        &mut self,
+        peer_id: PeerId,
+        message_fn: impl FnOnce() -> M,
+    ) -> bool {
+        self.queue_rated(RELIABLE_CHANNEL_INDEX, vec![peer_id], message_fn)
     }
 
-    /// Send a payload to a peer with no expectation of delivery.
-    /// The payload is created with lazy behavior, only when the send rate
-    /// allows.
-    pub fn unreliable_to_peer_with(&mut self, peer_id: PeerId, message_fn: impl Fn() -> M) {
-        self.outgoing
-            .unreliable_to_peer
-            .push((peer_id, message_fn()));
+    /// Send a payload to a peer with no expectation of delivery. The payload
+    /// is created lazily, only if the send rate allows sending to the peer
+    /// this frame, like [`NetworkWriter::reliable_to_all_with`]. Returns
+    /// whether `message_fn` was called and the payload queued.
+    pub fn unreliable_to_peer_with(
+        &mut self,
+        peer_id: PeerId,
+        message_fn: impl FnOnce() -> M,
+    ) -> bool {
+        self.queue_rated(UNRELIABLE_CHANNEL_INDEX, vec![peer_id], message_fn)
     }
 
     /// Send a payload to all connected peers except one with reliability. The
-    /// payload is created with lazy behavior, only when the send rate
-    /// allows.
-    pub fn reliable_to_all_except_with(&mut self, peer_id: PeerId, message_fn: impl Fn() -> M) {
+    /// payload is created lazily, only for the peers the send rate allows
+    /// sending to this frame, like [`NetworkWriter::reliable_to_all_with`].
+    /// Returns whether `message_fn` was called and the payload queued.
+    pub fn reliable_to_all_except_with(
+        &mut self,
+        peer_id: PeerId,
+        message_fn: impl FnOnce() -> M,
+    ) -> bool {
+        let peers: Vec<PeerId> = self
+            .state
+            .peers
+            .iter()
+            .copied()
+            .filter(|peer| *peer != peer_id)
+            .collect();
+        self.queue_rated(RELIABLE_CHANNEL_INDEX, peers, message_fn)
+    }
+
+    /// Send a payload to all connected peers except one with no expectation of
+    /// delivery. The payload is created lazily, only for the peers the send
+    /// rate allows sending to this frame, like
+    /// [`NetworkWriter::reliable_to_all_with`]. Returns whether `message_fn`
+    /// was called and the payload queued.
+    pub fn unreliable_to_all_except_with(
+        &mut self,
+        peer_id: PeerId,
+        message_fn: impl FnOnce() -> M,
+    ) -> bool {
+        let peers: Vec<PeerId> = self
+            .state
+            .peers
+            .iter()
+            .copied()
+            .filter(|peer| *peer != peer_id)
+            .collect();
+        self.queue_rated(UNRELIABLE_CHANNEL_INDEX, peers, message_fn)
+    }
+
+    /// Create and queue a payload for the peers which are not congested and
+    /// the send rate allows sending to now, if any. Returns whether it was
+    /// queued.
+    fn queue_rated(
+        &mut self,
+        channel: usize,
+        mut peers: Vec<PeerId>,
+        message_fn: impl FnOnce() -> M,
+    ) -> bool {
+        peers.retain(|peer| !self.buffered.is_congested(*peer, channel));
+        if let Some(send_rate) = self.outgoing.send_rate.as_mut() {
+            let now = Instant::now();
+            peers.retain(|peer| send_rate.try_take(*peer, now));
+        }
+        if peers.is_empty() {
+            trace!("Skipped a {} payload", M::reflect_name());
+            return false;
+        }
+        let message = message_fn();
+        self.outgoing
+            .rated
+            .push((next_enqueued(), channel, peers, message));
+        true
+    }
+
+    /// Send a payload to all connected peers on the channel set for its
+    /// protocol with
+    /// [`AddProtocolExt::set_protocol_channel`](super::AddProtocolExt::set_protocol_channel).
+    pub fn send_to_all(&mut self, message: M) {
+        let channel = self.outgoing.channel;
+        self.outgoing
+            .to_all
+            .push((next_enqueued(), channel, message));
+    }
+
+    /// Send a payload to a peer on the channel set for its protocol.
+    pub fn send_to_peer(&mut self, peer_id: PeerId, message: M) {
+        let channel = self.outgoing.channel;
         self.outgoing
-            .reliable_to_all_except
-            .push((peer_id, message_fn()));
+            .to_peer
+            .push((next_enqueued(), channel, peer_id, message));
+    }
+
+    /// Send a payload to all connected peers except one on the channel set for
+    /// its protocol.
+    pub fn send_to_all_except(&mut self, peer_id: PeerId, message: M) {
+        let channel = self.outgoing.channel;
+        self.outgoing
+            .to_all_except
+            .push((next_enqueued(), channel, peer_id, message));
+    }
+
+    /// Send a payload to all connected peers on a channel of
+    /// [`RtcChannels`](super::RtcChannels).
+    pub fn send_to_all_on(&mut self, channel: usize, message: M) {
+        self.outgoing
+            .to_all
+            .push((next_enqueued(), channel, message));
+    }
+
+    /// Send a payload to a peer on a channel of
+    /// [`RtcChannels`](super::RtcChannels).
+    pub fn send_to_peer_on(&mut self, channel: usize, peer_id: PeerId, message: M) {
+        self.outgoing
+            .to_peer
+            .push((next_enqueued(), channel, peer_id, message));
+    }
+
+    /// Send a payload to all connected peers except one on a channel of
+    /// [`RtcChannels`](super::RtcChannels).
+    pub fn send_to_all_except_on(&mut self, channel: usize, peer_id: PeerId, message: M) {
+        self.outgoing
+            .to_all_except
+            .push((next_enqueued(), channel, peer_id, message));
+    }
+
+    /// Send a payload to all connected peers with reliability, encoding it
+    /// immediately. Returns an error if it cannot be encoded or the channel is
+    /// closed.
+    pub fn try_reliable_to_all(&mut self, message: M) -> Result<(), SendErrorKind> {
+        self.try_send_to(RELIABLE_CHANNEL_INDEX, None, &message)
+    }
+
+    /// Send a payload to all connected peers with no expectation of delivery,
+    /// encoding it immediately. Returns an error if it cannot be encoded or the
+    /// channel is closed.
+    pub fn try_unreliable_to_all(&mut self, message: M) -> Result<(), SendErrorKind> {
+        self.try_send_to(UNRELIABLE_CHANNEL_INDEX, None, &message)
+    }
+
+    /// Send a payload to a peer with reliability, encoding it immediately.
+    /// Returns an error if it cannot be encoded, the channel is closed or
+    /// congested, or the peer is not connected.
+    pub fn try_reliable_to_peer(
+        &mut self,
+        peer_id: PeerId,
+        message: M,
+    ) -> Result<(), SendErrorKind> {
+        self.try_send_to(RELIABLE_CHANNEL_INDEX, Some(peer_id), &message)
+    }
+
+    /// Send a payload to a peer with no expectation of delivery, encoding it
+    /// immediately. Returns an error if it cannot be encoded, the channel is
+    /// closed or congested, or the peer is not connected.
+    pub fn try_unreliable_to_peer(
+        &mut self,
+        peer_id: PeerId,
+        message: M,
+    ) -> Result<(), SendErrorKind> {
+        self.try_send_to(UNRELIABLE_CHANNEL_INDEX, Some(peer_id), &message)
+    }
+
+    /// Send a payload to all connected peers except one with reliability,
+    /// encoding it immediately. Returns an error if it cannot be encoded or
+    /// the channel is closed.
+    pub fn try_reliable_to_all_except(
+        &mut self,
+        peer_id: PeerId,
+        message: M,
+    ) -> Result<(), SendErrorKind> {
+        self.try_send_to_all_except(RELIABLE_CHANNEL_INDEX, peer_id, &message)
     }
 
     /// Send a payload to all connected peers except one with no expectation of
-    /// delivery. The payload is created with lazy behavior, only when the
-    /// send rate allows.
-    pub fn unreliable_to_all_except_with(&mut self, peer_id: PeerId, message_fn: impl Fn() -> M) {
+    /// delivery, encoding it immediately. Returns an error if it cannot be
+    /// encoded or the channel is closed.
+    pub fn try_unreliable_to_all_except(
+        &mut self,
+        peer_id: PeerId,
+        message: M,
+    ) -> Result<(), SendErrorKind> {
+        self.try_send_to_all_except(UNRELIABLE_CHANNEL_INDEX, peer_id, &message)
+    }
+
+    /// Encode a payload and queue it for one peer, or all peers if `None`.
+    fn try_send_to(
+        &mut self,
+        channel: usize,
+        peer_id: Option<PeerId>,
+        message: &M,
+    ) -> Result<(), SendErrorKind> {
+        if peer_id.is_some_and(|peer_id| !self.state.peers.contains(&peer_id)) {
+            return Err(SendErrorKind::UnknownPeer);
+        }
+        if peer_id.is_some_and(|peer_id| self.buffered.is_congested(peer_id, channel)) {
+            return Err(SendErrorKind::Congested);
+        }
+        let packet = self.try_encode(channel, message)?;
+        let peers: Vec<PeerId> = match peer_id {
+            Some(peer_id) => vec![peer_id],
+            None => self.state.peers().collect(),
+        };
+        self.queue_encoded(channel, peers, packet);
+        Ok(())
+    }
+
+    /// Encode a payload and queue it for all peers except one.
+    fn try_send_to_all_except(
+        &mut self,
+        channel: usize,
+        peer_id: PeerId,
+        message: &M,
+    ) -> Result<(), SendErrorKind> {
+        let packet = self.try_encode(channel, message)?;
+        let peers: Vec<PeerId> = self.state.peers().filter(|p| *p != peer_id).collect();
+        self.queue_encoded(channel, peers, packet);
+        Ok(())
+    }
+
+    fn try_encode(&self, channel: usize, message: &M) -> Result<Packet, SendErrorKind> {
+        match self.socket.as_ref() {
+            Some(socket) => check_channel(socket, channel)?,
+            None => return Err(SendErrorKind::ChannelClosed),
+        }
         self.outgoing
-            .unreliable_to_all_except
-            .push((peer_id, message_fn()));
+            .encode_packet(message)
+            .map_err(SendErrorKind::Serialization)
+    }
+
+    fn queue_encoded(&mut self, channel: usize, peers: Vec<PeerId>, packet: Packet) {
+        let enqueued = next_enqueued();
+        self.outgoing.encoded.extend(
+            peers
+                .into_iter()
+                .map(|peer| (enqueued, channel, peer, packet.clone())),
+        );
     }
 }
@@ -1,6 +1,12 @@
 use super::{events::RtcServerEvent, NetworkReader, NetworkWriter, RtcServerStatus, RtcState};
 use crate::{
+    backpressure::{BackpressureConfig, Buffered, ReceiptPayload},
+    channel::RtcChannels,
+    events::CongestionEvent,
     latency::{LatencyTracer, LatencyTracerPayload},
+    manifest::ProtocolManifest,
+    overflow::{DisconnectPayload, Disconnections},
+    registry::ProtocolRegistry,
     socket::RtcSocket,
 };
 use bevy::prelude::*;
@@ -9,8 +15,7 @@ use bevy_matchbox::{
         topologies::client_server::{ClientServer, ClientServerState},
         SignalingServerBuilder,
     },
-    matchbox_socket::{PeerState, WebRtcSocket},
-    prelude::ChannelConfig,
+    matchbox_socket::{PeerId, PeerState, WebRtcSocket},
     OpenSocketExt, StartServerExt,
 };
 use instant::Duration;
@@ -74,17 +79,14 @@ pub fn init_signaling_server(mut commands: Commands, rtc_state: Res<RtcState>) {
 }
 
 /// Initialize the server socket
-pub fn init_server_socket(mut commands: Commands, state: Res<RtcState>) {
+pub fn init_server_socket(
+    mut commands: Commands,
+    state: Res<RtcState>,
+    channels: Res<RtcChannels>,
+) {
     // Create matchbox socket
     let room_url = format!("ws://{}", state.addr);
-    let socker_builder = WebRtcSocket::builder(room_url)
-        // Match UNRELIABLE_CHANNEL_INDEX
-        .add_channel(ChannelConfig {
-            ordered: true,
-            max_retransmits: Some(0),
-        })
-        // Match RELIABLE_CHANNEL_INDEX
-        .add_channel(ChannelConfig::reliable());
+    let socker_builder = channels.add_to(WebRtcSocket::builder(room_url));
     commands.open_socket(socker_builder);
 }
 
@@ -128,6 +130,56 @@ pub fn server_event_writer(
     }
 }
 
+/// Send joining peers the protocols registered by this server
+pub(crate) fn send_manifests(
+    registry: Res<ProtocolRegistry>,
+    mut event_rdr: EventReader<RtcServerEvent>,
+    mut writer: NetworkWriter<ProtocolManifest>,
+) {
+    for event in event_rdr.read() {
+        if let RtcServerEvent::ClientJoined(peer) = event {
+            writer.reliable_to_peer(*peer, ProtocolManifest::new(&registry));
+        }
+    }
+}
+
+/// Report peers whose protocols do not match this server's
+pub(crate) fn read_manifests(
+    registry: Res<ProtocolRegistry>,
+    mut reader: NetworkReader<ProtocolManifest>,
+    mut event_wtr: EventWriter<RtcServerEvent>,
+) {
+    let local = ProtocolManifest::new(&registry);
+    for (peer, manifest) in reader.read() {
+        let mismatches = local.mismatches(&manifest);
+        if mismatches.is_empty() {
+            debug!("protocols of {peer} match");
+            continue;
+        }
+        warn!("protocol mismatch with {peer}: {mismatches:?}");
+        event_wtr.send(RtcServerEvent::ProtocolMismatch { peer, mismatches });
+    }
+}
+
+/// Ask peers which overflowed a protocol with the `IgnoreAndRequestDisconnect`
+/// policy to disconnect, since the socket cannot disconnect them
+pub(crate) fn send_disconnects(
+    mut disconnections: ResMut<Disconnections>,
+    mut writer: NetworkWriter<DisconnectPayload>,
+    mut event_wtr: EventWriter<RtcServerEvent>,
+) {
+    for (peer, protocol) in disconnections.take_pending() {
+        warn!("ignoring {peer} and asking it to disconnect, since it overflowed `{protocol}`");
+        event_wtr.send(RtcServerEvent::PeerOverflowed { peer, protocol });
+        writer.reliable_to_peer(
+            peer,
+            DisconnectPayload {
+                reason: format!("Overflowed the `{protocol}` protocol"),
+            },
+        );
+    }
+}
+
 pub fn send_latency_tracers(state: Res<RtcState>, mut writer: NetworkWriter<LatencyTracerPayload>) {
     let peer_id = state.id.expect("expected peer id");
     writer.unreliable_to_all(LatencyTracerPayload::new(peer_id));
@@ -160,6 +212,53 @@ pub fn read_latency_tracers(
     }
 }
 
+/// Report the bytes received from each peer which asked, so they can estimate
+/// what they have buffered, or ask them to report if a high-water mark is set
+pub(crate) fn send_receipts(
+    state: Res<RtcState>,
+    channels: Res<RtcChannels>,
+    config: Res<BackpressureConfig>,
+    buffered: Res<Buffered>,
+    mut writer: NetworkWriter<ReceiptPayload>,
+) {
+    for peer in state.peers() {
+        if buffered.wants_receipts(peer, &config) {
+            writer.unreliable_to_peer(peer, buffered.receipt(peer, &channels));
+        }
+    }
+}
+
+/// Estimate the bytes buffered to each peer from their reports
+pub(crate) fn read_receipts(
+    mut state: ResMut<RtcState>,
+    channels: Res<RtcChannels>,
+    config: Res<BackpressureConfig>,
+    mut buffered: ResMut<Buffered>,
+    mut reader: NetworkReader<ReceiptPayload>,
+    mut event_wtr: EventWriter<CongestionEvent>,
+) {
+    for (peer, receipt) in reader.read() {
+        buffered.acknowledge(peer, receipt);
+    }
+    let peers: Vec<PeerId> = state.peers().collect();
+    let (estimates, events) = buffered.update(&peers, &channels, &config);
+    state.buffered = estimates.into_iter().collect();
+    for event in events {
+        warn!(
+            "{} {} on channel {} with {} bytes buffered",
+            event.peer,
+            if event.congested {
+                "congested"
+            } else {
+                "caught up"
+            },
+            event.channel,
+            event.buffered
+        );
+        event_wtr.send(event);
+    }
+}
+
 pub fn calculate_latency(
     time: Res<Time>,
     mut state: ResMut<RtcState>,
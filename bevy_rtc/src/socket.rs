@@ -1,9 +1,37 @@
-use crate::events::SocketRecvEvent;
-use bevy::prelude::*;
-use bevy_matchbox::{prelude::MultipleChannels, MatchboxSocket};
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::{
+    ack::{self, AckConfig, Acks, MessageId},
+    backpressure::Buffered,
+    bandwidth::{Bandwidth, BandwidthConfig, QueuedMessage},
+    batch::BatchingConfig,
+    channel::RtcChannels,
+    codec::CodecError,
+    events::{DeliveryEvent, DeliveryStatus, NetworkSendError, UnknownPayloadEvent},
+    fragment::Fragmenter,
+    order::OrderedMessages,
+    overflow::Disconnections,
+    registry::ProtocolRegistry,
+};
+use crate::{
+    batch,
+    compression::{self, CompressionConfig},
+    events::SendErrorKind,
+    fragment::{FragmentationConfig, Reassembler},
+    protocol::RtcPacketHeader,
+    sequence,
+};
+#[cfg(any(feature = "client", feature = "server"))]
+use bevy::{core::FrameCount, ecs::system::SystemParam, prelude::*, utils::HashMap};
+#[cfg(any(feature = "client", feature = "server"))]
+use bevy_matchbox::MatchboxSocket;
+use instant::Instant;
+use matchbox_socket::{ChannelError, MultipleChannels, Packet, PeerId, WebRtcSocket};
+use tracing::warn;
 
+#[cfg(any(feature = "client", feature = "server"))]
 /// A type alias to the underlying matchbox socket.
 pub type RtcSocket = MatchboxSocket<RtcSocketPlurality>;
+#[cfg(any(feature = "client", feature = "server"))]
 /// A type alias to the underlying matchbox socket plurality.
 pub type RtcSocketPlurality = MultipleChannels;
 
@@ -12,18 +40,550 @@ pub const UNRELIABLE_CHANNEL_INDEX: usize = 0;
 /// The index of the reliable channel in the [`WebRtcSocket`].
 pub const RELIABLE_CHANNEL_INDEX: usize = 1;
 
+/// Describes how a payload was received, see `NetworkReader::read_with_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageMeta {
+    /// The peer which sent the payload
+    pub peer: PeerId,
+    /// The index of the channel the payload arrived on
+    pub channel: usize,
+    /// Whether that channel is reliable
+    pub reliable: bool,
+    /// When the payload was read from the socket
+    pub received_at: Instant,
+    /// The bytes the payload took on the wire, including its header, before
+    /// decompression and after reassembly
+    pub size: usize,
+    /// The [`FrameCount`] of the frame the payload was read from the socket
+    /// in, or 0 if the app does not count frames
+    pub frame: u32,
+}
+
+/// A whole packet received from a peer, ready to be decoded.
+#[derive(Debug)]
+pub(crate) struct ReceivedPacket {
+    pub msg_id: u16,
+    pub packet: Packet,
+    /// The sequence number of a packet from a sequenced protocol
+    pub sequence: Option<u16>,
+    /// The order the packet arrived in across protocols, assigned once it is
+    /// routed
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub arrival: u64,
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub meta: MessageMeta,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Packets received this frame, grouped by payload ID.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct RoutedPackets {
+    packets: HashMap<u16, Vec<(PeerId, ReceivedPacket)>>,
+    next_arrival: u64,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl RoutedPackets {
+    /// Route a packet to its payload ID, numbering it in order of arrival.
+    fn route(&mut self, peer: PeerId, mut packet: ReceivedPacket) {
+        packet.arrival = self.next_arrival;
+        self.next_arrival += 1;
+        self.packets
+            .entry(packet.msg_id)
+            .or_default()
+            .push((peer, packet));
+    }
+
+    /// Take all packets received for a payload ID.
+    pub(crate) fn drain(
+        &mut self,
+        msg_id: u16,
+    ) -> impl Iterator<Item = (PeerId, ReceivedPacket)> + '_ {
+        self.packets
+            .get_mut(&msg_id)
+            .into_iter()
+            .flat_map(|packets| packets.drain(..))
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Payloads queued this frame, by recipient and channel.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct Outbox {
+    packets: HashMap<(PeerId, usize), Vec<QueuedMessage>>,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+#[allow(clippy::too_many_arguments)]
 pub fn common_socket_reader(
     mut socket: ResMut<RtcSocket>,
-    mut event_wtr: EventWriter<SocketRecvEvent>,
+    channels: Res<RtcChannels>,
+    registry: Res<ProtocolRegistry>,
+    config: Res<FragmentationConfig>,
+    compression: Res<CompressionConfig>,
+    mut reassembler: ResMut<Reassembler>,
+    mut routed: ResMut<RoutedPackets>,
+    ack_config: Res<AckConfig>,
+    mut acks: ResMut<Acks>,
+    mut buffered: ResMut<Buffered>,
+    mut disconnections: ResMut<Disconnections>,
+    mut ordered: ResMut<OrderedMessages>,
+    frame: Option<Res<FrameCount>>,
+    mut unknown_wtr: EventWriter<UnknownPayloadEvent>,
+    mut delivery_wtr: EventWriter<DeliveryEvent>,
 ) {
-    let messages = socket
-        .channel_mut(RELIABLE_CHANNEL_INDEX)
-        .receive()
-        .into_iter()
-        .chain(socket.channel_mut(UNRELIABLE_CHANNEL_INDEX).receive())
-        .map(SocketRecvEvent)
-        .collect::<Vec<_>>();
-    trace!("Received {} total messages", messages.len());
-
-    event_wtr.send_batch(messages);
+    let frame = frame.map_or(0, |frame| frame.0);
+    // Drop anything left over from the last frame, e.g. if a reader did not run
+    routed.packets.values_mut().for_each(Vec::clear);
+
+    let mut total = 0;
+    let mut unknown: HashMap<(PeerId, Option<u16>), usize> = HashMap::new();
+    let mut deliveries = vec![];
+    for (channel, reliable, (peer, message)) in channels.receive(&mut socket) {
+        buffered.record_received(peer, channel, message.len());
+        if disconnections.is_ignored(peer) {
+            continue;
+        }
+        let Some(message) = acks.open(peer, message, &mut deliveries) else {
+            continue;
+        };
+        let meta = MessageMeta {
+            peer,
+            channel,
+            reliable,
+            received_at: Instant::now(),
+            size: message.len(),
+            frame,
+        };
+        let packets = unpack_message(
+            meta,
+            message,
+            |msg_id| registry.is_readable(msg_id),
+            &mut reassembler,
+            &config,
+            &compression,
+        );
+        for packet in packets {
+            total += 1;
+            match packet {
+                Ok(packet) => routed.route(peer, packet),
+                Err(msg_id) => *unknown.entry((peer, msg_id)).or_default() += 1,
+            }
+        }
+    }
+    trace!("Received {total} total messages");
+
+    let connected: Vec<PeerId> = socket.connected_peers().collect();
+    reassembler.prune(&connected, &config);
+    disconnections.prune(&connected);
+    ordered.prune(&connected);
+    deliveries.extend(acks.expire(&connected, &ack_config));
+    delivery_wtr.send_batch(deliveries);
+
+    for ((peer, msg_id), count) in unknown {
+        debug!("Dropped {count} unroutable packets from {peer} (payload ID {msg_id:?})");
+        unknown_wtr.send(UnknownPayloadEvent {
+            peer,
+            msg_id,
+            count,
+        });
+    }
+}
+
+/// Check that a channel exists and is open to send on.
+pub(crate) fn check_channel(
+    socket: &WebRtcSocket<MultipleChannels>,
+    channel: usize,
+) -> Result<(), SendErrorKind> {
+    match socket.get_channel(channel) {
+        Ok(channel) if !channel.is_closed() => Ok(()),
+        Err(ChannelError::NotFound) => Err(SendErrorKind::UnknownChannel),
+        _ => Err(SendErrorKind::ChannelClosed),
+    }
+}
+
+/// Unpack a message received from a peer into the whole packets it carries,
+/// unbatching, reassembling, unsequencing and decompressing them. Packets whose payload ID is
+/// not readable, or whose header is unreadable, are returned as errors with
+/// their payload ID. Each packet is described by `meta`, with its own size.
+pub(crate) fn unpack_message(
+    meta: MessageMeta,
+    message: Packet,
+    is_readable: impl Fn(u16) -> bool,
+    reassembler: &mut Reassembler,
+    config: &FragmentationConfig,
+    compression: &CompressionConfig,
+) -> Vec<Result<ReceivedPacket, Option<u16>>> {
+    let (peer, reliable) = (meta.peer, meta.reliable);
+    let packets = match RtcPacketHeader::read(&message) {
+        Some((header, data)) if header.has_flag(RtcPacketHeader::FLAG_BATCH) => batch::unpack(data)
+            .unwrap_or_else(|| {
+                warn!("Received a malformed batch from {peer}");
+                vec![]
+            }),
+        _ => vec![message],
+    };
+    let mut unpacked = Vec::with_capacity(packets.len());
+    for packet in packets {
+        let header = match RtcPacketHeader::read(&packet) {
+            Some((header, _))
+                if is_readable(header.msg_id) && !header.has_flag(RtcPacketHeader::FLAG_BATCH) =>
+            {
+                header
+            }
+            header => {
+                unpacked.push(Err(header.map(|(header, _)| header.msg_id)));
+                continue;
+            }
+        };
+        let packet = if header.has_flag(RtcPacketHeader::FLAG_FRAGMENT) {
+            match reassembler.insert(peer, reliable, &packet[RtcPacketHeader::LEN..], config) {
+                Some(packet) => packet,
+                None => continue,
+            }
+        } else {
+            packet
+        };
+        #[cfg(any(feature = "client", feature = "server"))]
+        let size = packet.len();
+        let (sequence, packet) = match RtcPacketHeader::read(&packet) {
+            Some((header, data)) if header.has_flag(RtcPacketHeader::FLAG_SEQUENCED) => {
+                match sequence::strip(header, data) {
+                    Some((sequence, packet)) => (Some(sequence), packet),
+                    None => {
+                        warn!("Discarding a malformed sequenced packet from {peer}");
+                        continue;
+                    }
+                }
+            }
+            _ => (None, packet),
+        };
+        let packet = match RtcPacketHeader::read(&packet) {
+            Some((header, data)) if header.has_flag(RtcPacketHeader::FLAG_COMPRESSED) => {
+                match compression::decompress(header, data, compression) {
+                    Some(packet) => packet,
+                    None => {
+                        warn!(
+                            "Discarding a compressed packet from {peer} which is malformed or exceeds {} bytes",
+                            compression.max_decompressed_size
+                        );
+                        continue;
+                    }
+                }
+            }
+            _ => packet,
+        };
+        unpacked.push(Ok(ReceivedPacket {
+            msg_id: header.msg_id,
+            packet,
+            sequence,
+            #[cfg(any(feature = "client", feature = "server"))]
+            arrival: 0,
+            #[cfg(any(feature = "client", feature = "server"))]
+            meta: MessageMeta { size, ..meta },
+        }));
+    }
+    unpacked
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Sends the packets queued this frame in the order they were queued, within
+/// each peer's bandwidth budget, batching them if enabled, and acknowledging
+/// datagrams on the unreliable channel to peers which track deliveries.
+#[allow(clippy::too_many_arguments)]
+pub fn common_socket_writer(
+    mut socket: ResMut<RtcSocket>,
+    mut outbox: ResMut<Outbox>,
+    channels: Res<RtcChannels>,
+    batching: Res<BatchingConfig>,
+    fragmentation: Res<FragmentationConfig>,
+    config: Res<BandwidthConfig>,
+    mut bandwidth: ResMut<Bandwidth>,
+    mut acks: ResMut<Acks>,
+    mut buffered: ResMut<Buffered>,
+    mut errors: EventWriter<NetworkSendError>,
+    mut deliveries: EventWriter<DeliveryEvent>,
+) {
+    let mut queued: HashMap<PeerId, Vec<(usize, QueuedMessage)>> = HashMap::new();
+    for ((peer, channel), messages) in outbox.packets.drain() {
+        queued
+            .entry(peer)
+            .or_default()
+            .extend(messages.into_iter().map(|message| (channel, message)));
+    }
+    // Each protocol queues its payloads separately, so restore the order they
+    // were queued in across protocols
+    for messages in queued.values_mut() {
+        messages.sort_by_key(|(_, message)| message.enqueued);
+    }
+    let connected: Vec<PeerId> = socket.connected_peers().collect();
+    bandwidth.prune(&connected);
+    let deferred: Vec<PeerId> = bandwidth.deferred_peers().collect();
+    for peer in deferred {
+        queued.entry(peer).or_default();
+    }
+
+    type Tag = (&'static str, Option<MessageId>);
+    let mut packets: HashMap<(PeerId, usize), Vec<(Tag, Packet)>> = HashMap::new();
+    for (peer, messages) in queued {
+        let (send, dropped) = bandwidth.schedule(peer, messages, &channels, &config);
+        if !dropped.is_empty() {
+            debug!(
+                "Dropped {} payloads to {peer} which exceeded its bandwidth budget",
+                dropped.len()
+            );
+            deliveries.send_batch(dropped.iter().filter_map(|message| {
+                message.tracked.map(|id| DeliveryEvent {
+                    peer,
+                    protocol: message.protocol,
+                    id,
+                    status: DeliveryStatus::Lost,
+                })
+            }));
+            errors.send_batch(dropped.into_iter().map(|message| NetworkSendError {
+                peer,
+                protocol: message.protocol,
+                kind: SendErrorKind::OverBudget,
+            }));
+        }
+        for (channel, message) in send {
+            let tag = (message.protocol, message.tracked);
+            packets
+                .entry((peer, channel))
+                .or_default()
+                .extend(message.packets.into_iter().map(|packet| (tag, packet)));
+        }
+    }
+
+    for ((peer, channel), packets) in packets {
+        let acked = channel == UNRELIABLE_CHANNEL_INDEX
+            && (acks.is_active(peer) || packets.iter().any(|((_, id), _)| id.is_some()));
+        let packets = pack(packets, acked, &batching, fragmentation.max_packet_size);
+        trace!(
+            "sent {} messages to {peer} on channel {channel}",
+            packets.len()
+        );
+        for (tags, packet) in packets {
+            let (sequence, packet) = if acked {
+                let tracked = tags
+                    .iter()
+                    .filter_map(|(protocol, id)| id.map(|id| (*protocol, id)))
+                    .collect();
+                let (sequence, packet) = acks.wrap(peer, tracked, &packet);
+                (Some(sequence), packet)
+            } else {
+                (None, packet)
+            };
+            let len = packet.len();
+            if socket.channel_mut(channel).try_send(packet, peer).is_ok() {
+                buffered.record_sent(peer, channel, len);
+            } else {
+                error!("failed to send packet to {peer} on channel {channel}: channel closed");
+                if let Some(sequence) = sequence {
+                    deliveries.send_batch(acks.lose(peer, sequence));
+                }
+                errors.send_batch(tags.into_iter().map(|(protocol, _)| NetworkSendError {
+                    peer,
+                    protocol,
+                    kind: SendErrorKind::ChannelClosed,
+                }));
+            }
+        }
+    }
+
+    // Acknowledge payloads from peers which were sent nothing to carry the
+    // acknowledgements
+    let owed: Vec<PeerId> = acks.owed().collect();
+    for peer in owed {
+        let (_, packet) = acks.wrap(peer, vec![], &[]);
+        let len = packet.len();
+        if socket
+            .channel_mut(UNRELIABLE_CHANNEL_INDEX)
+            .try_send(packet, peer)
+            .is_ok()
+        {
+            buffered.record_sent(peer, UNRELIABLE_CHANNEL_INDEX, len);
+        } else {
+            debug!("failed to send acknowledgements to {peer}: channel closed");
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Batch the packets sent to a peer on a channel if enabled, leaving room for
+/// the acknowledgements of acked datagrams so none exceeds `max_packet_size`.
+fn pack<T>(
+    packets: Vec<(T, Packet)>,
+    acked: bool,
+    batching: &BatchingConfig,
+    max_packet_size: usize,
+) -> Vec<(Vec<T>, Packet)> {
+    if !batching.enabled {
+        return packets
+            .into_iter()
+            .map(|(tag, packet)| (vec![tag], packet))
+            .collect();
+    }
+    let max_batch_size = batching.max_batch_size.min(max_packet_size);
+    let max_batch_size = if acked {
+        max_batch_size.saturating_sub(ack::ENVELOPE_LEN)
+    } else {
+        max_batch_size
+    };
+    batch::pack(packets, max_batch_size)
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// The largest packet queued on a channel may be. Packets on the unreliable
+/// channel leave room for the acknowledgements which may frame them.
+fn max_queued_size(channel: usize, max_packet_size: usize) -> usize {
+    if channel == UNRELIABLE_CHANNEL_INDEX {
+        max_packet_size.saturating_sub(ack::ENVELOPE_LEN)
+    } else {
+        max_packet_size
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Queues packets to be sent by [`common_socket_writer`], splitting those too
+/// large for a single data channel message into fragments.
+#[derive(SystemParam)]
+pub(crate) struct SocketSender<'w> {
+    socket: Res<'w, RtcSocket>,
+    outbox: ResMut<'w, Outbox>,
+    fragmenter: ResMut<'w, Fragmenter>,
+    config: Res<'w, FragmentationConfig>,
+    buffered: Res<'w, Buffered>,
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl<'w> SocketSender<'w> {
+    /// Return the currently connected peers.
+    #[cfg(feature = "server")]
+    pub(crate) fn connected_peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.socket.connected_peers()
+    }
+
+    /// Queue a packet on a channel, if it was encoded successfully. Tracked
+    /// packets are reported by a [`DeliveryEvent`] once acknowledged or lost.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn send(
+        &mut self,
+        protocol: &'static str,
+        priority: f32,
+        enqueued: u64,
+        channel: usize,
+        peer: PeerId,
+        packet: Result<Packet, CodecError>,
+        tracked: Option<MessageId>,
+    ) -> Result<(), SendErrorKind> {
+        let packet = packet.map_err(SendErrorKind::Serialization)?;
+        check_channel(&self.socket, channel)?;
+        if self.buffered.is_congested(peer, channel) {
+            return Err(SendErrorKind::Congested);
+        }
+        let fragments = self
+            .fragmenter
+            .split(
+                packet,
+                max_queued_size(channel, self.config.max_packet_size),
+            )
+            .ok_or(SendErrorKind::TooLarge)?;
+        self.outbox
+            .packets
+            .entry((peer, channel))
+            .or_default()
+            .push(QueuedMessage {
+                protocol,
+                priority,
+                enqueued,
+                packets: fragments,
+                tracked,
+            });
+        Ok(())
+    }
+}
+
+#[cfg(all(test, any(feature = "client", feature = "server")))]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+    const MAX_PACKET_SIZE: usize = 100;
+
+    fn packet(len: usize) -> Packet {
+        let mut buf = vec![];
+        RtcPacketHeader {
+            msg_id: 7,
+            version: 0,
+            codec: 0,
+            flags: 0,
+        }
+        .write(&mut buf);
+        buf.resize(len, 0xAB);
+        buf.into_boxed_slice()
+    }
+
+    /// Frame packets queued on the unreliable channel as the socket writer
+    /// does, returning the messages sent.
+    fn send_acked(packets: Vec<Packet>, batching: &BatchingConfig) -> Vec<Packet> {
+        let mut fragmenter = Fragmenter::default();
+        let queued: Vec<((), Packet)> = packets
+            .into_iter()
+            .flat_map(|packet| {
+                fragmenter
+                    .split(
+                        packet,
+                        max_queued_size(UNRELIABLE_CHANNEL_INDEX, MAX_PACKET_SIZE),
+                    )
+                    .unwrap()
+            })
+            .map(|packet| ((), packet))
+            .collect();
+        let mut acks = Acks::default();
+        pack(queued, true, batching, MAX_PACKET_SIZE)
+            .into_iter()
+            .map(|(_, packet)| acks.wrap(PEER, vec![], &packet).1)
+            .collect()
+    }
+
+    #[test]
+    fn acked_packets_fit_in_a_message() {
+        let packets = vec![
+            packet(MAX_PACKET_SIZE),
+            packet(1000),
+            packet(10),
+            packet(90),
+        ];
+        for batching in [
+            BatchingConfig::default(),
+            BatchingConfig {
+                enabled: true,
+                ..default()
+            },
+        ] {
+            let sent = send_acked(packets.clone(), &batching);
+            assert!(!sent.is_empty());
+            for message in sent {
+                assert!(
+                    message.len() <= MAX_PACKET_SIZE,
+                    "sent {} bytes with {batching:?}",
+                    message.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unreliable_packets_leave_room_for_acknowledgements() {
+        assert_eq!(
+            max_queued_size(RELIABLE_CHANNEL_INDEX, MAX_PACKET_SIZE),
+            MAX_PACKET_SIZE
+        );
+        assert_eq!(
+            max_queued_size(UNRELIABLE_CHANNEL_INDEX, MAX_PACKET_SIZE),
+            MAX_PACKET_SIZE - ack::ENVELOPE_LEN
+        );
+    }
 }
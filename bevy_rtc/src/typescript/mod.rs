@@ -0,0 +1,247 @@
+//! TypeScript bindings for the registered protocols, so tools written in plain
+//! TypeScript can speak the same protocol as a Bevy client.
+
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::{
+    codec::{Codec, Json},
+    protocol::RtcPacketHeader,
+    registry::ProtocolRegistry,
+    socket::{RELIABLE_CHANNEL_INDEX, UNRELIABLE_CHANNEL_INDEX},
+};
+#[cfg(any(feature = "client", feature = "server"))]
+use bevy::{prelude::*, utils::HashSet};
+#[cfg(any(feature = "client", feature = "server"))]
+use std::fmt::Write;
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// The encoder and decoder shared by all generated bindings.
+const RUNTIME: &str = include_str!("runtime.ts");
+
+/// The shape of a payload's JSON form, as derived by `#[derive(Payload)]`.
+#[derive(Debug, Clone)]
+pub struct TypeScriptType {
+    /// The TypeScript type of the payload, including packed fields
+    pub ty: &'static str,
+    /// The fields which are packed into bits, in the order they are written
+    pub packed: Vec<PackedField>,
+}
+
+impl Default for TypeScriptType {
+    fn default() -> Self {
+        Self {
+            ty: "unknown",
+            packed: vec![],
+        }
+    }
+}
+
+/// A field packed with `#[payload(quantize(...))]` or `#[payload(bits = N)]`.
+#[derive(Debug, Clone)]
+pub struct PackedField {
+    pub name: &'static str,
+    pub packing: Packing,
+}
+
+/// How a packed field is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Packing {
+    Quantize { min: f64, max: f64, bits: u32 },
+    Unsigned { bits: u32 },
+    Signed { bits: u32 },
+    Bool { bits: u32 },
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// Generate a TypeScript module for the protocols registered on an app. It
+/// declares a type and `MSG_ID` constant for each payload, and exports
+/// `encode` and a `Decoder` which read and write the packet framing, including
+/// batches and fragments.
+///
+/// Only protocols using the [`Json`] codec can be encoded and decoded.
+/// Payload types are derived from their Rust definitions. Types the derive does
+/// not know, such as nested structs, 64 and 128-bit integers, which lose
+/// precision as JavaScript numbers, and payloads with any `#[serde(...)]`
+/// attribute are declared as `unknown`.
+pub fn bindings(app: &App) -> String {
+    let mut out = String::from("// Generated by bevy_rtc. Do not edit.\n\n");
+    writeln!(out, "export const HEADER_LEN = {};", RtcPacketHeader::LEN).unwrap();
+    writeln!(
+        out,
+        "export const FLAG_FRAGMENT = {};",
+        RtcPacketHeader::FLAG_FRAGMENT
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "export const FLAG_BATCH = {};",
+        RtcPacketHeader::FLAG_BATCH
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "export const FLAG_COMPRESSED = {};",
+        RtcPacketHeader::FLAG_COMPRESSED
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "export const FLAG_SEQUENCED = {};",
+        RtcPacketHeader::FLAG_SEQUENCED
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "export const FLAG_ACKED = {};",
+        RtcPacketHeader::FLAG_ACKED
+    )
+    .unwrap();
+    writeln!(out, "export const JSON_CODEC = {};", Json::ID).unwrap();
+    writeln!(
+        out,
+        "export const CHANNELS = {{ unreliable: {UNRELIABLE_CHANNEL_INDEX}, reliable: {RELIABLE_CHANNEL_INDEX} }} as const;\n"
+    )
+    .unwrap();
+
+    let protocols: Vec<_> = app
+        .world
+        .get_resource::<ProtocolRegistry>()
+        .map(|registry| {
+            let mut idents = HashSet::new();
+            registry
+                .iter()
+                .map(|(id, protocol)| {
+                    let mut ident = ts_ident(protocol.name);
+                    if !idents.insert(ident.clone()) {
+                        ident = format!("{ident}_{id}");
+                        idents.insert(ident.clone());
+                    }
+                    (id, ident, protocol, (protocol.typescript)())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for (_, ident, protocol, ts) in &protocols {
+        writeln!(out, "/** `{}` */", protocol.name).unwrap();
+        writeln!(out, "export type {ident} = {};\n", ts.ty).unwrap();
+    }
+
+    out.push_str("export interface Payloads {\n");
+    for (_, ident, _, _) in &protocols {
+        writeln!(out, "  {ident}: {ident};").unwrap();
+    }
+    out.push_str("}\n\nexport const MSG_ID = {\n");
+    for (id, ident, _, _) in &protocols {
+        writeln!(out, "  {ident}: {id},").unwrap();
+    }
+    out.push_str("} as const;\n\nconst PROTOCOLS: Record<number, Protocol> = {\n");
+    for (id, ident, protocol, ts) in &protocols {
+        let packed: Vec<_> = ts
+            .packed
+            .iter()
+            .map(|field| format!("[\"{}\", {}]", field.name, ts_packing(field.packing)))
+            .collect();
+        writeln!(
+            out,
+            "  {id}: {{ name: \"{ident}\", version: {}, json: {}, packed: [{}] }},",
+            protocol.version,
+            protocol.codec == Json::NAME,
+            packed.join(", ")
+        )
+        .unwrap();
+    }
+    out.push_str("};\n\n");
+    out.push_str(RUNTIME);
+    out
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+/// A TypeScript identifier for a payload name, without module paths, e.g.
+/// `Snapshot_Player` for `game::Snapshot<game::Player>`.
+fn ts_ident(name: &str) -> String {
+    let mut ident = String::new();
+    let mut segment = String::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            segment.push(c);
+        } else if c == ':' && chars.peek() == Some(&':') {
+            // Drop the module path before this segment
+            chars.next();
+            segment.clear();
+        } else {
+            ident.push_str(&segment);
+            segment.clear();
+            if !ident.is_empty() && !ident.ends_with('_') {
+                ident.push('_');
+            }
+        }
+    }
+    ident.push_str(&segment);
+    let ident = ident.trim_end_matches('_');
+    if ident.starts_with(|c: char| c.is_ascii_digit()) || ident.is_empty() {
+        format!("_{ident}")
+    } else {
+        ident.to_string()
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+fn ts_packing(packing: Packing) -> String {
+    match packing {
+        Packing::Quantize { min, max, bits } => {
+            format!("{{ kind: \"quantize\", min: {min:?}, max: {max:?}, bits: {bits} }}")
+        }
+        Packing::Unsigned { bits } => format!("{{ kind: \"unsigned\", bits: {bits} }}"),
+        Packing::Signed { bits } => format!("{{ kind: \"signed\", bits: {bits} }}"),
+        Packing::Bool { bits } => format!("{{ kind: \"bool\", bits: {bits} }}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::Payload;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    struct Plain {
+        name: String,
+        score: u32,
+        tags: Vec<Option<String>>,
+        id: u64,
+    }
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    struct Renamed {
+        #[serde(rename = "n")]
+        name: String,
+    }
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type")]
+    enum Tagged {
+        A,
+        B { x: f32 },
+    }
+
+    #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
+    enum Untagged {
+        A,
+        B { x: f32 },
+    }
+
+    #[test]
+    fn types_follow_the_json_form() {
+        assert_eq!(
+            Plain::typescript().ty,
+            "{ name: string; score: number; tags: (string | null)[]; id: unknown }"
+        );
+        assert_eq!(Untagged::typescript().ty, "\"A\" | { B: { x: number } }");
+    }
+
+    #[test]
+    fn serde_attributes_are_unknown() {
+        assert_eq!(Renamed::typescript().ty, "unknown");
+        assert_eq!(Tagged::typescript().ty, "unknown");
+    }
+}
@@ -0,0 +1,270 @@
+// Runtime shared by all bindings
+
+type Packing =
+  | { kind: "quantize"; min: number; max: number; bits: number }
+  | { kind: "unsigned"; bits: number }
+  | { kind: "signed"; bits: number }
+  | { kind: "bool"; bits: number };
+
+interface Protocol {
+  name: keyof Payloads;
+  version: number;
+  /** Whether the protocol uses the JSON codec, the only one supported here */
+  json: boolean;
+  packed: [string, Packing][];
+}
+
+export type ProtocolName = keyof Payloads;
+
+/** A received payload, tagged with its protocol */
+export type Message = {
+  [K in ProtocolName]: { protocol: K; payload: Payloads[K] };
+}[ProtocolName];
+
+/** The largest packet sent without fragmenting, matching `FragmentationConfig` */
+export const MAX_PACKET_SIZE = 16 * 1024;
+
+const FRAGMENT_HEADER_LEN = 6;
+
+const SEQUENCE_LEN = 2;
+
+/** The sequence number and acknowledgements of an acked datagram, which are skipped: the browser does not acknowledge */
+const ACKS_LEN = 8;
+
+const MAX_INCOMPLETE_MESSAGES = 64;
+
+let nextFragmentGroup = 0;
+
+function writeHeader(
+  buf: Uint8Array,
+  msgId: number,
+  version: number,
+  codec: number,
+  flags: number,
+) {
+  buf[0] = msgId & 0xff;
+  buf[1] = msgId >> 8;
+  buf[2] = version;
+  buf[3] = codec;
+  buf[4] = flags;
+}
+
+function readU16(buf: Uint8Array, offset: number): number {
+  return buf[offset] | (buf[offset + 1] << 8);
+}
+
+function maxValue(bits: number): bigint {
+  return (1n << BigInt(bits)) - 1n;
+}
+
+function clamp(value: bigint, min: bigint, max: bigint): bigint {
+  return value < min ? min : value > max ? max : value;
+}
+
+function pack(value: unknown, packing: Packing): bigint {
+  switch (packing.kind) {
+    case "quantize": {
+      const steps = Number(maxValue(packing.bits));
+      const normalized = (Number(value) - packing.min) / (packing.max - packing.min);
+      const clamped = Math.min(Math.max(normalized, 0), 1);
+      return Number.isNaN(clamped) ? 0n : BigInt(Math.round(clamped * steps));
+    }
+    case "unsigned":
+      return clamp(BigInt(Math.trunc(Number(value))), 0n, maxValue(packing.bits));
+    case "signed": {
+      let signed = BigInt(Math.trunc(Number(value)));
+      if (packing.bits < 64) {
+        const half = 1n << BigInt(packing.bits - 1);
+        signed = clamp(signed, -half, half - 1n);
+      }
+      // Zigzag encode, so small negative numbers stay small
+      return signed >= 0n ? signed << 1n : ((-signed) << 1n) - 1n;
+    }
+    case "bool":
+      return value ? 1n : 0n;
+  }
+}
+
+function unpack(value: bigint, packing: Packing): number | boolean {
+  switch (packing.kind) {
+    case "quantize": {
+      const steps = Number(maxValue(packing.bits));
+      return packing.min + (Number(value) / steps) * (packing.max - packing.min);
+    }
+    case "unsigned":
+      return Number(value);
+    case "signed":
+      return Number(value & 1n ? -((value + 1n) >> 1n) : value >> 1n);
+    case "bool":
+      return value !== 0n;
+  }
+}
+
+/** Pack fields into bits, least significant bit first */
+function packFields(payload: Record<string, unknown>, packed: [string, Packing][]): number[] {
+  const bytes: number[] = [];
+  let len = 0;
+  for (const [name, packing] of packed) {
+    const value = pack(payload[name], packing);
+    for (let i = 0; i < packing.bits; i++) {
+      if (len % 8 === 0) bytes.push(0);
+      if ((value >> BigInt(i)) & 1n) bytes[bytes.length - 1] |= 1 << len % 8;
+      len++;
+    }
+  }
+  return bytes;
+}
+
+function unpackFields(
+  bytes: number[],
+  packed: [string, Packing][],
+): Record<string, unknown> | undefined {
+  const fields: Record<string, unknown> = {};
+  let pos = 0;
+  for (const [name, packing] of packed) {
+    let value = 0n;
+    for (let i = 0; i < packing.bits; i++) {
+      const byte = bytes[pos >> 3];
+      if (byte === undefined) return undefined;
+      if ((byte >> pos % 8) & 1) value |= 1n << BigInt(i);
+      pos++;
+    }
+    fields[name] = unpack(value, packing);
+  }
+  return fields;
+}
+
+/**
+ * Encode a payload into the packets to send, fragmenting it if it is larger
+ * than `maxPacketSize`.
+ */
+export function encode<K extends ProtocolName>(
+  protocol: K,
+  payload: Payloads[K],
+  maxPacketSize = MAX_PACKET_SIZE,
+): Uint8Array[] {
+  const msgId = MSG_ID[protocol];
+  const { version, json, packed } = PROTOCOLS[msgId];
+  if (!json) throw new Error(`${protocol} does not use the JSON codec`);
+  let wire: unknown = payload;
+  if (packed.length > 0) {
+    const record = payload as unknown as Record<string, unknown>;
+    const fields = { ...record };
+    for (const [name] of packed) delete fields[name];
+    fields.__packed = packFields(record, packed);
+    wire = fields;
+  }
+  const body = new TextEncoder().encode(JSON.stringify(wire));
+  const packet = new Uint8Array(HEADER_LEN + body.length);
+  writeHeader(packet, msgId, version, JSON_CODEC, 0);
+  packet.set(body, HEADER_LEN);
+  if (packet.length <= maxPacketSize) return [packet];
+
+  const chunkSize = Math.max(maxPacketSize - HEADER_LEN - FRAGMENT_HEADER_LEN, 1);
+  const count = Math.ceil(packet.length / chunkSize);
+  if (count > 0xffff) throw new Error(`${protocol} is too large to fragment`);
+  const group = nextFragmentGroup;
+  nextFragmentGroup = (nextFragmentGroup + 1) & 0xffff;
+  const fragments: Uint8Array[] = [];
+  for (let index = 0; index < count; index++) {
+    const chunk = packet.subarray(index * chunkSize, (index + 1) * chunkSize);
+    const fragment = new Uint8Array(HEADER_LEN + FRAGMENT_HEADER_LEN + chunk.length);
+    writeHeader(fragment, msgId, version, JSON_CODEC, FLAG_FRAGMENT);
+    fragment.set([group & 0xff, group >> 8, index & 0xff, index >> 8, count & 0xff, count >> 8], HEADER_LEN);
+    fragment.set(chunk, HEADER_LEN + FRAGMENT_HEADER_LEN);
+    fragments.push(fragment);
+  }
+  return fragments;
+}
+
+/**
+ * Decodes the packets received from a peer, reassembling fragments. Use one
+ * decoder per peer. Payloads of sequenced protocols older than the newest
+ * already decoded are dropped. Packets which are compressed, use another
+ * codec or version, or belong to no known protocol are skipped.
+ */
+export class Decoder {
+  private fragments = new Map<number, { count: number; chunks: (Uint8Array | undefined)[]; received: number }>();
+  /** The newest sequence number delivered for each sequenced payload ID */
+  private sequences = new Map<number, number>();
+
+  decode(packet: Uint8Array): Message[] {
+    const messages: Message[] = [];
+    this.decodeInto(packet, messages);
+    return messages;
+  }
+
+  private decodeInto(packet: Uint8Array, messages: Message[]) {
+    if (packet.length < HEADER_LEN) return;
+    const msgId = readU16(packet, 0);
+    const flags = packet[4];
+    const data = packet.subarray(HEADER_LEN);
+    if (flags & FLAG_ACKED) {
+      if (data.length > ACKS_LEN) this.decodeInto(data.subarray(ACKS_LEN), messages);
+    } else if (flags & FLAG_BATCH) {
+      for (let offset = 0; offset + 2 <= data.length; ) {
+        const len = readU16(data, offset);
+        this.decodeInto(data.subarray(offset + 2, offset + 2 + len), messages);
+        offset += 2 + len;
+      }
+    } else if (flags & FLAG_FRAGMENT) {
+      const whole = this.reassemble(data);
+      if (whole) this.decodeInto(whole, messages);
+    } else if (flags & FLAG_SEQUENCED) {
+      if (data.length < SEQUENCE_LEN) return;
+      const sequence = readU16(data, 0);
+      const newest = this.sequences.get(msgId);
+      // Drop anything not newer than the newest delivered, accounting for wrap around
+      if (newest !== undefined && (sequence === newest || ((sequence - newest) & 0xffff) >= 0x7fff)) return;
+      this.sequences.set(msgId, sequence);
+      const unsequenced = new Uint8Array(packet.length - SEQUENCE_LEN);
+      unsequenced.set(packet.subarray(0, HEADER_LEN));
+      unsequenced[4] &= ~FLAG_SEQUENCED;
+      unsequenced.set(data.subarray(SEQUENCE_LEN), HEADER_LEN);
+      this.decodeInto(unsequenced, messages);
+    } else if (!(flags & FLAG_COMPRESSED)) {
+      const protocol = PROTOCOLS[msgId];
+      if (!protocol?.json || packet[2] !== protocol.version || packet[3] !== JSON_CODEC) return;
+      let payload = JSON.parse(new TextDecoder().decode(data));
+      if (protocol.packed.length > 0) {
+        const { __packed, ...fields } = payload;
+        const unpacked = unpackFields(__packed, protocol.packed);
+        if (!unpacked) return;
+        payload = { ...fields, ...unpacked };
+      }
+      messages.push({ protocol: protocol.name, payload } as Message);
+    }
+  }
+
+  private reassemble(data: Uint8Array): Uint8Array | undefined {
+    if (data.length < FRAGMENT_HEADER_LEN) return undefined;
+    const group = readU16(data, 0);
+    const index = readU16(data, 2);
+    const count = readU16(data, 4);
+    if (index >= count) return undefined;
+    let entry = this.fragments.get(group);
+    if (!entry || entry.count !== count) {
+      entry = { count, chunks: new Array(count), received: 0 };
+      this.fragments.set(group, entry);
+      // Forget the oldest incomplete messages, e.g. with fragments lost on an
+      // unreliable channel
+      while (this.fragments.size > MAX_INCOMPLETE_MESSAGES) {
+        this.fragments.delete(this.fragments.keys().next().value as number);
+      }
+    }
+    if (!entry.chunks[index]) {
+      entry.chunks[index] = data.slice(FRAGMENT_HEADER_LEN);
+      entry.received++;
+    }
+    if (entry.received < count) return undefined;
+    this.fragments.delete(group);
+    const chunks = entry.chunks as Uint8Array[];
+    const whole = new Uint8Array(chunks.reduce((len, chunk) => len + chunk.length, 0));
+    let offset = 0;
+    for (const chunk of chunks) {
+      whole.set(chunk, offset);
+      offset += chunk.length;
+    }
+    return whole;
+  }
+}
@@ -0,0 +1,81 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Fields, Path};
+
+/// Generate a struct holding the changed fields of a payload, and implement
+/// `DeltaPayload` with it.
+pub(crate) fn derive(input: DeriveInput, krate: Path) -> syn::Result<TokenStream> {
+    let DeriveInput {
+        ident,
+        vis,
+        generics,
+        data,
+        ..
+    } = input;
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &generics,
+            "`DeltaPayload` cannot be derived for generic payloads",
+        ));
+    }
+    let fields = match data {
+        Data::Struct(data) => match data.fields {
+            Fields::Named(fields) => fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &ident,
+                    "`DeltaPayload` can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &ident,
+                "`DeltaPayload` can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let delta_ident = format_ident!("{}Delta", ident);
+    let serde_path = quote!(#krate::protocol::__private::serde).to_string();
+    let doc = format!("The fields of [`{ident}`] which changed since a baseline.");
+    let names: Vec<_> = fields.iter().map(|field| &field.ident).collect();
+    let delta_fields = fields.iter().map(|field| {
+        let vis = &field.vis;
+        let name = &field.ident;
+        let ty = &field.ty;
+        quote! { #vis #name: ::std::option::Option<#ty> }
+    });
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(
+            ::std::fmt::Debug,
+            ::std::clone::Clone,
+            #krate::protocol::__private::Serialize,
+            #krate::protocol::__private::Deserialize,
+        )]
+        #[serde(crate = #serde_path)]
+        #vis struct #delta_ident {
+            #(#delta_fields,)*
+        }
+
+        impl #krate::protocol::DeltaPayload for #ident {
+            type Delta = #delta_ident;
+
+            fn diff(&self, baseline: &Self) -> Self::Delta {
+                #delta_ident {
+                    #(#names: (self.#names != baseline.#names).then(|| self.#names.clone()),)*
+                }
+            }
+
+            fn apply(&mut self, delta: Self::Delta) {
+                #(
+                    if let ::std::option::Option::Some(value) = delta.#names {
+                        self.#names = value;
+                    }
+                )*
+            }
+        }
+    })
+}
@@ -1,29 +1,225 @@
 extern crate proc_macro;
 
+mod delta;
+mod quantize;
+mod typescript;
+
 use proc_macro::TokenStream;
+use proc_macro2::Span;
+use proc_macro_crate::{crate_name, FoundCrate};
 use quote::quote;
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
+use syn::{
+    parse_macro_input, parse_quote, punctuated::Punctuated, Attribute, DeriveInput, GenericParam,
+    Ident, LitInt, LitStr, Path, Token, WherePredicate,
 };
-use syn::{parse_macro_input, DeriveInput};
 
-#[proc_macro_derive(Payload)]
+#[proc_macro_derive(Payload, attributes(payload))]
 pub fn derive_payload_fn(item: TokenStream) -> TokenStream {
-    let DeriveInput { ident, .. } = parse_macro_input!(item);
-    let mut s = DefaultHasher::new();
-    ident.to_string().hash(&mut s);
-    let id = s.finish() as u16;
-    let reflect_name = ident.to_string();
+    let DeriveInput {
+        ident,
+        attrs: item_attrs,
+        generics,
+        data,
+        ..
+    } = parse_macro_input!(item);
+    let attrs = match PayloadAttributes::parse(&item_attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let krate = attrs.krate.unwrap_or_else(bevy_rtc_path);
+    let is_generic = generics
+        .params
+        .iter()
+        .any(|param| !matches!(param, GenericParam::Lifetime(_)));
+    if is_generic && attrs.id.is_some() {
+        return syn::Error::new_spanned(
+            &generics,
+            "`#[payload(id = ...)]` cannot be used on generic payloads, since each instance needs a distinct ID",
+        )
+        .to_compile_error()
+        .into();
+    }
+    if !is_generic && attrs.bound.is_some() {
+        return syn::Error::new_spanned(
+            &ident,
+            "`#[payload(bound = ...)]` can only be used on generic payloads",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    // Payloads are named by their module path, so that payloads of the same
+    // name in different modules do not share an ID
+    let base_name = match attrs.name {
+        Some(name) => quote! { #name },
+        None => quote! { concat!(module_path!(), "::", stringify!(#ident)) },
+    };
+    let (reflect_name, id) = if is_generic {
+        // Each instance of a generic payload is named by its parameters. Type
+        // parameters are named by their own payload names rather than
+        // `type_name`, which is not stable across compiler versions, unless a
+        // custom bound means they need not be payloads.
+        let params = generics.params.iter().filter_map(|param| match param {
+            GenericParam::Type(param) => {
+                let ident = &param.ident;
+                Some(match attrs.bound {
+                    Some(_) => quote! { ::std::any::type_name::<#ident>().to_string() },
+                    None => quote! {
+                        <#ident as #krate::protocol::Payload>::reflect_name().to_string()
+                    },
+                })
+            }
+            GenericParam::Const(param) => {
+                let ident = &param.ident;
+                Some(quote! { #ident.to_string() })
+            }
+            GenericParam::Lifetime(_) => None,
+        });
+        let generic_payload = quote! {
+            #krate::protocol::__private::generic_payload::<Self>(|| {
+                format!("{}<{}>", #base_name, [#(#params),*].join(", "))
+            })
+        };
+        (quote! { #generic_payload.0 }, quote! { #generic_payload.1 })
+    } else {
+        let id = match attrs.id {
+            Some(id) => quote! { #id },
+            None => quote! {
+                const ID: u16 = #krate::protocol::stable_id(#base_name);
+                ID
+            },
+        };
+        (base_name, id)
+    };
+    let packed = match quantize::derive(&item_attrs, &data, is_generic, &krate) {
+        Ok(packed) => packed,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let typescript = typescript::derive(&item_attrs, &data, &krate);
+    let version = attrs.version.map(|version| {
+        quote! {
+            fn version() -> u8 {
+                #version
+            }
+        }
+    });
+
+    let mut generics = generics;
+    if is_generic {
+        let params: Vec<Ident> = generics
+            .type_params()
+            .map(|param| param.ident.clone())
+            .collect();
+        let where_clause = generics.make_where_clause();
+        match attrs.bound {
+            Some(bound) => where_clause.predicates.extend(bound),
+            None => {
+                for param in params {
+                    where_clause
+                        .predicates
+                        .push(parse_quote! { #param: #krate::protocol::Payload });
+                }
+            }
+        }
+        where_clause.predicates.push(parse_quote! {
+            Self: ::std::fmt::Debug
+                + ::std::clone::Clone
+                + ::std::marker::Send
+                + ::std::marker::Sync
+                + #krate::protocol::__private::Serialize
+                + #krate::protocol::__private::DeserializeOwned
+                + 'static
+        });
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     quote! {
-        impl bevy_rtc::protocol::Payload for #ident {
+        impl #impl_generics #krate::protocol::Payload for #ident #ty_generics #where_clause {
             fn id() -> u16 {
                 #id
             }
             fn reflect_name() -> &'static str {
                 #reflect_name
             }
+            #version
+            #packed
+            #typescript
         }
     }
     .into()
 }
+
+#[proc_macro_derive(DeltaPayload, attributes(payload))]
+pub fn derive_delta_payload_fn(item: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(item);
+    let attrs = match PayloadAttributes::parse(&input.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let krate = attrs.krate.unwrap_or_else(bevy_rtc_path);
+    delta::derive(input, krate)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// The path to the `bevy_rtc` crate, following renames in `Cargo.toml`.
+fn bevy_rtc_path() -> Path {
+    match crate_name("bevy_rtc") {
+        Ok(FoundCrate::Itself) => parse_quote! { crate },
+        Ok(FoundCrate::Name(name)) => {
+            let ident = Ident::new(&name, Span::call_site());
+            parse_quote! { ::#ident }
+        }
+        Err(_) => parse_quote! { ::bevy_rtc },
+    }
+}
+
+/// Options given through `#[payload(...)]` attributes.
+#[derive(Default)]
+struct PayloadAttributes {
+    /// An explicit payload ID, e.g. `#[payload(id = 42)]`
+    id: Option<u16>,
+    /// The name the payload ID is derived from, e.g. `#[payload(name = "game.Move")]`
+    name: Option<String>,
+    /// The schema version of the payload, e.g. `#[payload(version = 2)]`
+    version: Option<u8>,
+    /// The path `bevy_rtc` is available at, e.g. `#[payload(crate = "engine::net")]`
+    krate: Option<Path>,
+    /// Bounds replacing `T: Payload` on type parameters, e.g. `#[payload(bound = "T: Copy")]`
+    bound: Option<Punctuated<WherePredicate, Token![,]>>,
+}
+
+impl PayloadAttributes {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("payload")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    parsed.id = Some(lit.base10_parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("name") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    parsed.name = Some(lit.value());
+                    Ok(())
+                } else if meta.path.is_ident("version") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    parsed.version = Some(lit.base10_parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("crate") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    parsed.krate = Some(lit.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("bound") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    parsed.bound = Some(lit.parse_with(Punctuated::parse_terminated)?);
+                    Ok(())
+                } else {
+                    Err(meta.error(
+                        "unsupported payload attribute, expected `id`, `name`, `version`, `crate` or `bound`",
+                    ))
+                }
+            })?;
+        }
+        Ok(parsed)
+    }
+}
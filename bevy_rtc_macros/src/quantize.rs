@@ -0,0 +1,239 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Attribute, Data, Expr, Field, Fields, Ident, Lit, LitInt, Path, Type, UnOp};
+
+/// How a field is packed, from its `#[payload(...)]` attribute.
+pub(crate) enum Packing {
+    /// `#[payload(quantize(min = ..., max = ..., bits = N))]` on floats
+    Quantize {
+        min: Box<Expr>,
+        max: Box<Expr>,
+        bits: u32,
+    },
+    /// `#[payload(bits = N)]` on bools and integers
+    Bits(u32),
+}
+
+impl Packing {
+    pub(crate) fn parse(field: &Field) -> syn::Result<Option<Self>> {
+        let mut packing = None;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("payload"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("bits") {
+                    let bits = parse_bits(&meta.value()?.parse()?, 64)?;
+                    packing = Some(Self::Bits(bits));
+                    Ok(())
+                } else if meta.path.is_ident("quantize") {
+                    let (mut min, mut max): (Option<Box<Expr>>, Option<Box<Expr>>) = (None, None);
+                    let mut bits = None;
+                    meta.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("min") {
+                            min = Some(meta.value()?.parse()?);
+                        } else if meta.path.is_ident("max") {
+                            max = Some(meta.value()?.parse()?);
+                        } else if meta.path.is_ident("bits") {
+                            bits = Some(parse_bits(&meta.value()?.parse()?, 32)?);
+                        } else {
+                            return Err(meta.error("expected `min`, `max` or `bits`"));
+                        }
+                        Ok(())
+                    })?;
+                    match (min, max, bits) {
+                        (Some(min), Some(max), Some(bits)) => {
+                            if let (Some(lo), Some(hi)) = (literal(&min), literal(&max)) {
+                                if lo >= hi {
+                                    return Err(syn::Error::new_spanned(
+                                        max,
+                                        "`quantize` requires `min` to be less than `max`",
+                                    ));
+                                }
+                            }
+                            packing = Some(Self::Quantize { min, max, bits });
+                            Ok(())
+                        }
+                        _ => Err(meta.error("`quantize` requires `min`, `max` and `bits`")),
+                    }
+                } else {
+                    Err(meta.error("unsupported field attribute, expected `quantize` or `bits`"))
+                }
+            })?;
+        }
+        Ok(packing)
+    }
+}
+
+/// The value of a numeric literal, possibly negated.
+fn literal(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            Lit::Float(lit) => lit.base10_parse().ok(),
+            Lit::Int(lit) => lit.base10_parse().ok(),
+            _ => None,
+        },
+        Expr::Unary(unary) if matches!(unary.op, UnOp::Neg(_)) => {
+            literal(&unary.expr).map(|value| -value)
+        }
+        Expr::Paren(paren) => literal(&paren.expr),
+        _ => None,
+    }
+}
+
+/// Reject `#[serde(...)]` attributes, which the wire struct of a payload with
+/// packed fields would not follow.
+fn reject_serde(attrs: &[Attribute]) -> syn::Result<()> {
+    match attrs.iter().find(|attr| attr.path().is_ident("serde")) {
+        Some(attr) => Err(syn::Error::new_spanned(
+            attr,
+            "`#[serde(...)]` attributes are not supported on payloads with packed fields",
+        )),
+        None => Ok(()),
+    }
+}
+
+fn parse_bits(lit: &LitInt, max: u32) -> syn::Result<u32> {
+    let bits: u32 = lit.base10_parse()?;
+    if bits == 0 || bits > max {
+        return Err(syn::Error::new_spanned(
+            lit,
+            format!("`bits` must be between 1 and {max}"),
+        ));
+    }
+    Ok(bits)
+}
+
+/// Generate `encode_body` and `decode_body` for payloads with packed fields,
+/// or nothing if no field is packed.
+pub(crate) fn derive(
+    attrs: &[Attribute],
+    data: &Data,
+    is_generic: bool,
+    krate: &Path,
+) -> syn::Result<TokenStream> {
+    let fields = match data {
+        Data::Struct(data) => &data.fields,
+        _ => return Ok(TokenStream::new()),
+    };
+    let mut plain: Vec<(&Ident, &Type)> = vec![];
+    let mut packed: Vec<(&Ident, &Type, Packing)> = vec![];
+    for field in fields.iter() {
+        let Some(packing) = Packing::parse(field)? else {
+            if let Some(ident) = &field.ident {
+                plain.push((ident, &field.ty));
+            }
+            continue;
+        };
+        let Some(ident) = &field.ident else {
+            return Err(syn::Error::new_spanned(
+                field,
+                "packed fields are only supported on structs with named fields",
+            ));
+        };
+        packed.push((ident, &field.ty, packing));
+    }
+    if packed.is_empty() {
+        return Ok(TokenStream::new());
+    }
+    if is_generic || !matches!(fields, Fields::Named(_)) {
+        return Err(syn::Error::new_spanned(
+            packed[0].0,
+            "packed fields are only supported on non-generic structs with named fields",
+        ));
+    }
+    reject_serde(attrs)?;
+    for field in fields.iter() {
+        reject_serde(&field.attrs)?;
+    }
+
+    let private = quote!(#krate::protocol::__private);
+    let serde_path = quote!(#krate::protocol::__private::serde).to_string();
+    let plain_names: Vec<_> = plain.iter().map(|(ident, _)| ident).collect();
+    let plain_types: Vec<_> = plain.iter().map(|(_, ty)| ty).collect();
+    let packed_names: Vec<_> = packed.iter().map(|(ident, _, _)| ident).collect();
+    // The wire struct borrows the plain fields, if there are any
+    let lifetime = (!plain.is_empty()).then(|| quote!(<'a>));
+    // Ranges must be constant and ordered, or quantizing divides by zero
+    let ranges = packed.iter().filter_map(|(_, _, packing)| match packing {
+        Packing::Quantize { min, max, .. } => Some(quote! {
+            const _: () = ::std::assert!(
+                ((#min) as f64) < ((#max) as f64),
+                "`quantize` requires `min` to be less than `max`",
+            );
+        }),
+        Packing::Bits(_) => None,
+    });
+    let writes = packed.iter().map(|(ident, ty, packing)| match packing {
+        Packing::Quantize { min, max, bits } => quote! {
+            bits.write(
+                <#ty as #private::Quantize>::quantize(self.#ident, (#min) as f64, (#max) as f64, #bits),
+                #bits,
+            );
+        },
+        Packing::Bits(bits) => quote! {
+            bits.write(<#ty as #private::PackBits>::pack(self.#ident, #bits), #bits);
+        },
+    });
+    let reads = packed.iter().map(|(ident, ty, packing)| match packing {
+        Packing::Quantize { min, max, bits } => quote! {
+            let #ident = <#ty as #private::Quantize>::dequantize(
+                bits.read(#bits).ok_or_else(truncated)?,
+                (#min) as f64,
+                (#max) as f64,
+                #bits,
+            );
+        },
+        Packing::Bits(bits) => quote! {
+            let #ident =
+                <#ty as #private::PackBits>::unpack(bits.read(#bits).ok_or_else(truncated)?, #bits);
+        },
+    });
+
+    Ok(quote! {
+        fn encode_body<C: #private::Codec>(
+            &self,
+            buf: &mut ::std::vec::Vec<u8>,
+        ) -> ::std::result::Result<(), #private::CodecError> {
+            #[derive(#private::Serialize)]
+            #[serde(crate = #serde_path)]
+            struct PayloadWire #lifetime {
+                #(#plain_names: &'a #plain_types,)*
+                __packed: #private::Packed,
+            }
+            #(#ranges)*
+            let mut bits = #private::BitWriter::default();
+            #(#writes)*
+            C::encode(
+                &PayloadWire {
+                    #(#plain_names: &self.#plain_names,)*
+                    __packed: bits.finish(),
+                },
+                buf,
+            )
+        }
+
+        fn decode_body<C: #private::Codec>(
+            bytes: &[u8],
+        ) -> ::std::result::Result<Self, #private::CodecError> {
+            #[derive(#private::Deserialize)]
+            #[serde(crate = #serde_path)]
+            struct PayloadWire {
+                #(#plain_names: #plain_types,)*
+                __packed: #private::Packed,
+            }
+            let wire: PayloadWire = C::decode(bytes)?;
+            let truncated = || #private::CodecError {
+                codec: C::NAME,
+                message: "packed fields are truncated".to_string(),
+            };
+            let mut bits = #private::BitReader::new(&wire.__packed);
+            #(#reads)*
+            ::std::result::Result::Ok(Self {
+                #(#plain_names: wire.#plain_names,)*
+                #(#packed_names,)*
+            })
+        }
+    })
+}
@@ -0,0 +1,179 @@
+use crate::quantize::Packing;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Attribute, Data, Fields, GenericArgument, Path, PathArguments, Type};
+
+/// Generate `typescript`, describing the JSON form of a payload as a
+/// TypeScript type. Serde attributes can rename, skip, flatten or retag any
+/// part of the JSON form, so payloads using them are `unknown`.
+pub(crate) fn derive(attrs: &[Attribute], data: &Data, krate: &Path) -> TokenStream {
+    let ty = match data {
+        _ if uses_serde_attributes(attrs, data) => "unknown".to_string(),
+        Data::Struct(data) => fields_type(&data.fields),
+        Data::Enum(data) if data.variants.is_empty() => "never".to_string(),
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .map(|variant| {
+                let name = variant.ident.to_string();
+                match &variant.fields {
+                    Fields::Unit => format!("\"{name}\""),
+                    fields => format!("{{ {name}: {} }}", fields_type(fields)),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Data::Union(_) => "unknown".to_string(),
+    };
+
+    let typescript = quote!(#krate::typescript);
+    let packed = match data {
+        Data::Struct(data) => data
+            .fields
+            .iter()
+            .filter_map(|field| {
+                // Invalid attributes are reported by the quantize derive
+                let packing = Packing::parse(field).ok()??;
+                let name = field.ident.as_ref()?.to_string();
+                let packing = match packing {
+                    Packing::Quantize { min, max, bits } => quote! {
+                        #typescript::Packing::Quantize {
+                            min: (#min) as f64,
+                            max: (#max) as f64,
+                            bits: #bits,
+                        }
+                    },
+                    Packing::Bits(bits) => match path_ident(&field.ty).as_deref() {
+                        Some("bool") => quote!(#typescript::Packing::Bool { bits: #bits }),
+                        Some("i8" | "i16" | "i32" | "i64" | "isize") => {
+                            quote!(#typescript::Packing::Signed { bits: #bits })
+                        }
+                        _ => quote!(#typescript::Packing::Unsigned { bits: #bits }),
+                    },
+                };
+                Some(quote! {
+                    #typescript::PackedField {
+                        name: #name,
+                        packing: #packing,
+                    }
+                })
+            })
+            .collect(),
+        _ => vec![],
+    };
+
+    quote! {
+        fn typescript() -> #typescript::TypeScriptType {
+            #typescript::TypeScriptType {
+                ty: #ty,
+                packed: ::std::vec![#(#packed),*],
+            }
+        }
+    }
+}
+
+/// Whether `#[serde(...)]` is on the payload or any of its variants or fields.
+fn uses_serde_attributes(attrs: &[Attribute], data: &Data) -> bool {
+    let is_serde = |attrs: &[Attribute]| attrs.iter().any(|attr| attr.path().is_ident("serde"));
+    let fields_use_serde = |fields: &Fields| fields.iter().any(|field| is_serde(&field.attrs));
+    is_serde(attrs)
+        || match data {
+            Data::Struct(data) => fields_use_serde(&data.fields),
+            Data::Enum(data) => data
+                .variants
+                .iter()
+                .any(|variant| is_serde(&variant.attrs) || fields_use_serde(&variant.fields)),
+            Data::Union(_) => false,
+        }
+}
+
+/// The TypeScript type of the JSON serde produces for some fields.
+fn fields_type(fields: &Fields) -> String {
+    match fields {
+        Fields::Named(fields) => {
+            let fields: Vec<_> = fields
+                .named
+                .iter()
+                .filter_map(|field| {
+                    Some(format!("{}: {}", field.ident.as_ref()?, ts_type(&field.ty)))
+                })
+                .collect();
+            if fields.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", fields.join("; "))
+            }
+        }
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => ts_type(&fields.unnamed[0].ty),
+        Fields::Unnamed(fields) => tuple_type(fields.unnamed.iter().map(|field| &field.ty)),
+        Fields::Unit => "null".to_string(),
+    }
+}
+
+/// The TypeScript type of the JSON serde produces for a Rust type. Types which
+/// are not known, such as other structs or generic parameters, are `unknown`,
+/// as are integers which can exceed the 2^53 a JavaScript number holds exactly.
+fn ts_type(ty: &Type) -> String {
+    match ty {
+        Type::Path(path) => {
+            let Some(segment) = path.path.segments.last() else {
+                return "unknown".to_string();
+            };
+            let args: Vec<&Type> = match &segment.arguments {
+                PathArguments::AngleBracketed(args) => args
+                    .args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        GenericArgument::Type(ty) => Some(ty),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => vec![],
+            };
+            match (segment.ident.to_string().as_str(), args.as_slice()) {
+                ("bool", []) => "boolean".to_string(),
+                ("u8" | "u16" | "u32" | "i8" | "i16" | "i32" | "f32" | "f64", []) => {
+                    "number".to_string()
+                }
+                ("String" | "str" | "char", []) => "string".to_string(),
+                ("Option", [ty]) => format!("{} | null", ts_type(ty)),
+                ("Box" | "Rc" | "Arc" | "Cow", [ty]) => ts_type(ty),
+                ("Vec" | "VecDeque" | "HashSet" | "BTreeSet", [ty]) => array_type(ty),
+                ("HashMap" | "BTreeMap", [_, value]) => {
+                    format!("Record<string, {}>", ts_type(value))
+                }
+                _ => "unknown".to_string(),
+            }
+        }
+        Type::Reference(reference) => ts_type(&reference.elem),
+        Type::Paren(paren) => ts_type(&paren.elem),
+        Type::Group(group) => ts_type(&group.elem),
+        Type::Array(array) => array_type(&array.elem),
+        Type::Slice(slice) => array_type(&slice.elem),
+        Type::Tuple(tuple) if tuple.elems.is_empty() => "null".to_string(),
+        Type::Tuple(tuple) => tuple_type(tuple.elems.iter()),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn array_type(elem: &Type) -> String {
+    let elem = ts_type(elem);
+    if elem.contains(' ') {
+        format!("({elem})[]")
+    } else {
+        format!("{elem}[]")
+    }
+}
+
+fn tuple_type<'a>(elems: impl Iterator<Item = &'a Type>) -> String {
+    let elems: Vec<_> = elems.map(ts_type).collect();
+    format!("[{}]", elems.join(", "))
+}
+
+/// The last identifier of a type path, e.g. `u8` for `std::primitive::u8`.
+fn path_ident(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    }
+}
@@ -57,6 +57,12 @@ fn print_events(mut event_rdr: EventReader<RtcServerEvent>) {
             RtcServerEvent::IdAssigned(id) => {
                 info!("Server ready as {id}");
             }
+            RtcServerEvent::ProtocolMismatch { peer, mismatches } => {
+                warn!("Client {peer} is running a different build: {mismatches:?}");
+            }
+            RtcServerEvent::PeerOverflowed { peer, protocol } => {
+                warn!("Client {peer} overflowed {protocol} and was asked to disconnect");
+            }
         }
     }
 }
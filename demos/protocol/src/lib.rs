@@ -5,9 +5,15 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Payload, Serialize, Deserialize, Debug, Clone)]
 pub struct DrawLinePayload {
+    // Canvas coordinates are 0..1 along the short side of the window and
+    // further along the long side, so windows up to 4:1 fit in the range
+    #[payload(quantize(min = 0.0, max = 4.0, bits = 16))]
     pub x1: f32,
+    #[payload(quantize(min = 0.0, max = 4.0, bits = 16))]
     pub y1: f32,
+    #[payload(quantize(min = 0.0, max = 4.0, bits = 16))]
     pub x2: f32,
+    #[payload(quantize(min = 0.0, max = 4.0, bits = 16))]
     pub y2: f32,
 }
 